@@ -0,0 +1,77 @@
+// Expiring and renewing leases in a registry of 100k instances, e.g.
+//   cargo bench --bench expiry
+use criterion::{criterion_group, criterion_main, Criterion};
+
+use dist_rust_buted::svc_dsc::server::{
+    registry::{MemoryRegistry, Registry},
+    serdict::{ServiceMap, ServiceRecord},
+};
+
+use std::time::{Duration, SystemTime};
+
+const INSTANCES: u64 = 100_000;
+const EXPIRED: u64 = 100;
+
+fn record(lease_id: u64, age: Duration) -> ServiceRecord {
+    ServiceRecord {
+        addr: ("[::1]".to_string(), 50052),
+        last_updated: SystemTime::now() - age,
+        lease_id,
+        ttl: 60_000,
+        ..Default::default()
+    }
+}
+
+// 100 services of 1000 live instances each
+fn registry() -> MemoryRegistry {
+    let mut map = ServiceMap::new();
+    for lease_id in 1..=INSTANCES {
+        let key = (
+            "default".to_string(),
+            "bench".to_string(),
+            format!("svc-{}", lease_id % 100),
+        );
+        map.entry(key).or_default().insert(
+            format!("instance-{}", lease_id),
+            record(lease_id, Duration::ZERO),
+        );
+    }
+    MemoryRegistry::new(map)
+}
+
+fn expiry(c: &mut Criterion) {
+    let registry = registry();
+    let key = (
+        "default".to_string(),
+        "bench".to_string(),
+        "dead".to_string(),
+    );
+
+    // Only the instances that ran out are visited, however many are live
+    c.bench_function("expire 100 of 100k instances", |b| {
+        b.iter(|| {
+            for lease_id in 0..EXPIRED {
+                let record = record(INSTANCES + 1 + lease_id, Duration::from_secs(120));
+                let instance_id = format!("dead-{}", lease_id);
+                registry.insert(key.clone(), instance_id, record).unwrap();
+            }
+            let expired = registry.expire().unwrap();
+            assert_eq!(expired.len() as u64, EXPIRED);
+        })
+    });
+
+    let mut lease_id = 0;
+    c.bench_function("renew 1 of 100k instances", |b| {
+        b.iter(|| {
+            lease_id = lease_id % INSTANCES + 1;
+            registry.renew(lease_id).unwrap().unwrap();
+        })
+    });
+
+    c.bench_function("next deadline of 100k instances", |b| {
+        b.iter(|| registry.next_deadline().unwrap().unwrap())
+    });
+}
+
+criterion_group!(benches, expiry);
+criterion_main!(benches);
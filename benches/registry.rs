@@ -0,0 +1,75 @@
+// Registering and looking up services from many threads at once, e.g.
+//   cargo bench --bench registry
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+
+use dist_rust_buted::svc_dsc::server::{
+    registry::{MemoryRegistry, Registry},
+    serdict::{ServiceId, ServiceRecord},
+};
+
+use std::thread;
+
+const THREADS: usize = 8;
+const SERVICES: usize = 100;
+// Per thread and iteration
+const CALLS: usize = 1000;
+
+fn record(lease_id: u64) -> ServiceRecord {
+    ServiceRecord {
+        addr: ("[::1]".to_string(), 50052),
+        lease_id,
+        ttl: 60_000,
+        ..Default::default()
+    }
+}
+
+fn service(n: usize) -> ServiceId {
+    (
+        "default".to_string(),
+        "bench".to_string(),
+        format!("svc-{}", n % SERVICES),
+    )
+}
+
+// Runs `call` CALLS times on each of THREADS threads, with the thread and call numbers
+fn concurrently(call: impl Fn(usize, usize) + Sync) {
+    thread::scope(|scope| {
+        for t in 0..THREADS {
+            let call = &call;
+            scope.spawn(move || (0..CALLS).for_each(|n| call(t, n)));
+        }
+    });
+}
+
+fn registry(c: &mut Criterion) {
+    let registry = MemoryRegistry::default();
+    let mut group = c.benchmark_group("registry");
+    group.throughput(Throughput::Elements((THREADS * CALLS) as u64));
+
+    // Every thread registers the same instances again, as heartbeats do
+    group.bench_function("register from 8 threads", |b| {
+        b.iter(|| {
+            concurrently(|t, n| {
+                let lease_id = (t * CALLS + n) as u64 + 1;
+                let instance_id = format!("instance-{}-{}", t, n);
+                registry
+                    .insert(service(t * CALLS + n), instance_id, record(lease_id))
+                    .unwrap();
+            })
+        })
+    });
+
+    group.bench_function("get from 8 threads", |b| {
+        b.iter(|| {
+            concurrently(|t, n| {
+                let instances = registry.get(&service(t + n)).unwrap();
+                assert!(!instances.is_empty());
+            })
+        })
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, registry);
+criterion_main!(benches);
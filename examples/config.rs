@@ -0,0 +1,35 @@
+use dist_rust_buted::svc_dsc::{
+    self,
+    gen::{KvPutRequest, Precondition},
+};
+
+// Sets math/div/timeout_ms unless it is set already, then prints the math config every time it
+// changes, e.g. after
+//   grpcurl -plaintext -import-path proto -proto serdict.proto \
+//     -d '{"key": "math/div/timeout_ms", "value": "250"}' '[::1]:50050' serdict.SerDict/KvPut
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let mut client = svc_dsc::client::client().await?;
+    let res = client
+        .kv_put(KvPutRequest {
+            key: "math/div/timeout_ms".to_string(),
+            value: "100".to_string(),
+            // Only if nobody set it before us
+            precondition: Some(Precondition { revision: 0 }),
+            ..Default::default()
+        })
+        .await?
+        .into_inner();
+    println!(
+        "Default set: {}, at revision {}",
+        res.succeeded, res.revision
+    );
+
+    let mut config = svc_dsc::client::config::follow("math/")
+        .await
+        .map_err(|e| e.to_string())?;
+    loop {
+        println!("Config: {:?}", *config.borrow_and_update());
+        config.changed().await?;
+    }
+}
@@ -0,0 +1,32 @@
+use dist_rust_buted::dst_pfm::{run_as_leader, ServiceConfig};
+
+use std::time::Duration;
+
+// Start a few of these: only the elected one ticks, and another takes over within a lease TTL
+// once it is stopped
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let cfg = ServiceConfig {
+        service_group: "math".to_string(),
+        service_name: "jobs".to_string(),
+        host: "example".to_string(),
+        // Tells the replicas apart
+        port: std::process::id(),
+        ..Default::default()
+    };
+
+    let leader = tokio::spawn(async move {
+        run_as_leader(&cfg, "math/jobs/tick", |lock| async move {
+            loop {
+                println!("Tick from {} (token {})", lock.holder, lock.token);
+                tokio::time::sleep(Duration::from_secs(1)).await;
+            }
+        })
+        .await
+        .map_err(|e| e.to_string())
+    });
+
+    tokio::signal::ctrl_c().await?;
+    leader.abort();
+    Ok(())
+}
@@ -0,0 +1,60 @@
+use dist_rust_buted::svc_dsc::{
+    self,
+    gen::{service_event::Kind, InstanceState, ListServiceRequest, WatchServicesRequest},
+    GetServiceResponse,
+};
+
+use std::collections::BTreeMap;
+
+// Keeps a live view of the math services without polling svc-dsc
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let group = "math".to_string();
+    let mut client = svc_dsc::client::client().await?;
+
+    // Watch before listing, so no change slips in between the two calls
+    let mut events = client
+        .watch_services(WatchServicesRequest {
+            group: group.clone(),
+            ..Default::default()
+        })
+        .await?
+        .into_inner();
+
+    let mut view = BTreeMap::new();
+    let services = client
+        .list_service(ListServiceRequest::default())
+        .await?
+        .into_inner()
+        .services;
+    for GetServiceResponse {
+        name, instances, ..
+    } in services
+        .into_iter()
+        .filter(|service| service.group == group)
+    {
+        for instance in instances {
+            view.insert((name.clone(), instance.instance_id.clone()), instance);
+        }
+    }
+    println!("Initial view: {:#?}", view.keys());
+
+    while let Some(event) = events.message().await? {
+        let Some(instance) = event.instance.clone() else {
+            continue;
+        };
+        let key = (event.name.clone(), instance.instance_id.clone());
+        match event.kind() {
+            Kind::Registered | Kind::StateChanged if instance.state() == InstanceState::Active => {
+                view.insert(key, instance);
+            }
+            Kind::Registered | Kind::StateChanged | Kind::Deregistered | Kind::Expired => {
+                view.remove(&key);
+            }
+        }
+        println!("{:?} {}/{}", event.kind(), event.group, event.name);
+        println!("View: {:#?}", view.keys());
+    }
+
+    Ok(())
+}
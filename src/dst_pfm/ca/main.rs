@@ -0,0 +1,212 @@
+use dotenv::dotenv;
+use rcgen::{
+    BasicConstraints, Certificate, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa,
+    KeyPair, SanType,
+};
+
+use dist_rust_buted::dst_pfm::tls;
+
+use std::{
+    env, fs,
+    io::Write,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    path::Path,
+};
+
+const USAGE: &str = "usage:
+  dst-pfm-ca init                 create the CA in TLS_CERT_DIR
+  dst-pfm-ca issue <group/name>…  issue a certificate for each service identity";
+
+// Keys are only readable by their owner
+fn write_key(path: &Path, pem: &str) -> std::io::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path)?.write_all(pem.as_bytes())
+}
+
+fn init(dir: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let (cert_path, key_path) = (dir.join("ca.pem"), dir.join("ca.key"));
+    if cert_path.exists() {
+        return Err(format!("{:?} already exists", cert_path).into());
+    }
+
+    let mut params = CertificateParams::default();
+    params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    params
+        .distinguished_name
+        .push(DnType::CommonName, "dist-rust-buted local CA");
+    let ca = Certificate::from_params(params)?;
+
+    fs::create_dir_all(dir)?;
+    fs::write(&cert_path, ca.serialize_pem()?)?;
+    write_key(&key_path, &ca.serialize_private_key_pem())?;
+    println!("dst_pfm::ca: created {:?}", cert_path);
+    Ok(())
+}
+
+fn load_ca(dir: &Path) -> Result<Certificate, Box<dyn std::error::Error>> {
+    let cert = fs::read_to_string(dir.join("ca.pem"))?;
+    let key = KeyPair::from_pem(&fs::read_to_string(dir.join("ca.key"))?)?;
+    Ok(Certificate::from_params(
+        CertificateParams::from_ca_cert_pem(&cert, key)?,
+    )?)
+}
+
+// Valid for serving group/name and for calling other services as it, on this machine
+fn issue(dir: &Path, ca: &Certificate, identity: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let (group, name) = identity
+        .split_once('/')
+        .ok_or_else(|| format!("identity {:?} is not group/name", identity))?;
+
+    let mut params = CertificateParams::default();
+    params.distinguished_name.push(DnType::CommonName, identity);
+    params.subject_alt_names = vec![
+        SanType::DnsName(tls::server_name(group, name)),
+        SanType::DnsName("localhost".to_string()),
+        SanType::IpAddress(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+        SanType::IpAddress(IpAddr::V6(Ipv6Addr::LOCALHOST)),
+    ];
+    params.extended_key_usages = vec![
+        ExtendedKeyUsagePurpose::ServerAuth,
+        ExtendedKeyUsagePurpose::ClientAuth,
+    ];
+    let cert = Certificate::from_params(params)?;
+
+    let (cert_path, key_path) = tls::cert_paths(dir, group, name);
+    fs::create_dir_all(dir.join(group))?;
+    fs::write(&cert_path, cert.serialize_pem_with_signer(ca)?)?;
+    write_key(&key_path, &cert.serialize_private_key_pem())?;
+    println!("dst_pfm::ca: issued {:?} for {}", cert_path, identity);
+    Ok(())
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // TLS_CERT_DIR may come from .env
+    let _ = dotenv();
+    let dir = tls::cert_dir();
+
+    let args = env::args().skip(1).collect::<Vec<_>>();
+    match args.split_first() {
+        Some((command, [])) if command == "init" => init(&dir),
+        Some((command, identities)) if command == "issue" && !identities.is_empty() => {
+            let ca = load_ca(&dir)?;
+            for identity in identities {
+                issue(&dir, &ca, identity)?;
+            }
+            Ok(())
+        }
+        _ => {
+            eprintln!("{}", USAGE);
+            std::process::exit(2);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use futures::stream;
+    use tokio::net::TcpListener;
+    use tonic::{
+        codegen::InterceptedService, service::Interceptor, transport::Server, Request, Status,
+    };
+    use tonic_health::proto::{health_client::HealthClient, HealthCheckRequest};
+
+    use super::{init, issue, load_ca};
+    use dist_rust_buted::dst_pfm::tls;
+
+    use std::{
+        env,
+        sync::{Arc, Mutex},
+    };
+
+    // The identities of the clients a server saw
+    #[derive(Clone, Default)]
+    struct Callers(Arc<Mutex<Vec<String>>>);
+
+    impl Interceptor for Callers {
+        fn call(&mut self, req: Request<()>) -> Result<Request<()>, Status> {
+            let caller = match tls::peer_identity(&req) {
+                Some((group, name)) => format!("{}/{}", group, name),
+                None => "anonymous".to_string(),
+            };
+            self.0.lock().unwrap().push(caller);
+            Ok(req)
+        }
+    }
+
+    #[tokio::test]
+    async fn it_issues_certificates_that_complete_a_handshake() {
+        let dir = env::temp_dir().join(format!("dst-pfm-ca-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        init(&dir).unwrap();
+        assert!(init(&dir).is_err());
+        let ca = load_ca(&dir).unwrap();
+        issue(&dir, &ca, "platform/service_discovery").unwrap();
+        issue(&dir, &ca, "math/add").unwrap();
+        assert!(issue(&dir, &ca, "math").is_err());
+
+        // Only this test runs in the binary, nothing else reads the environment
+        env::set_var("TLS_MODE", "mtls");
+        env::set_var("TLS_CERT_DIR", &dir);
+        env::set_var("TLS_IDENTITY", "math/add");
+        let (cert, key) = tls::cert_paths(&dir, "platform", "service_discovery");
+        assert_eq!(cert, dir.join("platform").join("service_discovery.pem"));
+        assert!(key.exists());
+        assert_eq!(
+            tls::server_name("platform", "service_discovery"),
+            "service-discovery.platform.svc.local"
+        );
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let incoming = stream::unfold(listener, |listener| async move {
+            let conn = listener.accept().await.map(|(conn, _)| conn);
+            Some((conn, listener))
+        });
+        let callers = Callers::default();
+        let (_, health) = tonic_health::server::health_reporter();
+        let health = InterceptedService::new(health, callers.clone());
+        let tls_config = tls::server_config("platform", "service_discovery")
+            .unwrap()
+            .unwrap();
+        tokio::spawn(
+            Server::builder()
+                .tls_config(tls_config)
+                .unwrap()
+                .add_service(health)
+                .serve_with_incoming(incoming),
+        );
+
+        // The server's certificate chains up to the CA and names platform/service_discovery,
+        // the client presents math/add's
+        let channel = tls::endpoint(&addr, "platform", "service_discovery")
+            .unwrap()
+            .connect()
+            .await
+            .unwrap();
+        HealthClient::new(channel)
+            .check(HealthCheckRequest::default())
+            .await
+            .unwrap();
+        assert_eq!(*callers.0.lock().unwrap(), vec!["math/add"]);
+
+        // The certificate isn't valid for another service's name
+        let channel = tls::endpoint(&addr, "math", "add").unwrap().connect().await;
+        assert!(channel.is_err());
+
+        // Nor is a server trusted without the CA that issued it
+        let other_dir = dir.join("other");
+        init(&other_dir).unwrap();
+        env::set_var("TLS_CA_CERT", other_dir.join("ca.pem"));
+        let channel = tls::endpoint(&addr, "platform", "service_discovery")
+            .unwrap()
+            .connect()
+            .await;
+        assert!(channel.is_err());
+        assert_eq!(callers.0.lock().unwrap().len(), 1);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,224 @@
+use std::{future::Future, time::Duration};
+
+use super::lib::{keep_alive, svc_dsc_client, ServiceConfig};
+use crate::svc_dsc::{
+    gen::{CampaignRequest, GrantLeaseRequest, Lock, ReleaseLockRequest},
+    server::kv::key_group,
+    HEARTBEAT_INTERVAL,
+};
+
+// Runs job only while this instance leads election among the replicas campaigning in it, e.g. so
+// a single replica runs the periodic jobs of a service:
+//   run_as_leader(&cfg, "math/jobs", |lock| async move { loop { ... } }).await
+// The election is held by svc-dsc, with a lease renewed for as long as the instance is up. Job is
+// dropped as soon as the lease is lost and started again once re-elected, with the lock's token
+// fencing off whatever the previous leader still writes. Returns once job completes, after
+// resigning.
+pub async fn run_as_leader<F, Fut>(
+    cfg: &ServiceConfig,
+    election: &str,
+    mut job: F,
+) -> Result<(), Box<dyn std::error::Error>>
+where
+    F: FnMut(Lock) -> Fut,
+    Fut: Future<Output = ()>,
+{
+    let cfg = cfg.clone().with_env_labels();
+    loop {
+        match lead(&cfg, election, &mut job).await {
+            Ok(()) => return Ok(()),
+            Err(e) => println!("dst_pfm::run_as_leader: out of {}: {}", election, e),
+        }
+        // svc-dsc may be restarting or electing a new leader of its own, campaign again shortly
+        tokio::time::sleep(Duration::from_millis(HEARTBEAT_INTERVAL / 5)).await;
+    }
+}
+
+// Campaigns with a new lease, then runs job until it completes or the lease is lost
+async fn lead<F, Fut>(
+    cfg: &ServiceConfig,
+    election: &str,
+    job: &mut F,
+) -> Result<(), Box<dyn std::error::Error>>
+where
+    F: FnMut(Lock) -> Fut,
+    Fut: Future<Output = ()>,
+{
+    let mut client = svc_dsc_client(cfg).await?;
+    let lease = client
+        .grant_lease(GrantLeaseRequest {
+            group: key_group(election).to_string(),
+            ..Default::default()
+        })
+        .await?
+        .into_inner();
+
+    // Losing the lease while waiting to be elected means starting over too
+    let lease_lost = keep_alive(client.clone(), lease.lease_id, lease.ttl);
+    tokio::pin!(lease_lost);
+    let campaign = client.campaign(CampaignRequest {
+        election: election.to_string(),
+        lease_id: lease.lease_id,
+        candidate: cfg.instance_id(),
+        ..Default::default()
+    });
+    let lock = tokio::select! {
+        res = &mut lease_lost => return res,
+        res = campaign => res?.into_inner(),
+    };
+
+    println!(
+        "dst_pfm::run_as_leader: {} leads {} with token {}",
+        cfg.instance_id(),
+        election,
+        lock.token
+    );
+    tokio::select! {
+        res = &mut lease_lost => return res,
+        _ = job(lock) => {}
+    }
+
+    // Let another replica take over right away
+    client
+        .release_lock(ReleaseLockRequest {
+            name: election.to_string(),
+            lease_id: lease.lease_id,
+            ..Default::default()
+        })
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::run_as_leader;
+    use crate::{
+        dst_pfm::lib::ServiceConfig,
+        svc_dsc::{
+            gen::{
+                registry_command::Command, ser_dict_server::SerDictServer, ExpireLocksRequest,
+                Lock, RegistryCommand,
+            },
+            server::{
+                raft::StateMachine, registry::MemoryRegistry, serdict::SerDictImpl,
+                store::NoopStore,
+            },
+        },
+    };
+
+    use futures::stream;
+    use prost::Message;
+    use tokio::{
+        net::TcpListener,
+        sync::{broadcast, mpsc, Notify},
+    };
+    use tonic::transport::Server;
+
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc, Mutex,
+        },
+        time::Duration,
+    };
+
+    // Counts the jobs running at once
+    struct Running(Arc<AtomicUsize>);
+
+    impl Running {
+        fn start(count: &Arc<AtomicUsize>) -> Running {
+            count.fetch_add(1, Ordering::SeqCst);
+            Self(Arc::clone(count))
+        }
+    }
+
+    impl Drop for Running {
+        fn drop(&mut self) {
+            self.0.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    async fn next_leader(led: &mut mpsc::UnboundedReceiver<Lock>) -> Lock {
+        let lock = tokio::time::timeout(Duration::from_secs(10), led.recv()).await;
+        lock.expect("no leader elected").unwrap()
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn it_hands_over_when_the_leader_goes() {
+        let (events, _) = broadcast::channel(16);
+        let serdict = SerDictImpl::new(
+            Arc::new(MemoryRegistry::default()),
+            events,
+            Arc::new(Mutex::new(NoopStore)),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        std::env::set_var(
+            "SERVICE_DISCOVERY_ADDRS",
+            listener.local_addr().unwrap().to_string(),
+        );
+        let incoming = stream::unfold(listener, |listener| async move {
+            let conn = listener.accept().await.map(|(conn, _)| conn);
+            Some((conn, listener))
+        });
+        tokio::spawn(
+            Server::builder()
+                .add_service(SerDictServer::new(serdict.clone()))
+                .serve_with_incoming(incoming),
+        );
+
+        // Two replicas, each leading until told to stop
+        let running = Arc::new(AtomicUsize::new(0));
+        let (leads, mut led) = mpsc::unbounded_channel::<Lock>();
+        let mut stops = Vec::new();
+        for port in [1, 2] {
+            let cfg = ServiceConfig {
+                host: "127.0.0.1".into(),
+                port,
+                ..Default::default()
+            };
+            let (leads, running) = (leads.clone(), Arc::clone(&running));
+            let stop = Arc::new(Notify::new());
+            stops.push(Arc::clone(&stop));
+            tokio::spawn(async move {
+                run_as_leader(&cfg, "math/jobs", |lock| {
+                    let (leads, running, stop) =
+                        (leads.clone(), Arc::clone(&running), stop.clone());
+                    async move {
+                        let _running = Running::start(&running);
+                        leads.send(lock).unwrap();
+                        stop.notified().await;
+                    }
+                })
+                .await
+                .map_err(|e| e.to_string())
+            });
+        }
+        // svc-dsc expires the lease of the first leader, the other replica takes over
+        let first = next_leader(&mut led).await;
+        let expiry = Command::ExpireLocks(ExpireLocksRequest {
+            lease_ids: vec![first.lease_id],
+        });
+        let command = RegistryCommand {
+            command: Some(expiry),
+        };
+        serdict.apply(&command.encode_to_vec()).unwrap();
+        let second = next_leader(&mut led).await;
+        assert_ne!(second.holder, first.holder);
+        assert!(second.token > first.token);
+        // The first leader drops its job once it hears its lease is gone
+        for _ in 0..50 {
+            if running.load(Ordering::SeqCst) == 1 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        assert_eq!(running.load(Ordering::SeqCst), 1);
+
+        // A leader done with its job resigns, and the first one is elected again
+        let port = second.holder.rsplit(':').next().unwrap();
+        stops[port.parse::<usize>().unwrap() - 1].notify_one();
+        let third = next_leader(&mut led).await;
+        assert_eq!(third.holder, first.holder);
+        assert!(third.token > second.token);
+    }
+}
@@ -0,0 +1,679 @@
+use rand::seq::SliceRandom;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    net::UdpSocket,
+    sync::{oneshot, watch},
+    task::JoinHandle,
+    time::{timeout, MissedTickBehavior},
+};
+
+use super::resolver::{BoxError, Resolver};
+use crate::svc_dsc::gen::ServiceInstance;
+
+use std::{
+    cmp::Reverse,
+    collections::HashMap,
+    env,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+// SWIM-style membership (Das, Gupta & Motivala, 2002), for services to find each other without
+// svc-dsc. Every probe interval a member pings the next one of a shuffled round. Without an ack
+// within a third of the interval, it asks INDIRECT_PROBES other members to ping it on its behalf,
+// and suspects it when none of them got an ack by the end of the interval either. A suspect that
+// doesn't refute it with a higher incarnation within SUSPECT_PERIODS intervals is declared dead.
+// Membership changes are piggybacked on pings and acks.
+
+// in millis, default for GOSSIP_PROBE_INTERVAL
+pub const PROBE_INTERVAL: u64 = 1000;
+
+// Members asked to ping a member that didn't ack
+const INDIRECT_PROBES: usize = 3;
+
+// Probe intervals a suspect has to refute the suspicion
+const SUSPECT_PERIODS: u32 = 5;
+
+// Probe intervals dead members are remembered for, so stale gossip doesn't bring them back
+const DEAD_PERIODS: u32 = 60;
+
+// Changes piggybacked on a message
+const MAX_PIGGYBACK: usize = 8;
+
+// Each change is piggybacked RETRANSMIT_MULT * log2(members) times
+const RETRANSMIT_MULT: u32 = 3;
+
+const MAX_DATAGRAM: usize = 65507;
+
+// An instance a member serves, found by group/name as when registered with svc-dsc
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Service {
+    pub group: String,
+    pub name: String,
+    pub instance_id: String,
+    pub ip: String,
+    pub port: u32,
+    #[serde(default)]
+    pub metadata: HashMap<String, String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub version: String,
+}
+
+impl Service {
+    fn to_instance(&self) -> ServiceInstance {
+        ServiceInstance {
+            instance_id: self.instance_id.clone(),
+            ip: self.ip.clone(),
+            port: self.port,
+            metadata: self.metadata.clone(),
+            tags: self.tags.clone(),
+            version: self.version.clone(),
+            // Members that are going away leave the gossip instead
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    Alive,
+    Suspect,
+    Dead,
+    Left,
+}
+
+impl Status {
+    // Suspects are still members, until they are declared dead
+    fn is_live(self) -> bool {
+        matches!(self, Status::Alive | Status::Suspect)
+    }
+}
+
+// What a member claims about another one, or about itself
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+struct Update {
+    addr: SocketAddr,
+    // Raised by a member to refute suspicion about itself
+    incarnation: u64,
+    status: Status,
+    service: Option<Service>,
+}
+
+impl Update {
+    // Whether this is news to a member that knows of current
+    fn overrides(&self, current: &Update) -> bool {
+        match (self.status, current.status) {
+            (Status::Alive, _) => self.incarnation > current.incarnation,
+            (Status::Suspect, Status::Alive) => self.incarnation >= current.incarnation,
+            (Status::Suspect, _) => self.incarnation > current.incarnation,
+            (Status::Dead | Status::Left, Status::Alive | Status::Suspect) => {
+                self.incarnation >= current.incarnation
+            }
+            (Status::Dead | Status::Left, _) => false,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Message {
+    Ping {
+        seq: u64,
+        updates: Vec<Update>,
+    },
+    Ack {
+        seq: u64,
+        updates: Vec<Update>,
+    },
+    // Asks to ping target, and to pass its ack on
+    PingReq {
+        seq: u64,
+        target: SocketAddr,
+        updates: Vec<Update>,
+    },
+    // Sent to the seeds, which answer with every member they know of
+    Join {
+        member: Update,
+    },
+    Members {
+        members: Vec<Update>,
+    },
+}
+
+struct Member {
+    update: Update,
+    // When its status last changed
+    since: Instant,
+}
+
+struct State {
+    me: Update,
+    // Every other member, by gossip address
+    members: HashMap<SocketAddr, Member>,
+    // Changes still to piggyback, with how many more times each one goes out
+    broadcasts: Vec<(Update, u32)>,
+    // Probes waiting for an ack, by sequence number
+    acks: HashMap<u64, oneshot::Sender<()>>,
+    next_seq: u64,
+    // Members left to probe this round
+    probe_order: Vec<SocketAddr>,
+    // Followers of each group/name
+    watchers: HashMap<(String, String), watch::Sender<Vec<ServiceInstance>>>,
+}
+
+impl State {
+    fn new(me: Update) -> State {
+        Self {
+            me,
+            members: HashMap::new(),
+            broadcasts: Vec::new(),
+            acks: HashMap::new(),
+            next_seq: 1,
+            probe_order: Vec::new(),
+            watchers: HashMap::new(),
+        }
+    }
+
+    // A sequence number for a ping, and the receiver of its ack
+    fn expect_ack(&mut self) -> (u64, oneshot::Receiver<()>) {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        let (ack, ack_recv) = oneshot::channel();
+        self.acks.insert(seq, ack);
+        (seq, ack_recv)
+    }
+
+    fn live_members(&self) -> Vec<SocketAddr> {
+        self.members
+            .values()
+            .filter(|member| member.update.status.is_live())
+            .map(|member| member.update.addr)
+            .collect()
+    }
+
+    fn broadcast(&mut self, update: Update) {
+        self.broadcasts
+            .retain(|(queued, _)| queued.addr != update.addr);
+        let members = self.members.len() as u32 + 1;
+        let times = RETRANSMIT_MULT * (u32::BITS - members.leading_zeros());
+        self.broadcasts.push((update, times));
+    }
+
+    // The changes to send along with a message, those sent the least first
+    fn piggyback(&mut self) -> Vec<Update> {
+        self.broadcasts.sort_by_key(|(_, times)| Reverse(*times));
+        let updates = self
+            .broadcasts
+            .iter_mut()
+            .take(MAX_PIGGYBACK)
+            .map(|(update, times)| {
+                *times -= 1;
+                update.clone()
+            })
+            .collect();
+        self.broadcasts.retain(|(_, times)| *times > 0);
+        updates
+    }
+
+    // Applies what another member claims, and passes it on when it is news
+    fn merge(&mut self, update: Update) {
+        if update.addr == self.me.addr {
+            let suspected = matches!(update.status, Status::Suspect | Status::Dead);
+            if suspected
+                && self.me.status == Status::Alive
+                && update.incarnation >= self.me.incarnation
+            {
+                self.me.incarnation = update.incarnation + 1;
+                let me = self.me.clone();
+                self.broadcast(me);
+            }
+            return;
+        }
+
+        let news = match self.members.get(&update.addr) {
+            Some(member) => update.overrides(&member.update),
+            None => update.status.is_live(),
+        };
+        if !news {
+            return;
+        }
+
+        println!("dst_pfm::gossip: {} is {:?}", update.addr, update.status);
+        self.members.insert(
+            update.addr,
+            Member {
+                update: update.clone(),
+                since: Instant::now(),
+            },
+        );
+        self.broadcast(update);
+        self.notify();
+    }
+
+    fn suspect(&mut self, addr: SocketAddr) {
+        if let Some(member) = self.members.get(&addr) {
+            if member.update.status == Status::Alive {
+                let update = Update {
+                    status: Status::Suspect,
+                    ..member.update.clone()
+                };
+                self.merge(update);
+            }
+        }
+    }
+
+    // Declares the suspects that ran out of time dead, and forgets about the long dead
+    fn expire(&mut self, interval: Duration) {
+        let expired = self
+            .members
+            .values()
+            .filter(|member| {
+                member.update.status == Status::Suspect
+                    && member.since.elapsed() >= interval * SUSPECT_PERIODS
+            })
+            .map(|member| Update {
+                status: Status::Dead,
+                ..member.update.clone()
+            })
+            .collect::<Vec<_>>();
+        for update in expired {
+            self.merge(update);
+        }
+
+        self.members.retain(|_, member| {
+            member.update.status.is_live() || member.since.elapsed() < interval * DEAD_PERIODS
+        });
+    }
+
+    // The next member to probe, every live member once per round in random order
+    fn next_target(&mut self) -> Option<SocketAddr> {
+        loop {
+            if self.probe_order.is_empty() {
+                self.probe_order = self.live_members();
+                self.probe_order.shuffle(&mut rand::thread_rng());
+            }
+            let addr = self.probe_order.pop()?;
+            if matches!(self.members.get(&addr), Some(member) if member.update.status.is_live()) {
+                return Some(addr);
+            }
+        }
+    }
+
+    // Live instances of group/name, this member's included, sorted by instance id
+    fn instances(&self, group: &str, name: &str) -> Vec<ServiceInstance> {
+        let mut instances = std::iter::once(&self.me)
+            .chain(self.members.values().map(|member| &member.update))
+            .filter(|update| update.status.is_live())
+            .filter_map(|update| update.service.as_ref())
+            .filter(|service| service.group == group && service.name == name)
+            .map(Service::to_instance)
+            .collect::<Vec<_>>();
+        instances.sort_by(|a, b| a.instance_id.cmp(&b.instance_id));
+        instances
+    }
+
+    fn notify(&mut self) {
+        self.watchers
+            .retain(|_, watcher| watcher.receiver_count() > 0);
+        for ((group, name), watcher) in &self.watchers {
+            let instances = self.instances(group, name);
+            watcher.send_if_modified(|current| {
+                let modified = *current != instances;
+                *current = instances;
+                modified
+            });
+        }
+    }
+}
+
+pub struct GossipConfig {
+    // Where to listen for other members, which reach this one at the same address
+    pub addr: SocketAddr,
+    // Members to join through
+    pub seeds: Vec<SocketAddr>,
+    pub probe_interval: Duration,
+}
+
+impl GossipConfig {
+    // GOSSIP_ADDR ([::1]:0 by default), GOSSIP_SEEDS ("[::1]:7946,[::1]:7947") and
+    // GOSSIP_PROBE_INTERVAL in millis
+    pub fn from_env() -> Result<GossipConfig, BoxError> {
+        let addr = env::var("GOSSIP_ADDR").unwrap_or_else(|_| "[::1]:0".to_string());
+        let seeds = env::var("GOSSIP_SEEDS").unwrap_or_default();
+        let probe_interval = match env::var("GOSSIP_PROBE_INTERVAL") {
+            Ok(interval) => interval.parse()?,
+            Err(_) => PROBE_INTERVAL,
+        };
+
+        Ok(GossipConfig {
+            addr: addr.parse()?,
+            seeds: seeds
+                .split(',')
+                .map(str::trim)
+                .filter(|seed| !seed.is_empty())
+                .map(str::parse)
+                .collect::<Result<_, _>>()?,
+            probe_interval: Duration::from_millis(probe_interval),
+        })
+    }
+}
+
+#[derive(Clone)]
+struct Node {
+    socket: Arc<UdpSocket>,
+    state: Arc<Mutex<State>>,
+    seeds: Vec<SocketAddr>,
+    interval: Duration,
+}
+
+impl Node {
+    async fn send(&self, addr: SocketAddr, message: &Message) {
+        let bytes = serde_json::to_vec(message).expect("dst_pfm::gossip: unserializable message");
+        if let Err(e) = self.socket.send_to(&bytes, addr).await {
+            println!("dst_pfm::gossip: cannot send to {}: {}", addr, e);
+        }
+    }
+
+    async fn join(&self) {
+        let me = self.state.lock().unwrap().me.clone();
+        for seed in self.seeds.iter().filter(|seed| **seed != me.addr) {
+            let member = me.clone();
+            self.send(*seed, &Message::Join { member }).await;
+        }
+    }
+
+    fn merge(&self, updates: Vec<Update>) {
+        let mut state = self.state.lock().unwrap();
+        for update in updates {
+            state.merge(update);
+        }
+    }
+
+    fn piggyback(&self) -> Vec<Update> {
+        self.state.lock().unwrap().piggyback()
+    }
+
+    async fn receive(self) {
+        let mut buf = vec![0; MAX_DATAGRAM];
+        loop {
+            let (len, from) = match self.socket.recv_from(&mut buf).await {
+                Ok(received) => received,
+                Err(e) => {
+                    println!("dst_pfm::gossip: receive failed: {}", e);
+                    continue;
+                }
+            };
+            match serde_json::from_slice(&buf[..len]) {
+                Ok(message) => self.handle(from, message).await,
+                Err(e) => println!("dst_pfm::gossip: ignoring message from {}: {}", from, e),
+            }
+        }
+    }
+
+    async fn handle(&self, from: SocketAddr, message: Message) {
+        match message {
+            Message::Ping { seq, updates } => {
+                self.merge(updates);
+                let updates = self.piggyback();
+                self.send(from, &Message::Ack { seq, updates }).await;
+            }
+            Message::Ack { seq, updates } => {
+                let mut state = self.state.lock().unwrap();
+                for update in updates {
+                    state.merge(update);
+                }
+                if let Some(ack) = state.acks.remove(&seq) {
+                    let _ = ack.send(());
+                }
+            }
+            Message::PingReq {
+                seq,
+                target,
+                updates,
+            } => {
+                self.merge(updates);
+                let node = self.clone();
+                tokio::spawn(async move {
+                    if node.ping(target).await {
+                        let updates = node.piggyback();
+                        node.send(from, &Message::Ack { seq, updates }).await;
+                    }
+                });
+            }
+            Message::Join { member } => {
+                let members = {
+                    let mut state = self.state.lock().unwrap();
+                    state.merge(member);
+                    std::iter::once(state.me.clone())
+                        .chain(state.members.values().map(|member| member.update.clone()))
+                        .collect()
+                };
+                self.send(from, &Message::Members { members }).await;
+            }
+            Message::Members { members } => self.merge(members),
+        }
+    }
+
+    // Whether addr acks a ping within a probe interval
+    async fn ping(&self, addr: SocketAddr) -> bool {
+        let (seq, ack) = self.state.lock().unwrap().expect_ack();
+        let updates = self.piggyback();
+        self.send(addr, &Message::Ping { seq, updates }).await;
+
+        let acked = matches!(timeout(self.interval, ack).await, Ok(Ok(())));
+        self.state.lock().unwrap().acks.remove(&seq);
+        acked
+    }
+
+    // Pings target directly, then through other members, and suspects it when neither works
+    async fn probe(&self, target: SocketAddr) {
+        let (seq, mut ack) = self.state.lock().unwrap().expect_ack();
+        let updates = self.piggyback();
+        self.send(target, &Message::Ping { seq, updates }).await;
+
+        let direct = self.interval / 3;
+        let mut acked = matches!(timeout(direct, &mut ack).await, Ok(Ok(())));
+        if !acked {
+            let (helpers, updates) = {
+                let mut state = self.state.lock().unwrap();
+                let mut helpers = state.live_members();
+                helpers.retain(|helper| *helper != target);
+                helpers.shuffle(&mut rand::thread_rng());
+                helpers.truncate(INDIRECT_PROBES);
+                (helpers, state.piggyback())
+            };
+            for helper in helpers {
+                let updates = updates.clone();
+                let ping_req = Message::PingReq {
+                    seq,
+                    target,
+                    updates,
+                };
+                self.send(helper, &ping_req).await;
+            }
+            // Acks passed on by helpers carry our sequence number
+            acked = matches!(timeout(self.interval - direct, &mut ack).await, Ok(Ok(())));
+        }
+
+        let mut state = self.state.lock().unwrap();
+        state.acks.remove(&seq);
+        if !acked {
+            state.suspect(target);
+        }
+    }
+
+    async fn run(self) {
+        let mut ticks = tokio::time::interval(self.interval);
+        ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            ticks.tick().await;
+
+            let (target, alone) = {
+                let mut state = self.state.lock().unwrap();
+                state.expire(self.interval);
+                (state.next_target(), state.live_members().is_empty())
+            };
+            // The seeds may not have been up yet, or the others are all gone
+            if alone {
+                self.join().await;
+            }
+            if let Some(target) = target {
+                self.probe(target).await;
+            }
+        }
+    }
+}
+
+// Stops the member's tasks once its last handle is dropped
+struct Tasks(Vec<JoinHandle<()>>);
+
+impl Drop for Tasks {
+    fn drop(&mut self) {
+        for task in &self.0 {
+            task.abort();
+        }
+    }
+}
+
+// A member of the gossip, resolving the services of the other members
+#[derive(Clone)]
+pub struct Gossip {
+    node: Node,
+    _tasks: Arc<Tasks>,
+}
+
+impl Gossip {
+    // Joins through config's seeds, advertising service when given one
+    pub async fn start(config: GossipConfig, service: Option<Service>) -> Result<Gossip, BoxError> {
+        let socket = UdpSocket::bind(config.addr).await?;
+        let me = Update {
+            addr: socket.local_addr()?,
+            // Starts above the incarnations of a previous run at the same address
+            incarnation: SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as u64,
+            status: Status::Alive,
+            service,
+        };
+        println!(
+            "dst_pfm::gossip: member {} joining through {:?}",
+            me.addr, config.seeds
+        );
+
+        let node = Node {
+            socket: Arc::new(socket),
+            state: Arc::new(Mutex::new(State::new(me))),
+            seeds: config.seeds,
+            interval: config.probe_interval,
+        };
+        node.join().await;
+        let tasks = vec![
+            tokio::spawn(node.clone().receive()),
+            tokio::spawn(node.clone().run()),
+        ];
+
+        Ok(Gossip {
+            node,
+            _tasks: Arc::new(Tasks(tasks)),
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.node.state.lock().unwrap().me.addr
+    }
+
+    // Tells the other members this one is leaving, instead of letting them find out
+    pub async fn leave(&self) {
+        let (me, members) = {
+            let mut state = self.node.state.lock().unwrap();
+            state.me.status = Status::Left;
+            (state.me.clone(), state.live_members())
+        };
+        for addr in members {
+            let members = vec![me.clone()];
+            self.node.send(addr, &Message::Members { members }).await;
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl Resolver for Gossip {
+    async fn resolve(
+        &self,
+        group: &str,
+        name: &str,
+    ) -> Result<watch::Receiver<Vec<ServiceInstance>>, BoxError> {
+        let mut state = self.node.state.lock().unwrap();
+        let key = (group.to_string(), name.to_string());
+        if let Some(watcher) = state.watchers.get(&key) {
+            return Ok(watcher.subscribe());
+        }
+
+        let (watcher, instances) = watch::channel(state.instances(group, name));
+        state.watchers.insert(key, watcher);
+        Ok(instances)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::sync::watch;
+
+    use super::{Gossip, GossipConfig, Service};
+    use crate::{dst_pfm::resolver::Resolver, svc_dsc::gen::ServiceInstance};
+
+    use std::time::Duration;
+
+    async fn member(seeds: Vec<std::net::SocketAddr>, port: Option<u32>) -> Gossip {
+        let config = GossipConfig {
+            addr: "127.0.0.1:0".parse().unwrap(),
+            seeds,
+            probe_interval: Duration::from_millis(30),
+        };
+        let service = port.map(|port| Service {
+            group: "math".into(),
+            name: "add".into(),
+            instance_id: format!("127.0.0.1:{port}"),
+            ip: "127.0.0.1".into(),
+            port,
+            ..Default::default()
+        });
+        Gossip::start(config, service).await.unwrap()
+    }
+
+    async fn wait_for(
+        instances: &mut watch::Receiver<Vec<ServiceInstance>>,
+        ports: &[u32],
+    ) -> Result<(), tokio::time::error::Elapsed> {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while instances
+                .borrow_and_update()
+                .iter()
+                .map(|i| i.port)
+                .ne(ports.iter().copied())
+            {
+                instances.changed().await.unwrap();
+            }
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn it_finds_members_and_detects_failures() {
+        let seed = member(Vec::new(), None).await;
+        let add1 = member(vec![seed.local_addr()], Some(50061)).await;
+        let add2 = member(vec![seed.local_addr()], Some(50062)).await;
+
+        let mut instances = seed.resolve("math", "add").await.unwrap();
+        wait_for(&mut instances, &[50061, 50062]).await.unwrap();
+
+        // Gone without a word, found out by probes
+        drop(add1);
+        wait_for(&mut instances, &[50062]).await.unwrap();
+
+        add2.leave().await;
+        wait_for(&mut instances, &[]).await.unwrap();
+    }
+}
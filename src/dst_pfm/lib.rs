@@ -1,60 +1,240 @@
-use std::{convert::Infallible, thread, time};
+use std::{collections::HashMap, convert::Infallible, env, sync::Arc, time::Duration};
 
 use futures::FutureExt;
 use http::{Request as HttpRequest, Response as HttpResponse};
 use hyper::service::Service;
 use hyper::Body;
-use tokio::sync::oneshot;
+use tokio::sync::{mpsc, oneshot};
+use tokio_stream::wrappers::ReceiverStream;
 use tonic::{
     body::BoxBody,
     transport::{NamedService, Server},
 };
+use tonic_health::{server::HealthReporter, ServingStatus};
 
-use crate::svc_dsc::{self, HEARTBEAT_INTERVAL};
+use super::{
+    gossip::{self, Gossip, GossipConfig},
+    resolver::{self, BoxError, Mode},
+    tls,
+};
+use crate::svc_dsc::{
+    self,
+    client::{Identity, SerDictChannel},
+    gen::ser_dict_client::SerDictClient,
+    HEARTBEAT_INTERVAL,
+};
+
+// in millis, how long a shutting down service waits for its in-flight calls, default for
+// SERVICE_DRAIN_TIMEOUT
+const DRAIN_TIMEOUT: u64 = 10000;
 
-#[derive(Clone)]
+#[derive(Clone, Default)]
 pub struct ServiceConfig {
     pub service_group: String,
     pub service_name: String,
     pub host: String,
     pub port: u32,
     pub should_register: bool,
+    // Registered along with the instance, for svc-dsc's FindServices
+    pub metadata: HashMap<String, String>,
+    pub tags: Vec<String>,
+    pub version: String,
+    // Authenticates the instance with svc-dsc's ACL, SERVICE_DISCOVERY_TOKEN is used when empty
+    pub token: String,
+}
+
+impl ServiceConfig {
+    // Overrides the labels with SERVICE_VERSION, SERVICE_TAGS ("a,b") and SERVICE_METADATA
+    // ("zone=a,rack=2"), so a canary can be started without a rebuild. SERVICE_TOKEN sets the
+    // token, for services sharing a .env that need their own.
+    pub(crate) fn with_env_labels(mut self) -> ServiceConfig {
+        // Labels may come from .env too
+        let _ = dotenv::dotenv();
+        if let Ok(token) = env::var("SERVICE_TOKEN") {
+            self.token = token;
+        }
+        if let Ok(version) = env::var("SERVICE_VERSION") {
+            self.version = version;
+        }
+        if let Ok(tags) = env::var("SERVICE_TAGS") {
+            self.tags = tags
+                .split(',')
+                .map(str::trim)
+                .filter(|tag| !tag.is_empty())
+                .map(String::from)
+                .collect();
+        }
+        if let Ok(metadata) = env::var("SERVICE_METADATA") {
+            for entry in metadata.split(',') {
+                match entry.split_once('=') {
+                    Some((key, value)) => {
+                        self.metadata
+                            .insert(key.trim().to_string(), value.trim().to_string());
+                    }
+                    None => println!("dst-pfm::with_env_labels: ignoring metadata {:?}", entry),
+                }
+            }
+        }
+        self
+    }
+
+    // Identifies this replica among the other instances of group/name
+    pub fn instance_id(&self) -> String {
+        format!("{}:{}", self.host, self.port)
+    }
+
+    // What the instance advertises to the other members in gossip mode
+    fn gossip_service(&self) -> gossip::Service {
+        gossip::Service {
+            group: self.service_group.clone(),
+            name: self.service_name.clone(),
+            instance_id: self.instance_id(),
+            ip: self.host.clone(),
+            port: self.port,
+            metadata: self.metadata.clone(),
+            tags: self.tags.clone(),
+            version: self.version.clone(),
+        }
+    }
+}
+
+// Connects to svc-dsc with the service's own credentials
+pub(crate) async fn svc_dsc_client(
+    cfg: &ServiceConfig,
+) -> Result<SerDictClient<SerDictChannel>, Box<dyn std::error::Error>> {
+    let mut identity = Identity::from_env()?;
+    if !cfg.token.is_empty() {
+        identity = identity.with_token(&cfg.token)?;
+    }
+    svc_dsc::client::connect(identity).await
 }
 
-async fn register_service(cfg: &ServiceConfig) -> Result<(), Box<dyn std::error::Error>> {
+async fn register_service(
+    svc_dsc_client: &mut SerDictClient<SerDictChannel>,
+    cfg: &ServiceConfig,
+) -> Result<svc_dsc::RegisterServiceResponse, Box<dyn std::error::Error>> {
     let ServiceConfig {
         service_group,
         service_name,
         host,
         port,
-        should_register,
+        ..
     } = cfg;
 
-    if !should_register {
-        return Ok(());
-    }
-
-    let mut svc_dsc_client = svc_dsc::client::client()
-        .await
-        .expect("dst-pfm::init_service: unable to connect to svc_dsc");
-
     println!(
         "dst-pfm::init_service: registering {}/{} at {}:{}",
         service_group, service_name, host, port
     );
-    svc_dsc_client
+    let res = svc_dsc_client
         .register_service(svc_dsc::RegisterServiceRequest {
             group: service_group.clone(),
             name: service_name.clone(),
             ip: host.into(),
             port: *port,
+            instance_id: cfg.instance_id(),
+            metadata: cfg.metadata.clone(),
+            tags: cfg.tags.clone(),
+            version: cfg.version.clone(),
+            ..Default::default()
+        })
+        .await?;
+
+    Ok(res.into_inner())
+}
+
+// Renews a lease over one KeepAlive stream for as long as both ends are up. Only returns once
+// the lease is lost.
+pub(crate) async fn keep_alive(
+    mut svc_dsc_client: SerDictClient<SerDictChannel>,
+    lease_id: u64,
+    ttl: u64,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (renewals, renewals_recv) = mpsc::channel(1);
+    let mut responses = svc_dsc_client
+        .keep_alive(ReceiverStream::new(renewals_recv))
+        .await?
+        .into_inner();
+
+    // Renew well before the TTL runs out, so one slow round trip doesn't lose the lease
+    let interval = Duration::from_millis(ttl / 3);
+    loop {
+        renewals
+            .send(svc_dsc::KeepAliveRequest { lease_id })
+            .await?;
+        match responses.message().await? {
+            Some(res) if res.ttl > 0 => {}
+            Some(_) => return Err(format!("lease {} expired", lease_id).into()),
+            None => return Err("svc-dsc closed the keep-alive stream".into()),
+        }
+        tokio::time::sleep(interval).await;
+    }
+}
+
+// Registers the instance, then keeps its lease
+async fn register_and_keep_alive(cfg: &ServiceConfig) -> Result<(), Box<dyn std::error::Error>> {
+    let mut svc_dsc_client = svc_dsc_client(cfg).await?;
+    let lease = register_service(&mut svc_dsc_client, cfg).await?;
+    keep_alive(svc_dsc_client, lease.lease_id, lease.ttl).await
+}
+
+async fn deregister_service(cfg: &ServiceConfig) -> Result<(), Box<dyn std::error::Error>> {
+    let mut svc_dsc_client = svc_dsc_client(cfg).await?;
+    svc_dsc_client
+        .deregister_service(svc_dsc::DeregisterServiceRequest {
+            group: cfg.service_group.clone(),
+            name: cfg.service_name.clone(),
+            instance_id: cfg.instance_id(),
+            ..Default::default()
         })
-        .await
-        .expect("dst-pfm::init_service: unable to register service");
+        .await?;
 
     Ok(())
 }
 
+// Takes the instance out of rotation, or puts it back
+async fn set_instance_state(
+    cfg: &ServiceConfig,
+    state: svc_dsc::InstanceState,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut svc_dsc_client = svc_dsc_client(cfg).await?;
+    let mut request = svc_dsc::SetInstanceStateRequest {
+        group: cfg.service_group.clone(),
+        name: cfg.service_name.clone(),
+        instance_id: cfg.instance_id(),
+        ..Default::default()
+    };
+    request.set_state(state);
+    svc_dsc_client.set_instance_state(request).await?;
+
+    Ok(())
+}
+
+fn drain_timeout() -> Result<Duration, Box<dyn std::error::Error>> {
+    let timeout = match env::var("SERVICE_DRAIN_TIMEOUT") {
+        Ok(timeout) => timeout.parse()?,
+        Err(_) => DRAIN_TIMEOUT,
+    };
+    Ok(Duration::from_millis(timeout))
+}
+
+// Joins the gossip, advertising the instance when it should register, and resolves every service
+// the process calls through it
+async fn join_gossip(cfg: &ServiceConfig) -> Result<Gossip, BoxError> {
+    let service = cfg.should_register.then(|| cfg.gossip_service());
+    let gossip = Gossip::start(GossipConfig::from_env()?, service).await?;
+    resolver::set_resolver(Arc::new(gossip.clone()));
+    Ok(gossip)
+}
+
+// Sets both the server-wide status ("") and S's own, as probed by svc-dsc and other checkers
+async fn set_status<S: NamedService>(health: &mut HealthReporter, status: ServingStatus) {
+    health.set_service_status("", status).await;
+    health.set_service_status(S::NAME, status).await;
+}
+
+// Also serves grpc.health.v1.Health, reporting SERVING for as long as the service is up. On Ctrl-C
+// the instance is set draining, then given SERVICE_DRAIN_TIMEOUT to finish its in-flight calls
+// before it deregisters.
 pub async fn serve_with_shutdown<S>(
     service: S,
     cfg: &ServiceConfig,
@@ -67,15 +247,35 @@ where
         + 'static,
     S::Future: Send + 'static,
 {
+    // Deregistering needs the same token as registering
+    let cfg = &cfg.clone().with_env_labels();
+    let drain_timeout = drain_timeout()?;
+    // Calls to other services are made as this one
+    tls::set_identity(&cfg.service_group, &cfg.service_name);
+
+    // In gossip mode the instance is advertised by joining, and svc-dsc is left out entirely
+    let gossip = match resolver::mode()? {
+        Mode::SerDict => None,
+        Mode::Gossip => Some(
+            join_gossip(cfg)
+                .await
+                .map_err(|e| e as Box<dyn std::error::Error>)?,
+        ),
+    };
+
     let register_heartbeat_task = {
         let cfg = cfg.clone();
+        let register = cfg.should_register && gossip.is_none();
         tokio::spawn(async move {
+            if !register {
+                return;
+            }
             loop {
-                if !cfg.should_register {
-                    break;
+                // svc-dsc may be restarting or electing a new leader, register again shortly
+                if let Err(e) = register_and_keep_alive(&cfg).await {
+                    println!("dst-pfm::init_service: lost registration: {}", e);
                 }
-                let _ = register_service(&cfg).await;
-                thread::sleep(time::Duration::from_millis(HEARTBEAT_INTERVAL));
+                tokio::time::sleep(Duration::from_millis(HEARTBEAT_INTERVAL / 5)).await;
             }
         })
     };
@@ -86,20 +286,28 @@ where
         host,
         port,
         should_register,
+        ..
     } = cfg;
 
     let addr = format!("{}:{}", host, port).parse()?;
+    let mut server = Server::builder();
+    if let Some(tls_config) = tls::server_config(service_group, service_name)? {
+        server = server.tls_config(tls_config)?;
+    }
 
     // Serve server on another task(thread) with a shutdown message channel
     let name = service_name.clone();
     let group = service_group.clone();
     let (shutdown_send, shutdown_recv) = oneshot::channel();
-    let server_task = tokio::spawn(async move {
+    let (mut health, health_service) = tonic_health::server::health_reporter();
+    set_status::<S>(&mut health, ServingStatus::Serving).await;
+    let mut server_task = tokio::spawn(async move {
         println!(
             "dst-pfm::serve_with_shutdown: serving {}/{} at {}",
             group, name, addr
         );
-        Server::builder()
+        server
+            .add_service(health_service)
             .add_service(service)
             .serve_with_shutdown(addr, shutdown_recv.map(drop))
             .await
@@ -107,46 +315,76 @@ where
     });
 
     // Wait for either server_task finish or ctrl_c is pressed
-    tokio::select! {
-        _ = tokio::signal::ctrl_c() => {
-            // Send shutdown signal
-            let _ = shutdown_send.send(());
-        },
-        _ = server_task => {
-        }
-    }
+    let interrupted = tokio::select! {
+        _ = tokio::signal::ctrl_c() => true,
+        _ = &mut server_task => false,
+    };
 
     println!(
         "dst-pfm::serve_with_shutdown: gracefully shutting down service {}/{}",
         service_group, service_name
     );
 
-    let do_shutdown = async {
-        if *should_register {
-            // Stop registering heartbeat
-            drop(register_heartbeat_task);
+    // Out of rotation first, so no new calls come in while the in-flight ones finish
+    if let Some(gossip) = &gossip {
+        println!(
+            "dst_pfm::serve_with_shutdown: leaving the gossip at {}...",
+            gossip.local_addr()
+        );
+        gossip.leave().await;
+    } else if *should_register {
+        println!(
+            "dst_pfm::serve_with_shutdown: draining {}/{} ({})...",
+            service_group,
+            service_name,
+            cfg.instance_id()
+        );
+        if let Err(e) = set_instance_state(cfg, svc_dsc::InstanceState::Draining).await {
+            // Callers then find out from the closed connections instead
+            println!(
+                "dst_pfm::serve_with_shutdown: cannot set the service draining: {}",
+                e
+            );
+        }
+    }
 
-            // Get SerDict client,
-            let mut svc_dsc_client = svc_dsc::client::client()
-                .await
-                .expect("dst_pfm::serve_with_shutdown: cannot get svc_dsc client");
+    if interrupted {
+        // Checkers see the service going away before its connections do
+        set_status::<S>(&mut health, ServingStatus::NotServing).await;
 
-            // Deregister service
+        // Stop accepting connections, and wait for the calls already made
+        let _ = shutdown_send.send(());
+        if tokio::time::timeout(drain_timeout, &mut server_task)
+            .await
+            .is_err()
+        {
             println!(
-                "dst_pfm::serve_with_shutdown: deregistering {}/{}...",
-                service_group, service_name
+                "dst_pfm::serve_with_shutdown: calls still in flight after {:?}, dropping them",
+                drain_timeout
             );
-            svc_dsc_client
-                .deregister_service(svc_dsc::DeregisterServiceRequest {
-                    group: service_group.clone(),
-                    name: service_name.clone(),
-                })
-                .await
-                .expect("dst_pfm::serve_with_shutdown: cannot deregister service");
+            server_task.abort();
         }
-    };
+    }
 
-    do_shutdown.await;
+    if gossip.is_none() && *should_register {
+        // Stop renewing the lease
+        register_heartbeat_task.abort();
+
+        // Deregister service
+        println!(
+            "dst_pfm::serve_with_shutdown: deregistering {}/{} ({})...",
+            service_group,
+            service_name,
+            cfg.instance_id()
+        );
+        if let Err(e) = deregister_service(cfg).await {
+            // svc-dsc expires the instance after a heartbeat interval anyway
+            println!(
+                "dst_pfm::serve_with_shutdown: cannot deregister service: {}",
+                e
+            );
+        }
+    }
 
     Ok(())
 }
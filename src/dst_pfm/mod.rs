@@ -1,2 +1,7 @@
+pub mod election;
+pub mod gossip;
 pub mod lib;
+pub mod resolver;
+pub mod tls;
+pub use election::run_as_leader;
 pub use lib::{serve_with_shutdown, ServiceConfig};
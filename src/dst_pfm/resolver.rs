@@ -0,0 +1,79 @@
+use std::{
+    env,
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
+
+use tokio::sync::watch;
+
+use super::gossip::{Gossip, GossipConfig};
+use crate::svc_dsc::{client::resolver::SerDictResolver, gen::ServiceInstance};
+
+pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+// Where clients learn the live instances of a service: svc-dsc, or gossip between the services
+// themselves
+#[tonic::async_trait]
+pub trait Resolver: Send + Sync {
+    // The live instances of group/name, sorted by instance id. The receiver sees every change for
+    // as long as it is kept.
+    async fn resolve(
+        &self,
+        group: &str,
+        name: &str,
+    ) -> Result<watch::Receiver<Vec<ServiceInstance>>, BoxError>;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mode {
+    SerDict,
+    Gossip,
+}
+
+impl FromStr for Mode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "serdict" => Ok(Self::SerDict),
+            "gossip" => Ok(Self::Gossip),
+            _ => Err(format!(
+                "unknown discovery mode {s:?}, expected serdict or gossip"
+            )),
+        }
+    }
+}
+
+// SERVICE_DISCOVERY_MODE, serdict by default
+pub fn mode() -> Result<Mode, String> {
+    // Settings may come from .env too
+    let _ = dotenv::dotenv();
+    match env::var("SERVICE_DISCOVERY_MODE") {
+        Ok(mode) => mode.parse(),
+        Err(_) => Ok(Mode::SerDict),
+    }
+}
+
+static RESOLVER: Mutex<Option<Arc<dyn Resolver>>> = Mutex::new(None);
+
+// Makes every channel of the process resolve services with resolver
+pub fn set_resolver(resolver: Arc<dyn Resolver>) {
+    *RESOLVER.lock().unwrap() = Some(resolver);
+}
+
+// The resolver of this process. Without one set, processes that only call services get one for
+// SERVICE_DISCOVERY_MODE, joining the gossip without advertising anything.
+pub async fn resolver() -> Result<Arc<dyn Resolver>, BoxError> {
+    if let Some(resolver) = RESOLVER.lock().unwrap().clone() {
+        return Ok(resolver);
+    }
+
+    let resolver: Arc<dyn Resolver> = match mode()? {
+        Mode::SerDict => Arc::new(SerDictResolver),
+        Mode::Gossip => Arc::new(Gossip::start(GossipConfig::from_env()?, None).await?),
+    };
+
+    // Another call may have raced us here, keep whichever got in first
+    let mut current = RESOLVER.lock().unwrap();
+    Ok(Arc::clone(current.get_or_insert(resolver)))
+}
@@ -0,0 +1,174 @@
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Mutex,
+};
+
+use thiserror::Error;
+use tonic::{
+    transport::{Certificate, ClientTlsConfig, Endpoint, Identity, ServerTlsConfig},
+    Request,
+};
+use x509_parser::{certificate::X509Certificate, prelude::FromDer};
+
+// Every service has an identity, its group/name. Its certificate is issued for
+// <name>.<group>.svc.local, which clients check the server against.
+//
+// Configuration:
+//   TLS_MODE      off (default), tls, or mtls to also require client certificates
+//   TLS_CERT_DIR  where dst-pfm-ca puts certificates, "certs" by default:
+//                 ca.pem, and <group>/<name>.pem and .key for each identity
+//   TLS_CA_CERT   the CA to trust, <TLS_CERT_DIR>/ca.pem by default
+//   TLS_CERT      this process' certificate and key, those of its identity in TLS_CERT_DIR by
+//   TLS_KEY       default
+//   TLS_IDENTITY  identity of processes that only call services, like "client/cli"
+
+pub const DEFAULT_CERT_DIR: &str = "certs";
+
+// Set by serve_with_shutdown, clients present the certificate of the service they run in
+static IDENTITY: Mutex<Option<(String, String)>> = Mutex::new(None);
+
+#[derive(Error, Debug)]
+pub enum TlsError {
+    #[error("unknown TLS_MODE {0:?}, expected off, tls or mtls")]
+    Mode(String),
+    #[error("TLS_IDENTITY {0:?} is not group/name")]
+    Identity(String),
+    #[error("cannot read {path:?}: {source}")]
+    Read {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("invalid address: {0}")]
+    Uri(#[from] http::uri::InvalidUri),
+    #[error(transparent)]
+    Transport(#[from] tonic::transport::Error),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mode {
+    Off,
+    Tls,
+    Mtls,
+}
+
+impl FromStr for Mode {
+    type Err = TlsError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(Self::Off),
+            "tls" => Ok(Self::Tls),
+            "mtls" => Ok(Self::Mtls),
+            _ => Err(TlsError::Mode(s.to_string())),
+        }
+    }
+}
+
+pub fn mode() -> Result<Mode, TlsError> {
+    // Settings may come from .env too
+    let _ = dotenv::dotenv();
+    match env::var("TLS_MODE") {
+        Ok(mode) => mode.parse(),
+        Err(_) => Ok(Mode::Off),
+    }
+}
+
+pub fn set_identity(group: &str, name: &str) {
+    *IDENTITY.lock().unwrap() = Some((group.to_string(), name.to_string()));
+}
+
+// The name a certificate is issued for, and checked against. Underscores aren't allowed in DNS
+// names.
+pub fn server_name(group: &str, name: &str) -> String {
+    format!("{}.{}.svc.local", name, group).replace('_', "-")
+}
+
+pub fn cert_dir() -> PathBuf {
+    env::var("TLS_CERT_DIR")
+        .unwrap_or_else(|_| DEFAULT_CERT_DIR.to_string())
+        .into()
+}
+
+// Certificate and key of group/name in dir
+pub fn cert_paths(dir: &Path, group: &str, name: &str) -> (PathBuf, PathBuf) {
+    let base = dir.join(group).join(name);
+    (base.with_extension("pem"), base.with_extension("key"))
+}
+
+fn read(path: PathBuf) -> Result<Vec<u8>, TlsError> {
+    fs::read(&path).map_err(|source| TlsError::Read { path, source })
+}
+
+fn ca() -> Result<Certificate, TlsError> {
+    let path = match env::var("TLS_CA_CERT") {
+        Ok(path) => path.into(),
+        Err(_) => cert_dir().join("ca.pem"),
+    };
+    Ok(Certificate::from_pem(read(path)?))
+}
+
+// TLS_CERT and TLS_KEY, or the certificate of group/name
+fn identity(group: &str, name: &str) -> Result<Identity, TlsError> {
+    let (cert, key) = cert_paths(&cert_dir(), group, name);
+    let cert = env::var("TLS_CERT").map_or(cert, PathBuf::from);
+    let key = env::var("TLS_KEY").map_or(key, PathBuf::from);
+    Ok(Identity::from_pem(read(cert)?, read(key)?))
+}
+
+// This process' identity, None when it has none to present
+fn client_identity() -> Result<Option<Identity>, TlsError> {
+    if let Some((group, name)) = IDENTITY.lock().unwrap().clone() {
+        return identity(&group, &name).map(Some);
+    }
+    match env::var("TLS_IDENTITY") {
+        Ok(id) => match id.split_once('/') {
+            Some((group, name)) => identity(group, name).map(Some),
+            None => Err(TlsError::Identity(id)),
+        },
+        Err(_) => Ok(None),
+    }
+}
+
+// How the server of group/name should accept connections, None for plaintext
+pub fn server_config(group: &str, name: &str) -> Result<Option<ServerTlsConfig>, TlsError> {
+    let mode = mode()?;
+    if mode == Mode::Off {
+        return Ok(None);
+    }
+
+    let mut config = ServerTlsConfig::new().identity(identity(group, name)?);
+    if mode == Mode::Mtls {
+        config = config.client_ca_root(ca()?);
+    }
+    Ok(Some(config))
+}
+
+// The group/name a client's certificate was issued to, when it connected with one. Only mtls
+// servers ask for them.
+pub fn peer_identity<T>(request: &Request<T>) -> Option<(String, String)> {
+    let certs = request.peer_certs()?;
+    let (_, cert) = X509Certificate::from_der(certs.first()?.get_ref()).ok()?;
+    let common_name = cert.subject().iter_common_name().next()?.as_str().ok()?;
+    let (group, name) = common_name.split_once('/')?;
+    Some((group.to_string(), name.to_string()))
+}
+
+// An endpoint for the server of group/name at addr (host:port), over TLS when it is on
+pub fn endpoint(addr: &str, group: &str, name: &str) -> Result<Endpoint, TlsError> {
+    let mode = mode()?;
+    if mode == Mode::Off {
+        return Ok(Endpoint::from_shared(format!("http://{}", addr))?);
+    }
+
+    let mut config = ClientTlsConfig::new()
+        .ca_certificate(ca()?)
+        .domain_name(server_name(group, name));
+    if mode == Mode::Mtls {
+        if let Some(identity) = client_identity()? {
+            config = config.identity(identity);
+        }
+    }
+    Ok(Endpoint::from_shared(format!("https://{}", addr))?.tls_config(config)?)
+}
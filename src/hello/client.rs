@@ -2,13 +2,17 @@ pub mod hello {
     tonic::include_proto!("hello");
 }
 
+use dist_rust_buted::dst_pfm::tls;
 use hello::greeter_client::GreeterClient;
 use hello::SayRequest;
 use tonic::Request;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let mut client = GreeterClient::connect("http://[::1]:50051").await?;
+    let channel = tls::endpoint("[::1]:50051", "starter", "greeter")?
+        .connect()
+        .await?;
+    let mut client = GreeterClient::new(channel);
 
     let req = Request::new(SayRequest {
         name: "Dolpheyn".into(),
@@ -42,6 +42,8 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         host: "[::1]".into(),
         port: 50051,
         should_register: true,
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        ..Default::default()
     };
 
     serve_with_shutdown(service, &cfg).await?;
@@ -0,0 +1,374 @@
+use futures::future::poll_fn;
+use hyper::{service::Service, Body};
+use rand::Rng;
+use thiserror::Error;
+use tokio::sync::{oneshot, watch};
+use tonic::{body::BoxBody, transport::Channel};
+
+use crate::{
+    dst_pfm::{
+        resolver::{self, BoxError, Resolver},
+        tls,
+    },
+    svc_dsc::gen::ServiceInstance,
+};
+
+use std::{
+    collections::BTreeMap,
+    env,
+    future::Future,
+    pin::Pin,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, RwLock, Weak,
+    },
+    task::{Context, Poll},
+};
+
+// How a call picks one of the instances of a service
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Policy {
+    RoundRobin,
+    Random,
+    // The instance with the fewest calls in flight from this process
+    LeastOutstanding,
+}
+
+impl FromStr for Policy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "round_robin" => Ok(Self::RoundRobin),
+            "random" => Ok(Self::Random),
+            "least_outstanding" => Ok(Self::LeastOutstanding),
+            _ => Err(format!(
+                "unknown policy {s:?}, expected round_robin, random or least_outstanding"
+            )),
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum BalanceError {
+    #[error("no live instance of {group}/{name} is registered")]
+    NoInstance { group: String, name: String },
+}
+
+#[derive(Clone)]
+struct Backend {
+    instance: ServiceInstance,
+    channel: Channel,
+    outstanding: Arc<AtomicUsize>,
+}
+
+impl Backend {
+    // An instance of group/name
+    fn new(instance: ServiceInstance, group: &str, name: &str) -> Result<Backend, BoxError> {
+        let addr = format!("{}:{}", instance.ip, instance.port);
+        let channel = tls::endpoint(&addr, group, name)?.connect_lazy();
+
+        Ok(Self {
+            instance,
+            channel,
+            outstanding: Arc::new(AtomicUsize::new(0)),
+        })
+    }
+}
+
+struct Shared {
+    group: String,
+    name: String,
+    policy: Policy,
+    backends: RwLock<Vec<Backend>>,
+    next: AtomicUsize,
+    // Dropped along with the last BalancedChannel, which stops following the resolver
+    _closed: oneshot::Sender<()>,
+}
+
+impl Shared {
+    fn pick(&self) -> Result<Backend, BalanceError> {
+        let backends = self.backends.read().unwrap();
+        if backends.is_empty() {
+            return Err(BalanceError::NoInstance {
+                group: self.group.clone(),
+                name: self.name.clone(),
+            });
+        }
+
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let index = match self.policy {
+            Policy::RoundRobin => start % backends.len(),
+            Policy::Random => rand::thread_rng().gen_range(0..backends.len()),
+            // Ties go round-robin, so idle instances all get picked
+            Policy::LeastOutstanding => (0..backends.len())
+                .map(|offset| (start % backends.len() + offset) % backends.len())
+                .min_by_key(|index| backends[*index].outstanding.load(Ordering::Relaxed))
+                .unwrap_or_default(),
+        };
+
+        Ok(backends[index].clone())
+    }
+
+    // Replaces every backend, keeping the connections of instances that didn't move
+    fn reset(&self, instances: Vec<ServiceInstance>) {
+        let mut backends = self.backends.write().unwrap();
+        let mut current = backends
+            .drain(..)
+            .map(|backend| (backend.instance.instance_id.clone(), backend))
+            .collect::<BTreeMap<_, _>>();
+        for instance in instances {
+            match current.remove(&instance.instance_id) {
+                Some(backend) if backend.instance == instance => backends.push(backend),
+                _ => match Backend::new(instance, &self.group, &self.name) {
+                    Ok(backend) => backends.push(backend),
+                    Err(e) => println!("svc_dsc::client::balance: skipping instance: {}", e),
+                },
+            }
+        }
+    }
+}
+
+// A channel spreading calls over every live instance of a service, for use with any generated
+// client: `AddClient::new(channel)`. Instances are followed through svc-dsc's WatchServices.
+#[derive(Clone)]
+pub struct BalancedChannel {
+    shared: Arc<Shared>,
+}
+
+impl BalancedChannel {
+    // Addresses of the instances calls are currently spread over
+    pub fn instances(&self) -> Vec<ServiceInstance> {
+        let backends = self.shared.backends.read().unwrap();
+        backends
+            .iter()
+            .map(|backend| backend.instance.clone())
+            .collect()
+    }
+}
+
+pub async fn balanced_channel(
+    resolver: &dyn Resolver,
+    group: &str,
+    name: &str,
+    policy: Policy,
+) -> Result<BalancedChannel, Box<dyn std::error::Error>> {
+    let (closed_send, closed_recv) = oneshot::channel();
+    let shared = Arc::new(Shared {
+        group: group.to_string(),
+        name: name.to_string(),
+        policy,
+        backends: RwLock::new(Vec::new()),
+        next: AtomicUsize::new(0),
+        _closed: closed_send,
+    });
+
+    let mut instances = resolver
+        .resolve(group, name)
+        .await
+        .map_err(|e| e as Box<dyn std::error::Error>)?;
+    let listed = instances.borrow_and_update().clone();
+    shared.reset(listed);
+    tokio::spawn(follow(Arc::downgrade(&shared), instances, closed_recv));
+
+    Ok(BalancedChannel { shared })
+}
+
+// One channel per service for the whole process, resolved by the process' resolver and balanced
+// with the SERVICE_LB_POLICY policy
+pub async fn shared_channel(
+    group: &str,
+    name: &str,
+) -> Result<BalancedChannel, Box<dyn std::error::Error>> {
+    static CHANNELS: Mutex<BTreeMap<(String, String), BalancedChannel>> =
+        Mutex::new(BTreeMap::new());
+
+    let key = (group.to_string(), name.to_string());
+    if let Some(channel) = CHANNELS.lock().unwrap().get(&key) {
+        return Ok(channel.clone());
+    }
+
+    let policy = match env::var("SERVICE_LB_POLICY") {
+        Ok(policy) => policy.parse()?,
+        Err(_) => Policy::RoundRobin,
+    };
+    let resolver = resolver::resolver()
+        .await
+        .map_err(|e| e as Box<dyn std::error::Error>)?;
+    let channel = balanced_channel(resolver.as_ref(), group, name, policy).await?;
+
+    // Another call may have raced us here, keep whichever got in first
+    let mut channels = CHANNELS.lock().unwrap();
+    Ok(channels.entry(key).or_insert(channel).clone())
+}
+
+// Keeps the backends in line with the resolver until the channel is dropped
+async fn follow(
+    shared: Weak<Shared>,
+    mut instances: watch::Receiver<Vec<ServiceInstance>>,
+    mut closed: oneshot::Receiver<()>,
+) {
+    loop {
+        let changed = tokio::select! {
+            _ = &mut closed => return,
+            changed = instances.changed() => changed,
+        };
+        let Some(shared) = shared.upgrade() else {
+            return;
+        };
+        if changed.is_err() {
+            // The backends we know of keep serving
+            println!(
+                "svc_dsc::client::balance: stopped following {}/{}",
+                shared.group, shared.name
+            );
+            return;
+        }
+
+        let latest = instances.borrow_and_update().clone();
+        shared.reset(latest);
+    }
+}
+
+// Counts a call as in flight until it is dropped
+struct Outstanding(Arc<AtomicUsize>);
+
+impl Outstanding {
+    fn start(count: Arc<AtomicUsize>) -> Outstanding {
+        count.fetch_add(1, Ordering::Relaxed);
+        Self(count)
+    }
+}
+
+impl Drop for Outstanding {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Service<http::Request<BoxBody>> for BalancedChannel {
+    type Response = http::Response<Body>;
+    type Error = BoxError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    // Instances are picked per call, each one's channel is waited on there
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: http::Request<BoxBody>) -> Self::Future {
+        let backend = self.shared.pick();
+
+        Box::pin(async move {
+            let Backend {
+                mut channel,
+                outstanding,
+                ..
+            } = backend?;
+            let _outstanding = Outstanding::start(outstanding);
+
+            poll_fn(|cx| channel.poll_ready(cx)).await?;
+            Ok(channel.call(request).await?)
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::sync::oneshot;
+
+    use super::{BalanceError, Outstanding, Policy, Shared};
+    use crate::svc_dsc::gen::ServiceInstance;
+
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, RwLock,
+    };
+
+    fn shared(policy: Policy, ports: &[u32]) -> Shared {
+        let shared = Shared {
+            group: "math".into(),
+            name: "add".into(),
+            policy,
+            backends: RwLock::new(Vec::new()),
+            next: AtomicUsize::new(0),
+            _closed: oneshot::channel().0,
+        };
+        shared.reset(ports.iter().map(|port| instance(*port)).collect());
+        shared
+    }
+
+    fn instance(port: u32) -> ServiceInstance {
+        ServiceInstance {
+            instance_id: format!("127.0.0.1:{port}"),
+            ip: "127.0.0.1".into(),
+            port,
+            ..Default::default()
+        }
+    }
+
+    fn picked(shared: &Shared, times: usize) -> Vec<u32> {
+        (0..times)
+            .map(|_| shared.pick().unwrap().instance.port)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn it_rotates_round_robin() {
+        let shared = shared(Policy::RoundRobin, &[1, 2, 3]);
+        assert_eq!(picked(&shared, 7), vec![1, 2, 3, 1, 2, 3, 1]);
+
+        // The counter wraps around instead of overflowing
+        shared.next.store(usize::MAX, Ordering::Relaxed);
+        assert_eq!(picked(&shared, 2), vec![1, 1]);
+
+        // Instances that stay keep their connection and count
+        let kept = shared.backends.read().unwrap()[2].clone();
+        shared.reset(vec![instance(3), instance(4)]);
+        let backends = shared.backends.read().unwrap();
+        assert_eq!(
+            backends.iter().map(|b| b.instance.port).collect::<Vec<_>>(),
+            vec![3, 4]
+        );
+        assert!(Arc::ptr_eq(&backends[0].outstanding, &kept.outstanding));
+    }
+
+    #[tokio::test]
+    async fn it_picks_the_least_outstanding() {
+        let shared = shared(Policy::LeastOutstanding, &[1, 2, 3]);
+
+        // Idle instances take turns
+        assert_eq!(picked(&shared, 3), vec![1, 2, 3]);
+
+        let first = Outstanding::start(shared.pick().unwrap().outstanding);
+        let second = Outstanding::start(shared.pick().unwrap().outstanding);
+        let third = Outstanding::start(shared.pick().unwrap().outstanding);
+        let busiest = Outstanding::start(shared.pick().unwrap().outstanding);
+        assert_eq!(busiest.0.load(Ordering::Relaxed), 2);
+        // The instance with a call still in flight is passed over
+        drop(busiest);
+        drop(first);
+        drop(third);
+        assert!(!picked(&shared, 4).contains(&2));
+        shared.next.store(usize::MAX, Ordering::Relaxed);
+        assert!(!picked(&shared, 2).contains(&2));
+        drop(second);
+
+        let backends = shared.backends.read().unwrap();
+        assert!(backends
+            .iter()
+            .all(|backend| backend.outstanding.load(Ordering::Relaxed) == 0));
+    }
+
+    #[tokio::test]
+    async fn it_fails_without_instances() {
+        for policy in [Policy::RoundRobin, Policy::Random, Policy::LeastOutstanding] {
+            let shared = shared(policy, &[]);
+            let Err(BalanceError::NoInstance { group, name }) = shared.pick() else {
+                panic!("picked an instance out of none");
+            };
+            assert_eq!((group.as_str(), name.as_str()), ("math", "add"));
+        }
+    }
+}
@@ -0,0 +1,121 @@
+use tokio::sync::watch;
+use tonic::Streaming;
+
+use crate::{
+    dst_pfm::resolver::BoxError,
+    svc_dsc::{
+        gen::{kv_event::Kind, KvEvent, KvListRequest, KvWatchRequest},
+        HEARTBEAT_INTERVAL,
+    },
+};
+
+use std::{collections::BTreeMap, time::Duration};
+
+// Values by key
+pub type Config = BTreeMap<String, String>;
+
+// Follows the configuration keys starting with prefix through svc-dsc's KvWatch. The receiver
+// sees every change for as long as it is kept, so services pick up new values without a restart.
+pub async fn follow(prefix: &str) -> Result<watch::Receiver<Config>, BoxError> {
+    let (events, revision, config) = subscribe(prefix).await?;
+    let (updates, recv) = watch::channel(config.clone());
+    tokio::spawn(apply(prefix.to_string(), events, revision, config, updates));
+
+    Ok(recv)
+}
+
+// Lists the keys under prefix, and returns the changes that come after the listing along with
+// the revision it was taken at
+async fn subscribe(prefix: &str) -> Result<(Streaming<KvEvent>, u64, Config), BoxError> {
+    let mut client = super::client().await.map_err(|e| e.to_string())?;
+
+    // Watch before listing, so no change slips in between the two calls
+    let events = client
+        .kv_watch(KvWatchRequest {
+            prefix: prefix.to_string(),
+            ..Default::default()
+        })
+        .await?
+        .into_inner();
+
+    let listing = client
+        .kv_list(KvListRequest {
+            prefix: prefix.to_string(),
+            ..Default::default()
+        })
+        .await?
+        .into_inner();
+    let config = listing
+        .entries
+        .into_iter()
+        .map(|entry| (entry.key, entry.value))
+        .collect();
+
+    Ok((events, listing.revision, config))
+}
+
+// Applies changes past revision to config until every receiver of updates is dropped
+async fn apply(
+    prefix: String,
+    mut events: Streaming<KvEvent>,
+    mut revision: u64,
+    mut config: Config,
+    updates: watch::Sender<Config>,
+) {
+    loop {
+        let event = tokio::select! {
+            _ = updates.closed() => return,
+            event = events.message() => event,
+        };
+
+        match event {
+            Ok(Some(event)) => {
+                let Some(entry) = event.entry.clone() else {
+                    continue;
+                };
+                // Already part of the listing
+                if entry.mod_revision <= revision {
+                    continue;
+                }
+                revision = entry.mod_revision;
+                match event.kind() {
+                    Kind::Put => {
+                        config.insert(entry.key, entry.value);
+                    }
+                    Kind::Delete => {
+                        config.remove(&entry.key);
+                    }
+                }
+            }
+            Ok(None) | Err(_) => {
+                // svc-dsc went away or we fell behind, list again once it answers. The values we
+                // know of stay meanwhile.
+                println!(
+                    "svc_dsc::client::config: lost track of {:?}, resubscribing",
+                    prefix
+                );
+                loop {
+                    tokio::select! {
+                        _ = updates.closed() => return,
+                        _ = tokio::time::sleep(Duration::from_millis(HEARTBEAT_INTERVAL)) => {}
+                    }
+                    match subscribe(&prefix).await {
+                        Ok((resubscribed, listed_at, listed)) => {
+                            events = resubscribed;
+                            revision = listed_at;
+                            config = listed;
+                            break;
+                        }
+                        Err(e) => println!("svc_dsc::client::config: {}", e),
+                    }
+                }
+            }
+        }
+
+        updates.send_if_modified(|current| {
+            let modified = *current != config;
+            *current = config.clone();
+            modified
+        });
+    }
+}
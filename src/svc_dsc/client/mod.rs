@@ -0,0 +1,112 @@
+pub mod balance;
+pub mod config;
+pub mod resolver;
+
+use std::env;
+
+use dotenv::dotenv;
+use tonic::{
+    codegen::InterceptedService,
+    metadata::{Ascii, MetadataValue},
+    service::Interceptor,
+    transport::Channel,
+    Request, Status,
+};
+
+use crate::{
+    dst_pfm::tls,
+    svc_dsc::{
+        gen::ser_dict_client::SerDictClient, DEFAULT_NAMESPACE, NAMESPACE_HEADER, SERVICE_GROUP,
+        SERVICE_NAME,
+    },
+};
+
+pub type SerDictChannel = InterceptedService<Channel, Identity>;
+
+// Sent along with every request: the namespace, so requests that leave it empty land in the
+// client's namespace rather than the default one, and the token svc-dsc's ACL knows the caller by
+#[derive(Clone)]
+pub struct Identity {
+    namespace: MetadataValue<Ascii>,
+    authorization: Option<MetadataValue<Ascii>>,
+}
+
+impl Identity {
+    // SERVICE_DISCOVERY_NAMESPACE, or the default namespace, and SERVICE_DISCOVERY_TOKEN if set
+    pub fn from_env() -> Result<Identity, Box<dyn std::error::Error>> {
+        let namespace = env::var("SERVICE_DISCOVERY_NAMESPACE")
+            .unwrap_or_else(|_| DEFAULT_NAMESPACE.to_string());
+        let identity = Identity {
+            namespace: namespace.parse()?,
+            authorization: None,
+        };
+        match env::var("SERVICE_DISCOVERY_TOKEN") {
+            Ok(token) => identity.with_token(&token),
+            Err(_) => Ok(identity),
+        }
+    }
+
+    pub fn with_token(mut self, token: &str) -> Result<Identity, Box<dyn std::error::Error>> {
+        self.authorization = Some(format!("Bearer {}", token).parse()?);
+        Ok(self)
+    }
+}
+
+impl Interceptor for Identity {
+    fn call(&mut self, mut req: Request<()>) -> Result<Request<()>, Status> {
+        req.metadata_mut()
+            .insert(NAMESPACE_HEADER, self.namespace.clone());
+        if let Some(authorization) = &self.authorization {
+            req.metadata_mut()
+                .insert("authorization", authorization.clone());
+        }
+        Ok(req)
+    }
+}
+
+pub async fn client() -> Result<SerDictClient<SerDictChannel>, Box<dyn std::error::Error>> {
+    dotenv().expect("missing .env file. Create .env or run from the root of project");
+    let identity = Identity::from_env()?;
+    connect(identity).await
+}
+
+pub async fn connect(
+    identity: Identity,
+) -> Result<SerDictClient<SerDictChannel>, Box<dyn std::error::Error>> {
+    // Every node of a svc-dsc cluster serves requests, use the first one that answers
+    let addrs = match env::var("SERVICE_DISCOVERY_ADDRS") {
+        Ok(addrs) => addrs
+            .split(',')
+            .map(|addr| addr.trim().to_string())
+            .collect(),
+        Err(_) => {
+            let host =
+                env::var("SERVICE_DISCOVERY_HOST").expect("SERVICE_DISCOVERY_HOST must be set");
+            let port =
+                env::var("SERVICE_DISCOVERY_PORT").expect("SERVICE_DISCOVERY_PORT must be set");
+            vec![format!("{}:{}", host, port)]
+        }
+    };
+
+    let mut last_err = None;
+    for addr in addrs {
+        let endpoint = tls::endpoint(&addr, SERVICE_GROUP, SERVICE_NAME)?;
+        match endpoint.connect().await {
+            Ok(channel) => return Ok(SerDictClient::with_interceptor(channel, identity)),
+            Err(e) => last_err = Some(e),
+        }
+    }
+
+    Err(last_err
+        .expect("SERVICE_DISCOVERY_ADDRS must not be empty")
+        .into())
+}
+
+// A client of the svc-dsc at addr, connecting on first use
+pub fn connect_lazy(
+    addr: &str,
+    identity: Identity,
+) -> Result<SerDictClient<SerDictChannel>, tls::TlsError> {
+    let channel = tls::endpoint(addr, SERVICE_GROUP, SERVICE_NAME)?.connect_lazy();
+    Ok(SerDictClient::with_interceptor(channel, identity))
+}
@@ -0,0 +1,140 @@
+use tokio::sync::watch;
+use tonic::{Code, Streaming};
+
+use crate::{
+    dst_pfm::resolver::{BoxError, Resolver},
+    svc_dsc::{
+        gen::{
+            service_event::Kind, GetServiceRequest, InstanceState, ServiceEvent, ServiceInstance,
+            WatchServicesRequest,
+        },
+        HEARTBEAT_INTERVAL,
+    },
+};
+
+use std::{collections::BTreeMap, time::Duration};
+
+// By instance id
+type Instances = BTreeMap<String, ServiceInstance>;
+
+// Follows services through svc-dsc's WatchServices
+pub struct SerDictResolver;
+
+#[tonic::async_trait]
+impl Resolver for SerDictResolver {
+    async fn resolve(
+        &self,
+        group: &str,
+        name: &str,
+    ) -> Result<watch::Receiver<Vec<ServiceInstance>>, BoxError> {
+        let (events, instances) = subscribe(group, name).await?;
+        let (updates, recv) = watch::channel(instances.values().cloned().collect());
+        tokio::spawn(follow(
+            group.to_string(),
+            name.to_string(),
+            events,
+            instances,
+            updates,
+        ));
+
+        Ok(recv)
+    }
+}
+
+// Lists the instances of the service, and returns the changes that come after the listing
+async fn subscribe(
+    group: &str,
+    name: &str,
+) -> Result<(Streaming<ServiceEvent>, Instances), BoxError> {
+    let mut client = super::client().await.map_err(|e| e.to_string())?;
+
+    // Watch before listing, so no change slips in between the two calls
+    let events = client
+        .watch_services(WatchServicesRequest {
+            group: group.to_string(),
+            ..Default::default()
+        })
+        .await?
+        .into_inner();
+
+    let instances = match client
+        .get_service(GetServiceRequest {
+            group: group.to_string(),
+            name: name.to_string(),
+            ..Default::default()
+        })
+        .await
+    {
+        Ok(res) => res.into_inner().instances,
+        Err(status) if status.code() == Code::NotFound => Vec::new(),
+        Err(status) => return Err(status.into()),
+    };
+    let instances = instances
+        .into_iter()
+        .map(|instance| (instance.instance_id.clone(), instance))
+        .collect();
+
+    Ok((events, instances))
+}
+
+// Applies registry changes to instances until every receiver of updates is dropped
+async fn follow(
+    group: String,
+    name: String,
+    mut events: Streaming<ServiceEvent>,
+    mut instances: Instances,
+    updates: watch::Sender<Vec<ServiceInstance>>,
+) {
+    loop {
+        let event = tokio::select! {
+            _ = updates.closed() => return,
+            event = events.message() => event,
+        };
+
+        match event {
+            Ok(Some(event)) => {
+                let Some(instance) = event.instance.clone() else {
+                    continue;
+                };
+                if event.name != name {
+                    continue;
+                }
+                match event.kind() {
+                    // Only active instances take calls
+                    Kind::Registered | Kind::StateChanged
+                        if instance.state() == InstanceState::Active =>
+                    {
+                        instances.insert(instance.instance_id.clone(), instance);
+                    }
+                    Kind::Registered | Kind::StateChanged | Kind::Deregistered | Kind::Expired => {
+                        instances.remove(&instance.instance_id);
+                    }
+                }
+            }
+            Ok(None) | Err(_) => {
+                // svc-dsc went away or we fell behind, list again once it answers. The instances
+                // we know of keep serving meanwhile.
+                println!(
+                    "svc_dsc::client::resolver: lost track of {}/{}, resubscribing",
+                    group, name
+                );
+                loop {
+                    tokio::select! {
+                        _ = updates.closed() => return,
+                        _ = tokio::time::sleep(Duration::from_millis(HEARTBEAT_INTERVAL)) => {}
+                    }
+                    match subscribe(&group, &name).await {
+                        Ok((resubscribed, listed)) => {
+                            events = resubscribed;
+                            instances = listed;
+                            break;
+                        }
+                        Err(e) => println!("svc_dsc::client::resolver: {}", e),
+                    }
+                }
+            }
+        }
+
+        updates.send_replace(instances.values().cloned().collect());
+    }
+}
@@ -9,5 +9,51 @@ pub mod server;
 // in millis
 pub const HEARTBEAT_INTERVAL: u64 = 5000;
 
+// in millis, for registrations that don't ask for a TTL
+pub const LEASE_TTL: u64 = HEARTBEAT_INTERVAL;
+
+// in millis, default for SERVICE_DISCOVERY_HEALTH_CHECK_INTERVAL
+pub const HEALTH_CHECK_INTERVAL: u64 = 2000;
+
+// Failed probes in a row before an instance is unhealthy, default for
+// SERVICE_DISCOVERY_HEALTH_CHECK_FAILURES
+pub const HEALTH_CHECK_FAILURES: u32 = 3;
+
+// in millis, how often the registry summaries of federated datacenters are pulled
+pub const FEDERATION_SYNC_INTERVAL: u64 = HEARTBEAT_INTERVAL;
+
+// in millis
+pub const SNAPSHOT_INTERVAL: u64 = 60000;
+
+// in millis, how often SERVICE_DISCOVERY_STATIC_FILE is checked for changes
+pub const STATIC_FILE_POLL_INTERVAL: u64 = 1000;
+
+// Independently locked parts of the in-memory registry, services are spread over them by key
+pub const REGISTRY_SHARDS: usize = 16;
+
+// Default for SERVICE_DISCOVERY_SQLITE_PATH
+pub const SQLITE_PATH: &str = "svc-dsc.db";
+
+// How many registry events a slow watcher can lag behind before its stream is cut
+pub const WATCH_BUFFER_SIZE: usize = 1024;
+
+// Registry events kept for ListEvents, default for SERVICE_DISCOVERY_EVENT_HISTORY_SIZE
+pub const EVENT_HISTORY_SIZE: usize = 10000;
+
+// Services in a page of ListService that asks for no page size
+pub const DEFAULT_PAGE_SIZE: usize = 100;
+
+// Most services a page of ListService holds, larger page sizes are cut down to it
+pub const MAX_PAGE_SIZE: usize = 1000;
+
+// Namespace of requests that don't name one
+pub const DEFAULT_NAMESPACE: &str = "default";
+
+// Longest namespace, a DNS label
+pub const MAX_NAMESPACE_LEN: usize = 63;
+
+// gRPC metadata carrying the namespace of requests whose namespace field is empty
+pub const NAMESPACE_HEADER: &str = "svc-dsc-namespace";
+
 pub const SERVICE_GROUP: &str = "platform";
 pub const SERVICE_NAME: &str = "service_discovery";
@@ -0,0 +1,254 @@
+use serde::Deserialize;
+use thiserror::Error;
+use tonic::{metadata::MetadataMap, service::Interceptor, Request, Status};
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+
+// Who may do what, loaded from the file at SERVICE_DISCOVERY_ACL_FILE:
+//   {
+//     "principals": [
+//       { "name": "svc-mat", "token": "...", "rules": [
+//         { "group": "math", "permissions": ["read", "register", "deregister"] } ] }
+//     ],
+//     "anonymous": [ { "group": "*", "permissions": ["read"] } ]
+//   }
+// Requests carry their token as "authorization: Bearer <token>", requests without one get the
+// anonymous rules. A group of "*" matches every group. Configuration keys belong to the group
+// named by their first path segment, and take "read" and "write". Leases for locks take
+// "register" on the group they are granted for.
+
+#[derive(Error, Debug)]
+pub enum AclError {
+    #[error("cannot read policy file: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("invalid policy file: {0}")]
+    Parse(#[from] serde_json::Error),
+    #[error("principals {0:?} and {1:?} share a token")]
+    DuplicateToken(String, String),
+}
+
+#[derive(Error, Debug)]
+pub enum AuthError {
+    #[error("expected a bearer token")]
+    MalformedToken,
+    #[error("unknown token")]
+    UnknownToken,
+    #[error("{principal} may not {permission:?} services of group {group:?}")]
+    Denied {
+        principal: String,
+        permission: Permission,
+        group: String,
+    },
+    #[error("lease {0} was granted to another principal or namespace")]
+    ForeignLease(u64),
+}
+
+impl From<AuthError> for Status {
+    fn from(e: AuthError) -> Self {
+        match e {
+            AuthError::Denied { .. } | AuthError::ForeignLease(_) => {
+                Status::permission_denied(e.to_string())
+            }
+            e => Status::unauthenticated(e.to_string()),
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Permission {
+    Read,
+    Register,
+    Deregister,
+    // Putting and deleting configuration keys
+    Write,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+struct Rule {
+    group: String,
+    permissions: Vec<Permission>,
+}
+
+#[derive(Deserialize)]
+struct PrincipalPolicy {
+    name: String,
+    token: String,
+    rules: Vec<Rule>,
+}
+
+#[derive(Deserialize)]
+struct Policy {
+    #[serde(default)]
+    principals: Vec<PrincipalPolicy>,
+    #[serde(default)]
+    anonymous: Vec<Rule>,
+}
+
+// The caller of a request, as put in its extensions by the Authenticator
+#[derive(Debug)]
+pub struct Principal {
+    pub name: String,
+    rules: Vec<Rule>,
+}
+
+impl Principal {
+    pub fn allows(&self, group: &str, permission: Permission) -> bool {
+        self.rules.iter().any(|rule| {
+            (rule.group == "*" || rule.group == group) && rule.permissions.contains(&permission)
+        })
+    }
+}
+
+pub struct Acl {
+    // By token
+    principals: HashMap<String, Arc<Principal>>,
+    anonymous: Arc<Principal>,
+}
+
+impl Acl {
+    pub fn load(path: impl AsRef<Path>) -> Result<Acl, AclError> {
+        Acl::parse(&fs::read_to_string(path)?)
+    }
+
+    pub fn parse(policy: &str) -> Result<Acl, AclError> {
+        let policy: Policy = serde_json::from_str(policy)?;
+
+        let mut principals = HashMap::new();
+        for PrincipalPolicy { name, token, rules } in policy.principals {
+            let principal = Arc::new(Principal { name, rules });
+            if let Some(other) = principals.insert(token, Arc::clone(&principal)) {
+                return Err(AclError::DuplicateToken(
+                    other.name.clone(),
+                    principal.name.clone(),
+                ));
+            }
+        }
+
+        Ok(Acl {
+            principals,
+            anonymous: Arc::new(Principal {
+                name: "anonymous".to_string(),
+                rules: policy.anonymous,
+            }),
+        })
+    }
+
+    pub fn anonymous(&self) -> &Principal {
+        &self.anonymous
+    }
+
+    pub fn authenticate(&self, metadata: &MetadataMap) -> Result<Arc<Principal>, AuthError> {
+        let header = match metadata.get("authorization") {
+            Some(header) => header,
+            None => return Ok(Arc::clone(&self.anonymous)),
+        };
+        let token = header
+            .to_str()
+            .ok()
+            .and_then(|header| header.strip_prefix("Bearer "))
+            .ok_or(AuthError::MalformedToken)?;
+
+        self.principals
+            .get(token.trim())
+            .cloned()
+            .ok_or(AuthError::UnknownToken)
+    }
+}
+
+// Tells SerDict who is calling. Without an ACL every request is let through as is.
+#[derive(Clone)]
+pub struct Authenticator {
+    acl: Option<Arc<Acl>>,
+}
+
+impl Authenticator {
+    pub fn new(acl: Option<Arc<Acl>>) -> Authenticator {
+        Authenticator { acl }
+    }
+}
+
+impl Interceptor for Authenticator {
+    fn call(&mut self, mut req: Request<()>) -> Result<Request<()>, Status> {
+        let acl = match &self.acl {
+            Some(acl) => acl,
+            None => return Ok(req),
+        };
+
+        let principal = acl.authenticate(req.metadata())?;
+        // Requests are logged, tokens shouldn't be
+        req.metadata_mut().remove("authorization");
+        req.extensions_mut().insert(principal);
+        Ok(req)
+    }
+}
+
+// Requests that didn't go through an Authenticator with an ACL are allowed everything
+pub fn check(
+    principal: Option<&Arc<Principal>>,
+    group: &str,
+    permission: Permission,
+) -> Result<(), AuthError> {
+    match principal {
+        Some(principal) if !principal.allows(group, permission) => Err(AuthError::Denied {
+            principal: principal.name.clone(),
+            permission,
+            group: group.to_string(),
+        }),
+        _ => Ok(()),
+    }
+}
+
+pub fn authorize<T>(
+    request: &Request<T>,
+    group: &str,
+    permission: Permission,
+) -> Result<(), AuthError> {
+    check(request.extensions().get(), group, permission)
+}
+
+#[cfg(test)]
+mod test {
+    use tonic::{service::Interceptor, Code, Request, Status};
+
+    use super::{authorize, Acl, Authenticator, Permission};
+
+    use std::sync::Arc;
+
+    #[test]
+    fn it_checks_rules_by_token() {
+        let acl = Acl::parse(
+            r#"{
+                "principals": [
+                    { "name": "svc-mat", "token": "s3cr3t", "rules": [
+                        { "group": "math", "permissions": ["read", "register"] } ] }
+                ],
+                "anonymous": [ { "group": "*", "permissions": ["read"] } ]
+            }"#,
+        )
+        .unwrap();
+        let mut auth = Authenticator::new(Some(Arc::new(acl)));
+
+        let mut req = Request::new(());
+        req.metadata_mut()
+            .insert("authorization", "Bearer s3cr3t".parse().unwrap());
+        let req = auth.call(req).unwrap();
+        assert!(req.metadata().get("authorization").is_none());
+        assert!(authorize(&req, "math", Permission::Register).is_ok());
+        let denied = authorize(&req, "math", Permission::Deregister).unwrap_err();
+        assert_eq!(Status::from(denied).code(), Code::PermissionDenied);
+        assert!(authorize(&req, "hello", Permission::Read).is_err());
+
+        let req = auth.call(Request::new(())).unwrap();
+        assert!(authorize(&req, "hello", Permission::Read).is_ok());
+        assert!(authorize(&req, "math", Permission::Register).is_err());
+
+        let mut req = Request::new(());
+        req.metadata_mut()
+            .insert("authorization", "Bearer guess".parse().unwrap());
+        assert_eq!(auth.call(req).unwrap_err().code(), Code::Unauthenticated);
+    }
+}
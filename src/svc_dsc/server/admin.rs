@@ -0,0 +1,630 @@
+use hyper::{
+    header::CONTENT_TYPE,
+    server::{
+        conn::{AddrIncoming, AddrStream},
+        Builder,
+    },
+    service::{make_service_fn, service_fn},
+    Body, Method, Request as HttpRequest, Response as HttpResponse, Server, StatusCode,
+};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use thiserror::Error;
+use tonic::{
+    metadata::MetadataMap,
+    transport::server::{Connected, TcpConnectInfo},
+    Code, Request, Status,
+};
+
+use crate::{
+    dst_pfm::tls::{self, Mode, TlsError},
+    svc_dsc::gen::{
+        ser_dict_server::SerDict, DeregisterServiceRequest, GetServiceRequest, GetServiceResponse,
+        HealthFilter, InstanceState, ListServiceRequest, RegisterServiceRequest, ServiceInstance,
+    },
+};
+
+use super::{
+    acl::{self, Permission, Principal},
+    serdict::SerDictImpl,
+};
+
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    fmt::Write,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+#[derive(Error, Debug)]
+pub enum AdminError {
+    #[error("the admin API is plain HTTP, with TLS_MODE {0:?} it only binds loopback, not {1}")]
+    Cleartext(Mode, SocketAddr),
+    #[error(transparent)]
+    Tls(#[from] TlsError),
+    #[error(transparent)]
+    Http(#[from] hyper::Error),
+}
+
+// Body of POST /services/{group}/{name}
+#[derive(Deserialize)]
+struct RegisterBody {
+    ip: String,
+    port: u32,
+    #[serde(default)]
+    instance_id: String,
+    #[serde(default)]
+    metadata: HashMap<String, String>,
+    #[serde(default)]
+    tags: Vec<String>,
+    #[serde(default)]
+    version: String,
+    #[serde(default)]
+    ttl: u64,
+}
+
+// Serves the registry as JSON and an HTML status page:
+//   GET    /                                        status page
+//   GET    /namespaces                              every namespace in use
+//   GET    /services                                every live service
+//   GET    /services/{group}/{name}                 one service
+//   POST   /services/{group}/{name}                 register an instance
+//   DELETE /services/{group}/{name}/{instance_id}   deregister an instance
+//   GET    /health                                  every instance, live or not, with its health
+// The /services routes take a ?namespace= parameter, the default namespace is used without one.
+// With an ACL, callers authenticate with the same bearer tokens as over gRPC.
+pub async fn serve(
+    server: Builder<AddrIncoming>,
+    serdict: SerDictImpl,
+) -> Result<(), hyper::Error> {
+    let make_service = make_service_fn(move |conn: &AddrStream| {
+        let serdict = serdict.clone();
+        let connect_info = conn.connect_info();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |mut req: HttpRequest<Body>| {
+                let serdict = serdict.clone();
+                // Where the call came from, for the audit log as over gRPC
+                req.extensions_mut().insert(connect_info.clone());
+                async move { Ok::<_, Infallible>(handle(&serdict, req).await) }
+            }))
+        }
+    });
+
+    server.serve(make_service).await
+}
+
+// Binds the admin API's port. Its tokens and registrations aren't encrypted, so when the gRPC
+// server takes TLS it only listens on loopback, for local tools or a proxy terminating TLS.
+pub fn bind(addr: SocketAddr) -> Result<Builder<AddrIncoming>, AdminError> {
+    check_cleartext(tls::mode()?, addr)?;
+    let server = Server::try_bind(&addr)?;
+    println!("svc_dsc::admin: serving admin API at http://{}", addr);
+    Ok(server)
+}
+
+fn check_cleartext(mode: Mode, addr: SocketAddr) -> Result<(), AdminError> {
+    match mode {
+        Mode::Tls | Mode::Mtls if !addr.ip().is_loopback() => {
+            Err(AdminError::Cleartext(mode, addr))
+        }
+        _ => Ok(()),
+    }
+}
+
+async fn handle(serdict: &SerDictImpl, req: HttpRequest<Body>) -> HttpResponse<Body> {
+    let principal = match &serdict.acl {
+        Some(acl) => match acl.authenticate(&MetadataMap::from_headers(req.headers().clone())) {
+            Ok(principal) => Some(principal),
+            Err(e) => return error_response(&e.into()),
+        },
+        None => None,
+    };
+    let caller = Caller {
+        principal,
+        connect_info: req.extensions().get::<TcpConnectInfo>().cloned(),
+    };
+    let caller = &caller;
+    let method = req.method().clone();
+    let path = req
+        .uri()
+        .path()
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .map(percent_decode)
+        .collect::<Vec<_>>();
+    let path = path.iter().map(String::as_str).collect::<Vec<_>>();
+    // Empty means the default namespace, as in the gRPC API
+    let namespace = query_param(&req, "namespace").unwrap_or_default();
+
+    let res = match (method, path.as_slice()) {
+        (Method::GET, []) => match instance_rows(serdict, caller).await {
+            Ok(rows) => return html(status_page(rows)),
+            Err(status) => Err(status),
+        },
+        (Method::GET, ["health"]) => instance_rows(serdict, caller).await.map(health),
+        (Method::GET, ["namespaces"]) => namespaces(serdict, caller).await,
+        (Method::GET, ["services"]) => list(serdict, caller, namespace, &req).await,
+        (Method::GET, ["services", group, name]) => {
+            get(serdict, caller, namespace, group, name).await
+        }
+        (Method::POST, ["services", group, name]) => {
+            match hyper::body::to_bytes(req.into_body()).await {
+                Ok(body) => register(serdict, caller, namespace, group, name, &body).await,
+                Err(e) => Err(Status::invalid_argument(e.to_string())),
+            }
+        }
+        (Method::DELETE, ["services", group, name, instance_id]) => {
+            deregister(serdict, caller, namespace, group, name, instance_id).await
+        }
+        _ => Err(Status::not_found("no such route")),
+    };
+
+    match res {
+        Ok(body) => json_response(StatusCode::OK, body),
+        Err(status) => error_response(&status),
+    }
+}
+
+fn query_param(req: &HttpRequest<Body>, name: &str) -> Option<String> {
+    req.uri()
+        .query()
+        .unwrap_or_default()
+        .split('&')
+        .find_map(|param| param.strip_prefix(name)?.strip_prefix('='))
+        .map(percent_decode)
+}
+
+// Who a call is from, handed on to SerDict as the gRPC server does
+struct Caller {
+    principal: Option<Arc<Principal>>,
+    connect_info: Option<TcpConnectInfo>,
+}
+
+impl Caller {
+    fn principal(&self) -> Option<&Arc<Principal>> {
+        self.principal.as_ref()
+    }
+
+    // A SerDict request on behalf of the caller
+    fn request<T>(&self, message: T) -> Request<T> {
+        let mut req = Request::new(message);
+        if let Some(principal) = &self.principal {
+            req.extensions_mut().insert(Arc::clone(principal));
+        }
+        if let Some(connect_info) = &self.connect_info {
+            req.extensions_mut().insert(connect_info.clone());
+        }
+        req
+    }
+}
+
+async fn namespaces(serdict: &SerDictImpl, caller: &Caller) -> Result<Value, Status> {
+    let res = serdict
+        .list_namespaces(caller.request(()))
+        .await?
+        .into_inner();
+    Ok(json!({ "namespaces": res.namespaces }))
+}
+
+async fn list(
+    serdict: &SerDictImpl,
+    caller: &Caller,
+    namespace: String,
+    http_req: &HttpRequest<Body>,
+) -> Result<Value, Status> {
+    let param = |name| query_param(http_req, name).unwrap_or_default();
+    let health = match param("health").as_str() {
+        "" | "healthy" => HealthFilter::Healthy,
+        "unhealthy" => HealthFilter::Unhealthy,
+        "any" => HealthFilter::AnyHealth,
+        other => {
+            return Err(Status::invalid_argument(format!(
+                "health must be healthy, unhealthy or any, not {other:?}"
+            )))
+        }
+    };
+    let page_size = match param("page_size").as_str() {
+        "" => 0,
+        size => size
+            .parse()
+            .map_err(|_| Status::invalid_argument(format!("invalid page_size {size:?}")))?,
+    };
+    let mut req = ListServiceRequest {
+        namespace,
+        group_prefix: param("group_prefix"),
+        name_glob: param("name"),
+        page_size,
+        page_token: param("page_token"),
+        ..Default::default()
+    };
+    req.set_health(health);
+    let res = serdict
+        .list_service(caller.request(req))
+        .await?
+        .into_inner();
+    let services = res.services.iter().map(service_json).collect::<Vec<_>>();
+    Ok(json!({ "services": services, "next_page_token": res.next_page_token }))
+}
+
+async fn get(
+    serdict: &SerDictImpl,
+    caller: &Caller,
+    namespace: String,
+    group: &str,
+    name: &str,
+) -> Result<Value, Status> {
+    let req = GetServiceRequest {
+        namespace,
+        group: group.to_string(),
+        name: name.to_string(),
+        ..Default::default()
+    };
+    let res = serdict.get_service(caller.request(req)).await?.into_inner();
+    Ok(service_json(&res))
+}
+
+async fn register(
+    serdict: &SerDictImpl,
+    caller: &Caller,
+    namespace: String,
+    group: &str,
+    name: &str,
+    body: &[u8],
+) -> Result<Value, Status> {
+    let body: RegisterBody = serde_json::from_slice(body)
+        .map_err(|e| Status::invalid_argument(format!("invalid body: {e}")))?;
+    let req = RegisterServiceRequest {
+        namespace,
+        group: group.to_string(),
+        name: name.to_string(),
+        ip: body.ip,
+        port: body.port,
+        instance_id: body.instance_id,
+        metadata: body.metadata,
+        tags: body.tags,
+        version: body.version,
+        ttl: body.ttl,
+        ..Default::default()
+    };
+
+    let res = serdict
+        .register_service(caller.request(req))
+        .await?
+        .into_inner();
+    Ok(json!({
+        "instance_id": res.instance_id,
+        "ip": res.ip,
+        "port": res.port,
+        "lease_id": res.lease_id,
+        "ttl": res.ttl,
+    }))
+}
+
+async fn deregister(
+    serdict: &SerDictImpl,
+    caller: &Caller,
+    namespace: String,
+    group: &str,
+    name: &str,
+    instance_id: &str,
+) -> Result<Value, Status> {
+    let req = DeregisterServiceRequest {
+        namespace,
+        group: group.to_string(),
+        name: name.to_string(),
+        instance_id: instance_id.to_string(),
+        ..Default::default()
+    };
+    serdict.deregister_service(caller.request(req)).await?;
+    Ok(json!({}))
+}
+
+// One row per registered instance, as seen by this node
+struct InstanceRow {
+    namespace: String,
+    group: String,
+    name: String,
+    instance: ServiceInstance,
+    age: Duration,
+    ttl: u64,
+    expired: bool,
+    healthy: bool,
+}
+
+// Only those of the groups the caller may read
+async fn instance_rows(serdict: &SerDictImpl, caller: &Caller) -> Result<Vec<InstanceRow>, Status> {
+    let services_map = serdict
+        .registry
+        .list()
+        .map_err(|e| Status::internal(format!("Failed to read registry: {e}")))?;
+    let mut rows = services_map
+        .iter()
+        .filter(|(key, _)| acl::check(caller.principal(), &key.1, Permission::Read).is_ok())
+        .flat_map(|(key, instances)| {
+            instances
+                .iter()
+                .map(move |(instance_id, record)| InstanceRow {
+                    namespace: key.0.clone(),
+                    group: key.1.clone(),
+                    name: key.2.clone(),
+                    instance: record.to_instance(instance_id),
+                    age: SystemTime::now()
+                        .duration_since(record.last_updated)
+                        .unwrap_or(Duration::ZERO),
+                    ttl: record.ttl,
+                    expired: record.is_expired(),
+                    healthy: serdict.is_healthy(key, instance_id),
+                })
+        })
+        .collect::<Vec<_>>();
+    rows.sort_by(|a, b| {
+        (&a.namespace, &a.group, &a.name, &a.instance.instance_id).cmp(&(
+            &b.namespace,
+            &b.group,
+            &b.name,
+            &b.instance.instance_id,
+        ))
+    });
+    Ok(rows)
+}
+
+fn health(rows: Vec<InstanceRow>) -> Value {
+    let instances = rows
+        .into_iter()
+        .map(|row| {
+            json!({
+                "namespace": row.namespace,
+                "group": row.group,
+                "name": row.name,
+                "instance": instance_json(&row.instance),
+                "age_ms": row.age.as_millis() as u64,
+                "ttl_ms": row.ttl,
+                "expired": row.expired,
+                "healthy": row.healthy,
+            })
+        })
+        .collect::<Vec<_>>();
+    json!({ "instances": instances })
+}
+
+fn status_page(instance_rows: Vec<InstanceRow>) -> String {
+    let mut rows = String::new();
+    for row in instance_rows {
+        let state = match (row.expired, row.healthy, row.instance.state()) {
+            (true, _, _) => "expired",
+            (false, false, _) => "unhealthy",
+            (false, true, InstanceState::Active) => "live",
+            (false, true, InstanceState::Draining) => "draining",
+            (false, true, InstanceState::Maintenance) => "maintenance",
+        };
+        let _ = write!(
+            rows,
+            "<tr class=\"{state}\"><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}:{}</td>\
+             <td>{}</td><td>{:.1}s</td><td>{state}</td></tr>",
+            escape(&row.namespace),
+            escape(&row.group),
+            escape(&row.name),
+            escape(&row.instance.instance_id),
+            escape(&row.instance.ip),
+            row.instance.port,
+            escape(&row.instance.version),
+            row.age.as_secs_f64(),
+        );
+    }
+    if rows.is_empty() {
+        rows.push_str("<tr><td colspan=\"8\">No service registered</td></tr>");
+    }
+
+    format!(
+        "<!DOCTYPE html>
+<html>
+<head>
+<meta charset=\"utf-8\">
+<meta http-equiv=\"refresh\" content=\"5\">
+<title>svc-dsc</title>
+<style>
+body {{ font-family: sans-serif; margin: 2em; }}
+table {{ border-collapse: collapse; }}
+th, td {{ padding: 0.3em 1em; border-bottom: 1px solid #ddd; text-align: left; }}
+.expired {{ color: #999; }}
+.unhealthy {{ color: #c00; }}
+.draining, .maintenance {{ color: #b60; }}
+</style>
+</head>
+<body>
+<h1>svc-dsc</h1>
+<table>
+<tr><th>Namespace</th><th>Group</th><th>Name</th><th>Instance</th><th>Address</th><th>Version</th>\
+<th>Since last heartbeat</th><th>State</th></tr>
+{rows}
+</table>
+</body>
+</html>
+"
+    )
+}
+
+fn service_json(service: &GetServiceResponse) -> Value {
+    let instances = service
+        .instances
+        .iter()
+        .map(instance_json)
+        .collect::<Vec<_>>();
+    json!({
+        "namespace": service.namespace,
+        "group": service.group,
+        "name": service.name,
+        "datacenter": service.datacenter,
+        "instances": instances,
+    })
+}
+
+fn instance_json(instance: &ServiceInstance) -> Value {
+    json!({
+        "instance_id": instance.instance_id,
+        "ip": instance.ip,
+        "port": instance.port,
+        "metadata": instance.metadata,
+        "tags": instance.tags,
+        "version": instance.version,
+        "state": instance.state().as_str_name().to_lowercase(),
+        "pinned": instance.pinned,
+    })
+}
+
+fn http_status(status: &Status) -> StatusCode {
+    match status.code() {
+        Code::NotFound => StatusCode::NOT_FOUND,
+        Code::InvalidArgument => StatusCode::BAD_REQUEST,
+        Code::Unauthenticated => StatusCode::UNAUTHORIZED,
+        Code::PermissionDenied => StatusCode::FORBIDDEN,
+        Code::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+fn json_response(status: StatusCode, body: Value) -> HttpResponse<Body> {
+    let mut res = HttpResponse::new(Body::from(body.to_string()));
+    *res.status_mut() = status;
+    res.headers_mut()
+        .insert(CONTENT_TYPE, "application/json".parse().unwrap());
+    res
+}
+
+fn error_response(status: &Status) -> HttpResponse<Body> {
+    json_response(http_status(status), json!({ "error": status.message() }))
+}
+
+fn html(page: String) -> HttpResponse<Body> {
+    let mut res = HttpResponse::new(Body::from(page));
+    res.headers_mut()
+        .insert(CONTENT_TYPE, "text/html; charset=utf-8".parse().unwrap());
+    res
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+// Instance ids like [::1]:50052 come percent-encoded in paths
+fn percent_decode(segment: &str) -> String {
+    let bytes = segment.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+#[cfg(test)]
+mod test {
+    use hyper::{server::conn::AddrIncoming, Body, Method, Request, Server, StatusCode};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    use super::{check_cleartext, handle, serve};
+    use crate::{
+        dst_pfm::tls::Mode,
+        svc_dsc::server::{registry::MemoryRegistry, serdict::SerDictImpl, store::NoopStore},
+    };
+
+    use std::sync::Arc;
+
+    async fn call(serdict: &SerDictImpl, method: Method, uri: &str, body: &str) -> StatusCode {
+        let req = Request::builder()
+            .method(method)
+            .uri(uri)
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        handle(serdict, req).await.status()
+    }
+
+    #[tokio::test]
+    async fn it_registers_and_deregisters_over_http() {
+        let (events, _) = tokio::sync::broadcast::channel(1);
+        let serdict = SerDictImpl::new(
+            Arc::new(MemoryRegistry::default()),
+            events,
+            Arc::new(std::sync::Mutex::new(NoopStore)),
+        );
+
+        let get = |serdict| call(serdict, Method::GET, "/services/math/add", "");
+        assert_eq!(get(&serdict).await, StatusCode::NOT_FOUND);
+
+        let body = r#"{"ip": "[::1]", "port": 50052}"#;
+        let res = call(&serdict, Method::POST, "/services/math/add", body).await;
+        assert_eq!(res, StatusCode::OK);
+        assert_eq!(get(&serdict).await, StatusCode::OK);
+
+        let uri = "/services/math/add/%5B%3A%3A1%5D%3A50052";
+        assert_eq!(
+            call(&serdict, Method::DELETE, uri, "").await,
+            StatusCode::OK
+        );
+        assert_eq!(get(&serdict).await, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn it_audits_where_calls_come_from() {
+        let (events, _) = tokio::sync::broadcast::channel(1);
+        let serdict = SerDictImpl::new(
+            Arc::new(MemoryRegistry::default()),
+            events,
+            Arc::new(std::sync::Mutex::new(NoopStore)),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let incoming = AddrIncoming::from_listener(listener).unwrap();
+        tokio::spawn(serve(Server::builder(incoming), serdict.clone()));
+
+        let mut conn = TcpStream::connect(addr).await.unwrap();
+        let body = r#"{"ip": "[::1]", "port": 50052}"#;
+        let req = format!(
+            "POST /services/math/add HTTP/1.1\r\nhost: {addr}\r\ncontent-length: {}\r\n\
+             connection: close\r\n\r\n{body}",
+            body.len()
+        );
+        conn.write_all(req.as_bytes()).await.unwrap();
+        let mut res = String::new();
+        conn.read_to_string(&mut res).await.unwrap();
+        assert!(res.starts_with("HTTP/1.1 200"), "{res}");
+
+        let events = serdict.audit.list(|_| true);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].caller, conn.local_addr().unwrap().to_string());
+    }
+
+    #[test]
+    fn it_keeps_cleartext_on_loopback_with_tls() {
+        for addr in ["0.0.0.0:8080", "10.0.0.1:8080", "[::]:8080"] {
+            let addr = addr.parse().unwrap();
+            assert!(check_cleartext(Mode::Off, addr).is_ok());
+            assert!(check_cleartext(Mode::Tls, addr).is_err());
+            assert!(check_cleartext(Mode::Mtls, addr).is_err());
+        }
+        for addr in ["127.0.0.1:8080", "[::1]:8080"] {
+            assert!(check_cleartext(Mode::Mtls, addr.parse().unwrap()).is_ok());
+        }
+    }
+}
@@ -0,0 +1,266 @@
+use serde::{Deserialize, Serialize};
+
+use crate::svc_dsc::gen::{audit_event::Kind, AuditEvent};
+
+use super::serdict::{InstanceId, ServiceId, ServiceRecord};
+
+use std::{
+    collections::VecDeque,
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::{Mutex, PoisonError},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+// Kept in SERVICE_DISCOVERY_DATA_DIR along with the registry's snapshot and log
+pub const EVENTS_FILE: &str = "events.jsonl";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditKind {
+    Registered,
+    Reregistered,
+    Deregistered,
+    Expired,
+    StateChanged,
+}
+
+impl AuditKind {
+    fn to_proto(self) -> Kind {
+        match self {
+            AuditKind::Registered => Kind::Registered,
+            AuditKind::Reregistered => Kind::Reregistered,
+            AuditKind::Deregistered => Kind::Deregistered,
+            AuditKind::Expired => Kind::Expired,
+            AuditKind::StateChanged => Kind::StateChanged,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub id: u64,
+    pub timestamp: SystemTime,
+    pub kind: AuditKind,
+    pub namespace: String,
+    pub group: String,
+    pub name: String,
+    pub instance_id: InstanceId,
+    pub record: ServiceRecord,
+    // Empty for expiries
+    pub caller: String,
+    pub reason: String,
+}
+
+impl AuditEntry {
+    pub fn millis(&self) -> u64 {
+        self.timestamp
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or(Duration::ZERO)
+            .as_millis() as u64
+    }
+
+    pub fn to_event(&self) -> AuditEvent {
+        let mut event = AuditEvent {
+            id: self.id,
+            timestamp: self.millis(),
+            namespace: self.namespace.clone(),
+            group: self.group.clone(),
+            name: self.name.clone(),
+            instance: Some(self.record.to_instance(&self.instance_id)),
+            caller: self.caller.clone(),
+            reason: self.reason.clone(),
+            ..Default::default()
+        };
+        event.set_kind(self.kind.to_proto());
+        event
+    }
+}
+
+#[derive(Debug, Default)]
+struct Events {
+    entries: VecDeque<AuditEntry>,
+    next_id: u64,
+    // Appended to for every event when persisted, rewritten once it holds twice the capacity
+    file: Option<(PathBuf, File)>,
+    lines: usize,
+}
+
+// The latest registry events, oldest first. Older ones are dropped past the capacity.
+#[derive(Debug)]
+pub struct AuditLog {
+    capacity: usize,
+    events: Mutex<Events>,
+}
+
+fn to_line(entry: &AuditEntry) -> io::Result<Vec<u8>> {
+    let mut line = serde_json::to_vec(entry)?;
+    line.push(b'\n');
+    Ok(line)
+}
+
+impl AuditLog {
+    // Keeps the events in memory only
+    pub fn new(capacity: usize) -> AuditLog {
+        Self {
+            capacity,
+            events: Mutex::new(Events {
+                next_id: 1,
+                ..Default::default()
+            }),
+        }
+    }
+
+    // Also writes the events to a JSON lines file, and starts from those it already holds
+    pub fn open(path: impl AsRef<Path>, capacity: usize) -> io::Result<AuditLog> {
+        let path = path.as_ref().to_path_buf();
+        let log = Self::new(capacity);
+        {
+            let mut events = log.events.lock().unwrap_or_else(PoisonError::into_inner);
+            if path.exists() {
+                for line in BufReader::new(File::open(&path)?).lines() {
+                    let line = line?;
+                    match serde_json::from_str::<AuditEntry>(&line) {
+                        Ok(entry) => {
+                            events.next_id = entry.id + 1;
+                            events.entries.push_back(entry);
+                            if events.entries.len() > capacity {
+                                events.entries.pop_front();
+                            }
+                        }
+                        // A line cut short by a crash
+                        Err(e) => println!("svc_dsc::audit: skipping unreadable event: {}", e),
+                    }
+                }
+            }
+            log.rewrite(&mut events, path)?;
+        }
+
+        Ok(log)
+    }
+
+    // Replaces the file with the events kept in memory
+    fn rewrite(&self, events: &mut Events, path: PathBuf) -> io::Result<()> {
+        let tmp_path = path.with_extension("jsonl.tmp");
+        let mut tmp = BufWriter::new(File::create(&tmp_path)?);
+        for entry in &events.entries {
+            tmp.write_all(&to_line(entry)?)?;
+        }
+        tmp.into_inner()?.sync_all()?;
+        fs::rename(&tmp_path, &path)?;
+
+        let file = OpenOptions::new().append(true).open(&path)?;
+        events.lines = events.entries.len();
+        events.file = Some((path, file));
+        Ok(())
+    }
+
+    pub fn record(
+        &self,
+        kind: AuditKind,
+        (namespace, group, name): &ServiceId,
+        instance_id: &str,
+        record: &ServiceRecord,
+        caller: &str,
+        reason: String,
+    ) {
+        let mut events = self.events.lock().unwrap_or_else(PoisonError::into_inner);
+        let entry = AuditEntry {
+            id: events.next_id,
+            timestamp: SystemTime::now(),
+            kind,
+            namespace: namespace.clone(),
+            group: group.clone(),
+            name: name.clone(),
+            instance_id: instance_id.to_string(),
+            record: record.clone(),
+            caller: caller.to_string(),
+            reason,
+        };
+        events.next_id += 1;
+
+        events.entries.push_back(entry.clone());
+        if events.entries.len() > self.capacity {
+            events.entries.pop_front();
+        }
+
+        // Losing the history of a change is no reason to refuse it
+        if let Err(e) = self.persist(&mut events, &entry) {
+            println!("svc_dsc::audit: failed to persist event: {}", e);
+        }
+    }
+
+    fn persist(&self, events: &mut Events, entry: &AuditEntry) -> io::Result<()> {
+        let Some((path, file)) = &mut events.file else {
+            return Ok(());
+        };
+        if events.lines + 1 >= 2 * self.capacity {
+            let path = path.clone();
+            return self.rewrite(events, path);
+        }
+        file.write_all(&to_line(entry)?)?;
+        events.lines += 1;
+        Ok(())
+    }
+
+    // The events passing keep, oldest first
+    pub fn list(&self, keep: impl Fn(&AuditEntry) -> bool) -> Vec<AuditEntry> {
+        let events = self.events.lock().unwrap_or_else(PoisonError::into_inner);
+        events
+            .entries
+            .iter()
+            .filter(|entry| keep(entry))
+            .cloned()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{AuditKind, AuditLog};
+    use crate::svc_dsc::server::serdict::ServiceRecord;
+
+    #[test]
+    fn it_keeps_the_latest_events_across_restarts() {
+        let dir = std::env::temp_dir().join(format!("svc-dsc-audit-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("events.jsonl");
+        let key = ("default".to_string(), "math".to_string(), "add".to_string());
+        let record = ServiceRecord {
+            addr: ("[::1]".to_string(), 50052),
+            lease_id: 1,
+            ttl: 5000,
+            ..Default::default()
+        };
+
+        let log = AuditLog::open(&path, 3).unwrap();
+        for instance_id in ["a", "b", "c", "d", "e", "f", "g"] {
+            let reason = "registered".to_string();
+            log.record(
+                AuditKind::Registered,
+                &key,
+                instance_id,
+                &record,
+                "",
+                reason,
+            );
+        }
+        let ids = |log: &AuditLog| {
+            log.list(|_| true)
+                .iter()
+                .map(|entry| (entry.id, entry.instance_id.clone()))
+                .collect::<Vec<_>>()
+        };
+        let latest = vec![(5, "e".to_string()), (6, "f".into()), (7, "g".into())];
+        assert_eq!(ids(&log), latest);
+        drop(log);
+
+        let log = AuditLog::open(&path, 3).unwrap();
+        assert_eq!(ids(&log), latest);
+        log.record(AuditKind::Expired, &key, "e", &record, "", "ttl".into());
+        let e = log.list(|entry| entry.instance_id == "e");
+        assert_eq!((e.len(), e[0].id, e[0].kind), (1, 8, AuditKind::Expired));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
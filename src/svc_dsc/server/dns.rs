@@ -0,0 +1,495 @@
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream, UdpSocket},
+};
+
+use crate::svc_dsc::{gen::ServiceInstance, DEFAULT_NAMESPACE, HEARTBEAT_INTERVAL};
+
+use super::{acl::Permission, serdict::SerDictImpl};
+
+use std::{
+    io,
+    net::{IpAddr, SocketAddr},
+};
+
+// Names are answered under <name>.<group>[.<namespace>].svc.local
+const ZONE: [&str; 2] = ["svc", "local"];
+
+// Instances can go away within a heartbeat interval, so answers are cached no longer than that
+const TTL: u32 = (HEARTBEAT_INTERVAL / 1000) as u32;
+
+// Without EDNS, a UDP answer must fit in 512 bytes
+const MAX_UDP_LEN: usize = 512;
+
+// Longest label, and longest name as sent with its length bytes
+const MAX_LABEL_LEN: usize = 63;
+const MAX_NAME_LEN: usize = 255;
+
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+const TYPE_SRV: u16 = 33;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+
+const RCODE_FORMERR: u16 = 1;
+const RCODE_SERVFAIL: u16 = 2;
+const RCODE_NXDOMAIN: u16 = 3;
+const RCODE_NOTIMP: u16 = 4;
+const RCODE_REFUSED: u16 = 5;
+
+// Serves A, AAAA and SRV records for the live instances in the registry, over UDP and TCP
+pub async fn serve(addr: SocketAddr, serdict: SerDictImpl) -> io::Result<()> {
+    let udp = UdpSocket::bind(addr).await?;
+    let tcp = TcpListener::bind(addr).await?;
+    println!("svc_dsc::dns: serving svc.local at {}", addr);
+
+    tokio::try_join!(serve_udp(udp, serdict.clone()), serve_tcp(tcp, serdict))?;
+    Ok(())
+}
+
+async fn serve_udp(socket: UdpSocket, serdict: SerDictImpl) -> io::Result<()> {
+    let mut buf = [0; 4096];
+    loop {
+        let (len, peer) = socket.recv_from(&mut buf).await?;
+        if let Some(res) = answer(&serdict, &buf[..len], MAX_UDP_LEN).await {
+            if let Err(e) = socket.send_to(&res, peer).await {
+                println!("svc_dsc::dns: failed to answer {}: {}", peer, e);
+            }
+        }
+    }
+}
+
+async fn serve_tcp(listener: TcpListener, serdict: SerDictImpl) -> io::Result<()> {
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let serdict = serdict.clone();
+        tokio::spawn(async move {
+            if let Err(e) = serve_tcp_conn(stream, &serdict).await {
+                println!("svc_dsc::dns: connection from {} failed: {}", peer, e);
+            }
+        });
+    }
+}
+
+// Answers length-prefixed queries until the client hangs up
+async fn serve_tcp_conn(mut stream: TcpStream, serdict: &SerDictImpl) -> io::Result<()> {
+    loop {
+        let len = match stream.read_u16().await {
+            Ok(len) => len as usize,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        let mut query = vec![0; len];
+        stream.read_exact(&mut query).await?;
+
+        if let Some(res) = answer(serdict, &query, u16::MAX as usize).await {
+            // One write, so the length and the message leave in the same segment
+            let mut framed = (res.len() as u16).to_be_bytes().to_vec();
+            framed.extend(res);
+            stream.write_all(&framed).await?;
+        }
+    }
+}
+
+struct Question {
+    labels: Vec<String>,
+    qtype: u16,
+    qclass: u16,
+    // The question as sent, echoed back in the answer
+    raw: Vec<u8>,
+}
+
+struct Record {
+    labels: Vec<String>,
+    rtype: u16,
+    data: Vec<u8>,
+}
+
+// Builds the answer to one query, or None when it isn't worth answering
+async fn answer(serdict: &SerDictImpl, query: &[u8], max_len: usize) -> Option<Vec<u8>> {
+    if query.len() < 12 {
+        return None;
+    }
+    let id = u16::from_be_bytes([query[0], query[1]]);
+    let flags = u16::from_be_bytes([query[2], query[3]]);
+    // Responses sent our way
+    if flags & 0x8000 != 0 {
+        return None;
+    }
+
+    let opcode = (flags >> 11) & 0xf;
+    let qdcount = u16::from_be_bytes([query[4], query[5]]);
+    let question = match parse_question(&query[12..]) {
+        Some(question) if qdcount == 1 => question,
+        _ => return Some(reply(id, flags, RCODE_FORMERR, None)),
+    };
+    if opcode != 0 {
+        return Some(reply(id, flags, RCODE_NOTIMP, Some(&question)));
+    }
+
+    let (rcode, answers, additionals) = lookup(serdict, &question).await;
+    let Some(res) = message(id, flags, rcode, Some(&question), &answers, &additionals) else {
+        println!(
+            "svc_dsc::dns: an answer to {:?} has a name too long for DNS",
+            question.labels
+        );
+        return Some(reply(id, flags, RCODE_SERVFAIL, Some(&question)));
+    };
+    if res.len() <= max_len {
+        return Some(res);
+    }
+
+    // Too big for UDP, the client retries over TCP
+    let mut res = reply(id, flags, rcode, Some(&question));
+    res[2] |= 0x02;
+    Some(res)
+}
+
+async fn lookup(serdict: &SerDictImpl, question: &Question) -> (u16, Vec<Record>, Vec<Record>) {
+    let labels = &question.labels;
+    let in_zone = labels.len() > ZONE.len()
+        && labels[labels.len() - ZONE.len()..]
+            .iter()
+            .zip(ZONE)
+            .all(|(label, zone)| label.eq_ignore_ascii_case(zone));
+    if !in_zone || question.qclass != CLASS_IN {
+        return (RCODE_REFUSED, Vec::new(), Vec::new());
+    }
+
+    let (want_addrs, want_srv) = match question.qtype {
+        TYPE_A | TYPE_AAAA => (true, false),
+        TYPE_SRV => (false, true),
+        TYPE_ANY => (true, true),
+        _ => (false, false),
+    };
+
+    let owner = labels.clone();
+    let names = &labels[..labels.len() - ZONE.len()];
+    // Names without a namespace are in the default one
+    let (host, name, group, namespace, srv_only) = match names {
+        // _name._tcp.group[.namespace].svc.local
+        [service, proto, group, namespace @ ..]
+            if service.starts_with('_') && proto == "_tcp" && namespace.len() <= 1 =>
+        {
+            let namespace = namespace.first().map_or(DEFAULT_NAMESPACE, String::as_str);
+            (None, &service[1..], group.as_str(), namespace, true)
+        }
+        // host.name.group.namespace.svc.local, the SRV targets
+        [host, name, group, namespace] => (
+            Some(host.as_str()),
+            name.as_str(),
+            group.as_str(),
+            namespace.as_str(),
+            false,
+        ),
+        // name.group[.namespace].svc.local
+        [name, group, namespace @ ..] if namespace.len() <= 1 => {
+            let namespace = namespace.first().map_or(DEFAULT_NAMESPACE, String::as_str);
+            (None, name.as_str(), group.as_str(), namespace, false)
+        }
+        _ => return (RCODE_NXDOMAIN, Vec::new(), Vec::new()),
+    };
+
+    // DNS queries carry no token, they get the ACL's anonymous rules
+    if let Some(acl) = &serdict.acl {
+        if !acl.anonymous().allows(group, Permission::Read) {
+            return (RCODE_REFUSED, Vec::new(), Vec::new());
+        }
+    }
+
+    let instances = match serdict.live_instances(namespace, group, name) {
+        Ok(instances) => instances,
+        Err(e) => {
+            println!("svc_dsc::dns: failed to read registry: {}", e);
+            return (RCODE_SERVFAIL, Vec::new(), Vec::new());
+        }
+    };
+    let instances = instances
+        .into_iter()
+        .filter(|instance| match host {
+            Some(host) => host_label(instance).eq_ignore_ascii_case(host),
+            None => true,
+        })
+        .collect::<Vec<_>>();
+    if instances.is_empty() {
+        return (RCODE_NXDOMAIN, Vec::new(), Vec::new());
+    }
+
+    let mut answers = Vec::new();
+    let mut additionals = Vec::new();
+    for instance in &instances {
+        if want_addrs && !srv_only {
+            answers.extend(addr_record(&owner, instance, question.qtype));
+        }
+        if want_srv && host.is_none() {
+            let Ok(port) = u16::try_from(instance.port) else {
+                continue;
+            };
+            let mut target = vec![
+                host_label(instance),
+                name.to_string(),
+                group.to_string(),
+                namespace.to_string(),
+            ];
+            target.extend(ZONE.map(String::from));
+
+            // Every instance gets the same share of traffic
+            let mut data = Vec::new();
+            data.extend(0u16.to_be_bytes());
+            data.extend(1u16.to_be_bytes());
+            data.extend(port.to_be_bytes());
+            if write_name(&mut data, &target).is_none() {
+                println!("svc_dsc::dns: SRV target {:?} is too long for DNS", target);
+                return (RCODE_SERVFAIL, Vec::new(), Vec::new());
+            }
+            answers.push(Record {
+                labels: owner.clone(),
+                rtype: TYPE_SRV,
+                data,
+            });
+            additionals.extend(addr_record(&target, instance, TYPE_ANY));
+        }
+    }
+
+    (0, answers, additionals)
+}
+
+// The instance's address as a record of qtype (A or AAAA, either one for ANY)
+fn addr_record(owner: &[String], instance: &ServiceInstance, qtype: u16) -> Option<Record> {
+    let ip = instance.ip.trim_start_matches('[').trim_end_matches(']');
+    let (rtype, data) = match ip.parse::<IpAddr>().ok()? {
+        IpAddr::V4(ip) if qtype != TYPE_AAAA => (TYPE_A, ip.octets().to_vec()),
+        IpAddr::V6(ip) if qtype != TYPE_A => (TYPE_AAAA, ip.octets().to_vec()),
+        _ => return None,
+    };
+
+    Some(Record {
+        labels: owner.to_vec(),
+        rtype,
+        data,
+    })
+}
+
+// A DNS label naming the instance, e.g. 10-0-0-1 or --1 for [::1]
+fn host_label(instance: &ServiceInstance) -> String {
+    instance
+        .ip
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .replace(['.', ':'], "-")
+        .to_ascii_lowercase()
+}
+
+fn parse_question(buf: &[u8]) -> Option<Question> {
+    let mut labels = Vec::new();
+    let mut pos = 0;
+    loop {
+        let len = *buf.get(pos)? as usize;
+        pos += 1;
+        if len == 0 {
+            break;
+        }
+        // Queries have nothing to point back to, so compressed names are malformed
+        if len & 0xc0 != 0 {
+            return None;
+        }
+        let label = buf.get(pos..pos + len)?;
+        labels.push(String::from_utf8_lossy(label).into_owned());
+        pos += len;
+    }
+    if pos > MAX_NAME_LEN {
+        return None;
+    }
+
+    let fixed = buf.get(pos..pos + 4)?;
+    Some(Question {
+        labels,
+        qtype: u16::from_be_bytes([fixed[0], fixed[1]]),
+        qclass: u16::from_be_bytes([fixed[2], fixed[3]]),
+        raw: buf[..pos + 4].to_vec(),
+    })
+}
+
+// Writes the name uncompressed, or nothing and None when a label is empty or over 63 bytes, or
+// the name is over 255 bytes
+fn write_name(buf: &mut Vec<u8>, labels: &[String]) -> Option<()> {
+    let len = labels.iter().map(|label| label.len() + 1).sum::<usize>() + 1;
+    let fits = labels
+        .iter()
+        .all(|label| (1..=MAX_LABEL_LEN).contains(&label.len()));
+    if !fits || len > MAX_NAME_LEN {
+        return None;
+    }
+
+    for label in labels {
+        buf.push(label.len() as u8);
+        buf.extend(label.as_bytes());
+    }
+    buf.push(0);
+    Some(())
+}
+
+// A response holding no records, only the question if any
+fn reply(id: u16, query_flags: u16, rcode: u16, question: Option<&Question>) -> Vec<u8> {
+    header(id, query_flags, rcode, question, 0, 0)
+}
+
+fn header(
+    id: u16,
+    query_flags: u16,
+    rcode: u16,
+    question: Option<&Question>,
+    ancount: usize,
+    arcount: usize,
+) -> Vec<u8> {
+    // QR and AA, with the query's opcode and RD echoed back
+    let flags = 0x8000 | (query_flags & 0x7900) | 0x0400 | rcode;
+
+    let mut buf = Vec::new();
+    buf.extend(id.to_be_bytes());
+    buf.extend(flags.to_be_bytes());
+    buf.extend((question.is_some() as u16).to_be_bytes());
+    buf.extend((ancount as u16).to_be_bytes());
+    buf.extend(0u16.to_be_bytes());
+    buf.extend((arcount as u16).to_be_bytes());
+
+    if let Some(question) = question {
+        buf.extend(&question.raw);
+    }
+    buf
+}
+
+// The response, or None when a record's name can't be sent
+fn message(
+    id: u16,
+    query_flags: u16,
+    rcode: u16,
+    question: Option<&Question>,
+    answers: &[Record],
+    additionals: &[Record],
+) -> Option<Vec<u8>> {
+    let mut buf = header(
+        id,
+        query_flags,
+        rcode,
+        question,
+        answers.len(),
+        additionals.len(),
+    );
+    for record in answers.iter().chain(additionals) {
+        write_name(&mut buf, &record.labels)?;
+        buf.extend(record.rtype.to_be_bytes());
+        buf.extend(CLASS_IN.to_be_bytes());
+        buf.extend(TTL.to_be_bytes());
+        buf.extend((record.data.len() as u16).to_be_bytes());
+        buf.extend(&record.data);
+    }
+    Some(buf)
+}
+
+#[cfg(test)]
+mod test {
+    use prost::Message;
+
+    use super::{answer, TYPE_SRV};
+    use crate::svc_dsc::{
+        gen::{registry_command::Command, RegisterServiceRequest, RegistryCommand},
+        server::{
+            raft::StateMachine, registry::MemoryRegistry, serdict::SerDictImpl, store::NoopStore,
+        },
+        DEFAULT_NAMESPACE,
+    };
+
+    use std::sync::Arc;
+
+    fn query(name: &str, qtype: u16) -> Vec<u8> {
+        let mut buf = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+        for label in name.split('.') {
+            buf.push(label.len() as u8);
+            buf.extend(label.as_bytes());
+        }
+        buf.push(0);
+        buf.extend(qtype.to_be_bytes());
+        buf.extend(1u16.to_be_bytes());
+        buf
+    }
+
+    #[tokio::test]
+    async fn it_answers_srv_with_ports() {
+        let (events, _) = tokio::sync::broadcast::channel(1);
+        let serdict = SerDictImpl::new(
+            Arc::new(MemoryRegistry::default()),
+            events,
+            Arc::new(std::sync::Mutex::new(NoopStore)),
+        );
+        for port in [50052, 50062] {
+            let register = RegisterServiceRequest {
+                namespace: DEFAULT_NAMESPACE.into(),
+                group: "math".into(),
+                name: "add".into(),
+                ip: "10.0.0.1".into(),
+                port,
+                instance_id: format!("10.0.0.1:{port}"),
+                ..Default::default()
+            };
+            let command = RegistryCommand {
+                command: Some(Command::Register(register)),
+            };
+            serdict.apply(&command.encode_to_vec()).unwrap();
+        }
+
+        let res = answer(&serdict, &query("_add._tcp.math.svc.local", TYPE_SRV), 512)
+            .await
+            .unwrap();
+        assert_eq!(res[3] & 0x0f, 0, "rcode");
+        assert_eq!(u16::from_be_bytes([res[6], res[7]]), 2, "answers");
+        assert_eq!(u16::from_be_bytes([res[10], res[11]]), 2, "additionals");
+        assert!(res.windows(2).any(|port| port == 50062u16.to_be_bytes()));
+
+        let res = answer(&serdict, &query("sub.math.svc.local", 1), 512)
+            .await
+            .unwrap();
+        assert_eq!(res[3] & 0x0f, 3, "rcode");
+
+        // Over 255 bytes
+        let long = [
+            "a".repeat(63),
+            "b".repeat(63),
+            "c".repeat(63),
+            "d".repeat(52),
+        ]
+        .join(".");
+        let res = answer(&serdict, &query(&format!("{long}.svc.local"), 1), 512)
+            .await
+            .unwrap();
+        assert_eq!(res[3] & 0x0f, 1, "rcode");
+
+        // An instance registered under a host name too long to be a label of the SRV target
+        let register = RegisterServiceRequest {
+            namespace: DEFAULT_NAMESPACE.into(),
+            group: "math".into(),
+            name: "mul".into(),
+            ip: format!("{}.example.com", "h".repeat(64)),
+            port: 50072,
+            ..Default::default()
+        };
+        let command = RegistryCommand {
+            command: Some(Command::Register(register)),
+        };
+        serdict.apply(&command.encode_to_vec()).unwrap();
+        let res = answer(&serdict, &query("_mul._tcp.math.svc.local", TYPE_SRV), 512)
+            .await
+            .unwrap();
+        assert_eq!(res[3] & 0x0f, 2, "rcode");
+        assert_eq!(u16::from_be_bytes([res[6], res[7]]), 0, "answers");
+
+        assert!(super::write_name(&mut Vec::new(), &["a".repeat(64)]).is_none());
+        let mut name = Vec::new();
+        let labels = format!("{long}.svc.local")
+            .split('.')
+            .map(String::from)
+            .collect::<Vec<_>>();
+        assert!(super::write_name(&mut name, &labels).is_none());
+        assert!(name.is_empty());
+    }
+}
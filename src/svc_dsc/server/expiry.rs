@@ -0,0 +1,112 @@
+use super::serdict::{InstanceId, ServiceId, ServiceRecord};
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    time::SystemTime,
+};
+
+// When each record of a registry runs out, soonest first. Scheduling and cancelling are
+// O(log n), and expiring only visits the records that are due, so the reaper can sleep until the
+// next deadline instead of sweeping the whole registry.
+#[derive(Debug, Default)]
+pub struct Deadlines {
+    queue: BTreeSet<(SystemTime, ServiceId, InstanceId)>,
+    // The deadline each record is queued at, to find it again when it changes
+    scheduled: HashMap<(ServiceId, InstanceId), SystemTime>,
+}
+
+impl Deadlines {
+    // Queues the record at its deadline, in place of the one it had. Pinned records have none.
+    pub fn schedule(&mut self, key: &ServiceId, instance_id: &str, record: &ServiceRecord) {
+        self.cancel(key, instance_id);
+        let Some(deadline) = record.deadline() else {
+            return;
+        };
+        self.queue
+            .insert((deadline, key.clone(), instance_id.to_string()));
+        self.scheduled
+            .insert((key.clone(), instance_id.to_string()), deadline);
+    }
+
+    pub fn cancel(&mut self, key: &ServiceId, instance_id: &str) {
+        let id = (key.clone(), instance_id.to_string());
+        if let Some(deadline) = self.scheduled.remove(&id) {
+            let (key, instance_id) = id;
+            self.queue.remove(&(deadline, key, instance_id));
+        }
+    }
+
+    // The soonest deadline, None when no record expires
+    pub fn next(&self) -> Option<SystemTime> {
+        self.queue.first().map(|(deadline, _, _)| *deadline)
+    }
+
+    // The records due by now, soonest first, left queued
+    pub fn due(&self, now: SystemTime) -> Vec<(ServiceId, InstanceId)> {
+        self.queue
+            .iter()
+            .take_while(|(deadline, _, _)| *deadline <= now)
+            .map(|(_, key, instance_id)| (key.clone(), instance_id.clone()))
+            .collect()
+    }
+
+    // Unqueues the records due by now and returns them, soonest first
+    pub fn pop_due(&mut self, now: SystemTime) -> Vec<(ServiceId, InstanceId)> {
+        let mut due = Vec::new();
+        while matches!(self.queue.first(), Some((deadline, _, _)) if *deadline <= now) {
+            let (_, key, instance_id) = self.queue.pop_first().unwrap();
+            self.scheduled.remove(&(key.clone(), instance_id.clone()));
+            due.push((key, instance_id));
+        }
+        due
+    }
+
+    pub fn clear(&mut self) {
+        self.queue.clear();
+        self.scheduled.clear();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Deadlines;
+    use crate::svc_dsc::server::serdict::ServiceRecord;
+
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn it_pops_records_as_they_fall_due() {
+        let key = ("default".to_string(), "math".to_string(), "add".to_string());
+        let start = SystemTime::now();
+        let record = |ttl, pinned| ServiceRecord {
+            last_updated: start,
+            lease_id: 1,
+            ttl,
+            pinned,
+            ..Default::default()
+        };
+
+        let mut deadlines = Deadlines::default();
+        deadlines.schedule(&key, "a", &record(3000, false));
+        deadlines.schedule(&key, "b", &record(1000, false));
+        deadlines.schedule(&key, "c", &record(2000, false));
+        deadlines.schedule(&key, "pinned", &record(1000, true));
+        // Renewed, its old deadline goes away
+        deadlines.schedule(&key, "b", &record(4000, false));
+        deadlines.cancel(&key, "c");
+
+        let after = |millis| start + Duration::from_millis(millis);
+        assert_eq!(deadlines.next(), Some(after(3000)));
+        assert!(deadlines.pop_due(after(2999)).is_empty());
+        assert_eq!(deadlines.due(after(3000)).len(), 1);
+        let due = |deadlines: &mut Deadlines, millis| {
+            deadlines
+                .pop_due(after(millis))
+                .into_iter()
+                .map(|(_, instance_id)| instance_id)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(due(&mut deadlines, 10000), vec!["a", "b"]);
+        assert_eq!(deadlines.next(), None);
+    }
+}
@@ -0,0 +1,401 @@
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::svc_dsc::{
+    gen::{registry_change::Kind, RegistryChange, RegistryFormat},
+    DEFAULT_NAMESPACE, LEASE_TTL,
+};
+
+use super::serdict::{
+    InstanceId, InstanceState, ServiceId, ServiceInstances, ServiceMap, ServiceRecord,
+};
+
+use std::{
+    collections::{BTreeMap, HashSet},
+    time::{Duration, SystemTime},
+};
+
+#[derive(Debug, Error)]
+pub enum ExportError {
+    #[error("invalid JSON: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("invalid YAML: {0}")]
+    Yaml(#[from] serde_yaml::Error),
+    #[error("{0}")]
+    Invalid(String),
+}
+
+// The registry as exported, sorted so that two exports of the same registry diff cleanly
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct RegistryDump {
+    pub services: Vec<ServiceDump>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ServiceDump {
+    #[serde(default)]
+    pub namespace: String,
+    pub group: String,
+    pub name: String,
+    pub instances: Vec<InstanceDump>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct InstanceDump {
+    pub instance_id: InstanceId,
+    pub ip: String,
+    pub port: u32,
+    #[serde(default)]
+    pub metadata: BTreeMap<String, String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub version: String,
+    #[serde(default)]
+    pub state: InstanceState,
+    // Millis since the instance last registered or renewed its lease, as of the export
+    #[serde(default)]
+    pub age_ms: u64,
+    #[serde(default = "default_ttl")]
+    pub ttl_ms: u64,
+    #[serde(default)]
+    pub lease_id: u64,
+}
+
+fn default_ttl() -> u64 {
+    LEASE_TTL
+}
+
+impl RegistryDump {
+    // The services passing keep, with the ages of their instances as of now. Pinned instances
+    // are left to the static entries file.
+    pub fn new(map: &ServiceMap, keep: impl Fn(&ServiceId) -> bool, now: SystemTime) -> Self {
+        let mut services = map
+            .iter()
+            .filter(|(key, _)| keep(key))
+            .map(|((namespace, group, name), instances)| {
+                let mut instances = instances
+                    .iter()
+                    .filter(|(_, record)| !record.pinned)
+                    .map(|(instance_id, record)| InstanceDump {
+                        instance_id: instance_id.clone(),
+                        ip: record.addr.0.clone(),
+                        port: record.addr.1,
+                        metadata: record.metadata.clone(),
+                        tags: record.tags.clone(),
+                        version: record.version.clone(),
+                        state: record.state,
+                        age_ms: now
+                            .duration_since(record.last_updated)
+                            .unwrap_or(Duration::ZERO)
+                            .as_millis() as u64,
+                        ttl_ms: record.ttl,
+                        lease_id: record.lease_id,
+                    })
+                    .collect::<Vec<_>>();
+                instances.sort_by(|a, b| a.instance_id.cmp(&b.instance_id));
+                ServiceDump {
+                    namespace: namespace.clone(),
+                    group: group.clone(),
+                    name: name.clone(),
+                    instances,
+                }
+            })
+            .filter(|service| !service.instances.is_empty())
+            .collect::<Vec<_>>();
+        services.sort_by(|a, b| {
+            (&a.namespace, &a.group, &a.name).cmp(&(&b.namespace, &b.group, &b.name))
+        });
+
+        Self { services }
+    }
+
+    pub fn encode(&self, format: RegistryFormat) -> Result<String, ExportError> {
+        Ok(match format {
+            RegistryFormat::Json => serde_json::to_string_pretty(self)?,
+            RegistryFormat::Yaml => serde_yaml::to_string(self)?,
+        })
+    }
+
+    // Also checks that every instance is named, and named once
+    pub fn decode(data: &str, format: RegistryFormat) -> Result<RegistryDump, ExportError> {
+        let mut dump: RegistryDump = match format {
+            RegistryFormat::Json => serde_json::from_str(data)?,
+            RegistryFormat::Yaml => serde_yaml::from_str(data)?,
+        };
+
+        let mut seen = HashSet::new();
+        for service in &mut dump.services {
+            if service.namespace.is_empty() {
+                service.namespace = DEFAULT_NAMESPACE.to_string();
+            }
+            if service.group.is_empty() || service.name.is_empty() {
+                return Err(ExportError::Invalid(
+                    "every service needs a group and a name".to_string(),
+                ));
+            }
+            for instance in &service.instances {
+                let id = (
+                    &service.namespace,
+                    &service.group,
+                    &service.name,
+                    &instance.instance_id,
+                );
+                if instance.instance_id.is_empty() || !seen.insert(id) {
+                    return Err(ExportError::Invalid(format!(
+                        "{}/{}/{} has an empty or duplicate instance id {:?}",
+                        service.namespace, service.group, service.name, instance.instance_id
+                    )));
+                }
+            }
+        }
+
+        Ok(dump)
+    }
+
+    // The instances as records whose ages count back from now
+    pub fn into_map(self, now: SystemTime) -> ServiceMap {
+        let mut map = ServiceMap::new();
+        for service in self.services {
+            let instances = map
+                .entry((service.namespace, service.group, service.name))
+                .or_default();
+            for instance in service.instances {
+                let record = ServiceRecord {
+                    addr: (instance.ip, instance.port),
+                    last_updated: now
+                        .checked_sub(Duration::from_millis(instance.age_ms))
+                        .unwrap_or(now),
+                    metadata: instance.metadata,
+                    tags: instance.tags,
+                    version: instance.version,
+                    lease_id: instance.lease_id,
+                    ttl: instance.ttl_ms,
+                    state: instance.state,
+                    pinned: false,
+                };
+                instances.insert(instance.instance_id, record);
+            }
+        }
+        map
+    }
+}
+
+// The instances that are pinned, or those that aren't
+pub fn filter_pinned(map: ServiceMap, pinned: bool) -> ServiceMap {
+    map.into_iter()
+        .map(|(key, instances)| {
+            let instances = instances
+                .into_iter()
+                .filter(|(_, record)| record.pinned == pinned)
+                .collect::<ServiceInstances>();
+            (key, instances)
+        })
+        .filter(|(_, instances)| !instances.is_empty())
+        .collect()
+}
+
+pub type Change = (Kind, ServiceId, InstanceId, ServiceRecord);
+
+// Kind, as replicated
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum ChangeKind {
+    Added,
+    Updated,
+    Removed,
+}
+
+// The changes as JSON, for replicating them once the caller is authorized for them
+pub fn encode_changes(changes: &[Change]) -> Result<String, ExportError> {
+    let changes = changes
+        .iter()
+        .map(|(kind, key, instance_id, record)| {
+            let kind = match kind {
+                Kind::Added => ChangeKind::Added,
+                Kind::Updated => ChangeKind::Updated,
+                Kind::Removed => ChangeKind::Removed,
+            };
+            (kind, key, instance_id, record)
+        })
+        .collect::<Vec<_>>();
+    Ok(serde_json::to_string(&changes)?)
+}
+
+pub fn decode_changes(data: &str) -> Result<Vec<Change>, serde_json::Error> {
+    let changes: Vec<(ChangeKind, ServiceId, InstanceId, ServiceRecord)> =
+        serde_json::from_str(data)?;
+    let changes = changes
+        .into_iter()
+        .map(|(kind, key, instance_id, record)| {
+            let kind = match kind {
+                ChangeKind::Added => Kind::Added,
+                ChangeKind::Updated => Kind::Updated,
+                ChangeKind::Removed => Kind::Removed,
+            };
+            (kind, key, instance_id, record)
+        })
+        .collect();
+    Ok(changes)
+}
+
+// What importing `imported` into `current` changes, sorted by instance. Instances the import
+// leaves as they are aren't touched, so they keep their lease and age. Replacing only removes
+// instances from the namespaces and groups the import lists.
+pub fn diff(current: &ServiceMap, imported: &ServiceMap, replace: bool) -> Vec<Change> {
+    let mut changes = Vec::new();
+    for (key, instances) in imported {
+        for (instance_id, record) in instances {
+            let existing = current
+                .get(key)
+                .and_then(|instances| instances.get(instance_id));
+            let kind = match existing {
+                None => Kind::Added,
+                Some(old)
+                    if old.same_instance(record)
+                        && old.state == record.state
+                        && old.ttl == record.ttl =>
+                {
+                    continue
+                }
+                Some(_) => Kind::Updated,
+            };
+            changes.push((kind, key.clone(), instance_id.clone(), record.clone()));
+        }
+    }
+    if replace {
+        let groups = imported
+            .keys()
+            .map(|(namespace, group, _)| (namespace, group))
+            .collect::<HashSet<_>>();
+        let replaced = current
+            .iter()
+            .filter(|((namespace, group, _), _)| groups.contains(&(namespace, group)));
+        for (key, instances) in replaced {
+            for (instance_id, record) in instances {
+                let kept = imported
+                    .get(key)
+                    .map(|instances| instances.contains_key(instance_id));
+                if !matches!(kept, Some(true)) {
+                    changes.push((
+                        Kind::Removed,
+                        key.clone(),
+                        instance_id.clone(),
+                        record.clone(),
+                    ));
+                }
+            }
+        }
+    }
+
+    changes.sort_by(|a, b| (&a.1, &a.2).cmp(&(&b.1, &b.2)));
+    changes
+}
+
+pub fn to_change((kind, (namespace, group, name), instance_id, record): &Change) -> RegistryChange {
+    let mut change = RegistryChange {
+        namespace: namespace.clone(),
+        group: group.clone(),
+        name: name.clone(),
+        instance: Some(record.to_instance(instance_id)),
+        ..Default::default()
+    };
+    change.set_kind(*kind);
+    change
+}
+
+#[cfg(test)]
+mod test {
+    use super::{decode_changes, diff, encode_changes, RegistryDump};
+    use crate::svc_dsc::{
+        gen::{registry_change::Kind, RegistryFormat},
+        server::serdict::{InstanceState, ServiceMap, ServiceRecord},
+    };
+
+    use std::time::{Duration, SystemTime};
+
+    fn record(port: u32, state: InstanceState) -> ServiceRecord {
+        ServiceRecord {
+            addr: ("[::1]".to_string(), port),
+            last_updated: SystemTime::now() - Duration::from_millis(1500),
+            metadata: [("zone".to_string(), "a".to_string())].into(),
+            tags: vec!["canary".to_string()],
+            version: "1.2.0".to_string(),
+            lease_id: 7,
+            ttl: 5000,
+            state,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn it_imports_what_it_exports() {
+        let key = ("default".to_string(), "math".to_string(), "add".to_string());
+        let mut map = ServiceMap::new();
+        map.entry(key.clone()).or_default().extend([
+            ("a".to_string(), record(50052, InstanceState::Active)),
+            ("b".to_string(), record(50053, InstanceState::Draining)),
+        ]);
+        let now = SystemTime::now();
+        let dump = RegistryDump::new(&map, |_| true, now);
+
+        for format in [RegistryFormat::Json, RegistryFormat::Yaml] {
+            let data = dump.encode(format).unwrap();
+            let decoded = RegistryDump::decode(&data, format).unwrap();
+            assert_eq!(decoded, dump);
+            let imported = decoded.into_map(now);
+            assert!(diff(&map, &imported, true).is_empty());
+            let age = imported[&key]["b"].last_updated.elapsed().unwrap();
+            assert!(age >= Duration::from_millis(1500));
+        }
+
+        // Merging leaves a, replacing removes it
+        let mut other = ServiceMap::new();
+        other.entry(key.clone()).or_default().extend([
+            ("b".to_string(), record(50053, InstanceState::Active)),
+            ("c".to_string(), record(50054, InstanceState::Active)),
+        ]);
+        let changes = |replace| {
+            diff(&map, &other, replace)
+                .into_iter()
+                .map(|(kind, _, instance_id, _)| (instance_id, kind))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            changes(false),
+            vec![("b".into(), Kind::Updated), ("c".into(), Kind::Added)]
+        );
+        assert_eq!(
+            changes(true),
+            vec![
+                ("a".into(), Kind::Removed),
+                ("b".into(), Kind::Updated),
+                ("c".into(), Kind::Added)
+            ]
+        );
+
+        // Replacing leaves the groups the import doesn't list alone
+        let sub = ("default".to_string(), "math".to_string(), "sub".to_string());
+        let staging = ("staging".to_string(), "math".to_string(), "add".to_string());
+        for key in [&sub, &staging] {
+            map.entry(key.clone())
+                .or_default()
+                .insert("d".to_string(), record(50055, InstanceState::Active));
+        }
+        let replaced = diff(&map, &other, true);
+        assert_eq!(replaced.len(), 4);
+        assert!(replaced.iter().all(|(_, key, _, _)| key.0 == "default"));
+        assert!(replaced.iter().any(|(_, key, _, _)| *key == sub));
+
+        let ids = |changes: Vec<_>| {
+            changes
+                .into_iter()
+                .map(|(kind, key, instance_id, record): super::Change| {
+                    (kind, key, instance_id, record.addr)
+                })
+                .collect::<Vec<_>>()
+        };
+        let decoded = decode_changes(&encode_changes(&replaced).unwrap()).unwrap();
+        assert_eq!(ids(decoded), ids(replaced));
+    }
+}
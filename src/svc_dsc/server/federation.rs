@@ -0,0 +1,178 @@
+use futures::future::join_all;
+
+use crate::svc_dsc::{
+    client::{self, Identity, SerDictChannel},
+    gen::{ser_dict_client::SerDictClient, GetServiceResponse, RegistrySummary},
+};
+
+use super::serdict::ServiceId;
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, PoisonError, RwLock},
+    time::{Duration, Instant},
+};
+
+// The svc-dsc of another datacenter
+#[derive(Clone)]
+struct Peer {
+    datacenter: String,
+    client: SerDictClient<SerDictChannel>,
+}
+
+// What a peer had registered when it was last pulled
+struct Summary {
+    services: HashMap<ServiceId, GetServiceResponse>,
+    pulled_at: Instant,
+}
+
+// Lets GetService fall back to other datacenters. Every svc-dsc pulls the registry summary of its
+// peers, which only ever describe their own datacenter, so summaries don't travel further.
+#[derive(Clone)]
+pub struct Federation {
+    pub datacenter: String,
+    // In order of preference
+    peers: Vec<Peer>,
+    interval: Duration,
+    summaries: Arc<RwLock<HashMap<String, Summary>>>,
+}
+
+impl Federation {
+    pub fn new(datacenter: String, interval: Duration) -> Federation {
+        Self {
+            datacenter,
+            peers: Vec::new(),
+            interval,
+            summaries: Arc::new(RwLock::new(HashMap::new())),
+        }
+    }
+
+    // Adds the peers of "dc2=[::1]:50070,dc3=[::1]:50080"
+    pub fn with_peers(
+        mut self,
+        peers: &str,
+        identity: Identity,
+    ) -> Result<Federation, Box<dyn std::error::Error>> {
+        for peer in peers.split(',').filter(|peer| !peer.trim().is_empty()) {
+            let (datacenter, addr) = peer
+                .split_once('=')
+                .ok_or_else(|| format!("federation peer {:?} is not datacenter=addr", peer))?;
+            self.peers.push(Peer {
+                datacenter: datacenter.trim().to_string(),
+                client: client::connect_lazy(addr.trim(), identity.clone())?,
+            });
+        }
+        Ok(self)
+    }
+
+    // The live instances of the first datacenter that has some, as of its last summary.
+    // Summaries of peers that stopped answering are ignored after a few intervals.
+    pub fn remote_service(&self, key: &ServiceId) -> Option<GetServiceResponse> {
+        let summaries = self
+            .summaries
+            .read()
+            .unwrap_or_else(PoisonError::into_inner);
+        self.peers
+            .iter()
+            .filter_map(|peer| summaries.get(&peer.datacenter))
+            .filter(|summary| summary.pulled_at.elapsed() < self.interval * 3)
+            .find_map(|summary| summary.services.get(key).cloned())
+    }
+
+    fn update(&self, datacenter: &str, summary: RegistrySummary) {
+        let services = summary
+            .services
+            .into_iter()
+            .filter(|service| !service.instances.is_empty())
+            .map(|mut service| {
+                service.datacenter = datacenter.to_string();
+                let key = (
+                    service.namespace.clone(),
+                    service.group.clone(),
+                    service.name.clone(),
+                );
+                (key, service)
+            })
+            .collect();
+
+        let mut summaries = self
+            .summaries
+            .write()
+            .unwrap_or_else(PoisonError::into_inner);
+        summaries.insert(
+            datacenter.to_string(),
+            Summary {
+                services,
+                pulled_at: Instant::now(),
+            },
+        );
+    }
+
+    // Pulls the summary of every peer every interval, forever
+    pub async fn run(self) {
+        loop {
+            let pulls = self.peers.iter().cloned().map(|mut peer| async move {
+                let res = tokio::time::timeout(self.interval, peer.client.get_registry_summary(()))
+                    .await
+                    .map_err(|e| e.to_string())
+                    .and_then(|res| res.map_err(|e| e.message().to_string()));
+                (peer.datacenter, res)
+            });
+            for (datacenter, res) in join_all(pulls).await {
+                match res {
+                    Ok(res) => self.update(&datacenter, res.into_inner()),
+                    Err(e) => println!(
+                        "svc_dsc::federation: cannot pull the registry of {}: {}",
+                        datacenter, e
+                    ),
+                }
+            }
+
+            tokio::time::sleep(self.interval).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Federation;
+    use crate::svc_dsc::{
+        client::Identity,
+        gen::{GetServiceResponse, RegistrySummary, ServiceInstance},
+    };
+
+    use std::time::Duration;
+
+    fn summary(datacenter: &str, port: u32) -> RegistrySummary {
+        RegistrySummary {
+            datacenter: datacenter.to_string(),
+            services: vec![GetServiceResponse {
+                namespace: "default".into(),
+                group: "math".into(),
+                name: "add".into(),
+                instances: vec![ServiceInstance {
+                    port,
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }],
+        }
+    }
+
+    #[tokio::test]
+    async fn it_prefers_peers_in_order() {
+        let identity = Identity::from_env().unwrap();
+        let federation = Federation::new("dc1".into(), Duration::from_secs(5))
+            .with_peers("dc2=[::1]:1,dc3=[::1]:2", identity)
+            .unwrap();
+        let key = ("default".into(), "math".into(), "add".into());
+        assert!(federation.remote_service(&key).is_none());
+
+        federation.update("dc3", summary("dc3", 3));
+        assert_eq!(federation.remote_service(&key).unwrap().datacenter, "dc3");
+
+        federation.update("dc2", summary("dc2", 2));
+        let res = federation.remote_service(&key).unwrap();
+        assert_eq!((res.datacenter.as_str(), res.instances[0].port), ("dc2", 2));
+    }
+}
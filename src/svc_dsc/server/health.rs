@@ -0,0 +1,257 @@
+use futures::future::join_all;
+use tonic::Code;
+use tonic_health::proto::{
+    health_check_response::ServingStatus, health_client::HealthClient, HealthCheckRequest,
+};
+
+use crate::dst_pfm::tls;
+
+use super::{
+    registry::Registry,
+    serdict::{InstanceId, ServiceId},
+};
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, PoisonError, RwLock},
+    time::Duration,
+};
+
+// How an instance is asked whether it is healthy
+#[tonic::async_trait]
+pub trait Prober: Send + Sync {
+    // Checks the server of key at ip:port, giving up after timeout
+    async fn probe(
+        &self,
+        key: &ServiceId,
+        ip: &str,
+        port: u32,
+        timeout: Duration,
+    ) -> Result<(), String>;
+}
+
+// Probes every registered instance with grpc.health.v1.Health/Check. An instance failing
+// `threshold` probes in a row is left out of GetService and the List RPCs until a probe passes.
+// Probe results are local to this node, they are not replicated.
+#[derive(Clone)]
+pub struct HealthChecks {
+    interval: Duration,
+    threshold: u32,
+    prober: Arc<dyn Prober>,
+    // Consecutive failed probes, for instances that failed their last one
+    failures: Arc<RwLock<HashMap<ServiceId, HashMap<InstanceId, u32>>>>,
+}
+
+impl HealthChecks {
+    pub fn new(interval: Duration, threshold: u32) -> HealthChecks {
+        Self {
+            interval,
+            threshold,
+            prober: Arc::new(GrpcProber),
+            failures: Arc::new(RwLock::new(HashMap::new())),
+        }
+    }
+
+    pub fn with_prober(mut self, prober: Arc<dyn Prober>) -> HealthChecks {
+        self.prober = prober;
+        self
+    }
+
+    pub fn is_healthy(&self, service: &ServiceId, instance_id: &str) -> bool {
+        let failures = self.failures.read().unwrap_or_else(PoisonError::into_inner);
+        let count = failures
+            .get(service)
+            .and_then(|instances| instances.get(instance_id))
+            .copied()
+            .unwrap_or(0);
+        count < self.threshold
+    }
+
+    // Probes the instances of registry every interval, forever
+    pub async fn run(self, registry: Arc<dyn Registry>) {
+        loop {
+            tokio::time::sleep(self.interval).await;
+            self.check(registry.as_ref()).await;
+        }
+    }
+
+    // Probes every instance of registry once
+    async fn check(&self, registry: &dyn Registry) {
+        let services_map = match registry.list() {
+            Ok(services_map) => services_map,
+            Err(e) => {
+                println!("svc_dsc::health: failed to read registry: {}", e);
+                return;
+            }
+        };
+        let targets = services_map
+            .iter()
+            .flat_map(|(key, instances)| {
+                instances.iter().map(|(instance_id, record)| {
+                    ((key.clone(), instance_id.clone()), record.addr.clone())
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let probes = targets
+            .iter()
+            .map(|((key, _), (ip, port))| self.prober.probe(key, ip, *port, self.interval));
+        let results = join_all(probes).await;
+
+        // Failure counts are only ever replaced whole
+        let mut failures = self
+            .failures
+            .write()
+            .unwrap_or_else(PoisonError::into_inner);
+        let mut still_failing: HashMap<ServiceId, HashMap<InstanceId, u32>> = HashMap::new();
+        for (((key, instance_id), _), result) in targets.into_iter().zip(results) {
+            let count = failures
+                .get(&key)
+                .and_then(|instances| instances.get(&instance_id))
+                .copied()
+                .unwrap_or(0);
+            let (namespace, group, name) = &key;
+            match result {
+                Ok(_) if count >= self.threshold => println!(
+                    "svc_dsc::health: {}/{}/{}/{} is healthy again",
+                    namespace, group, name, instance_id
+                ),
+                Ok(_) => {}
+                Err(e) => {
+                    if count + 1 == self.threshold {
+                        println!(
+                            "svc_dsc::health: {}/{}/{}/{} is unhealthy: {}",
+                            namespace, group, name, instance_id, e
+                        );
+                    }
+                    still_failing
+                        .entry(key)
+                        .or_default()
+                        .insert(instance_id, count + 1);
+                }
+            }
+        }
+        // Also forgets the instances that are gone from the registry
+        *failures = still_failing;
+    }
+}
+
+// Asks for the overall health of the server with grpc.health.v1
+pub struct GrpcProber;
+
+#[tonic::async_trait]
+impl Prober for GrpcProber {
+    async fn probe(
+        &self,
+        key: &ServiceId,
+        ip: &str,
+        port: u32,
+        timeout: Duration,
+    ) -> Result<(), String> {
+        let (_, group, name) = key;
+        let endpoint = tls::endpoint(&format!("{}:{}", ip, port), group, name)
+            .map_err(|e| e.to_string())?
+            .connect_timeout(timeout)
+            .timeout(timeout);
+        let channel = endpoint.connect().await.map_err(|e| e.to_string())?;
+
+        let request = HealthCheckRequest {
+            service: "".to_string(),
+        };
+        match HealthClient::new(channel).check(request).await {
+            Ok(res) => match res.into_inner().status() {
+                ServingStatus::Serving => Ok(()),
+                status => Err(format!("status is {:?}", status)),
+            },
+            // The server answers but has no health service, its heartbeats are all we have
+            Err(status) if status.code() == Code::Unimplemented => Ok(()),
+            Err(status) => Err(status.message().to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{HealthChecks, Prober};
+    use crate::svc_dsc::server::{
+        registry::{MemoryRegistry, Registry},
+        serdict::{ServiceId, ServiceMap, ServiceRecord},
+    };
+
+    use std::{
+        sync::{
+            atomic::{AtomicBool, AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    // Answers from a switch instead of the network
+    #[derive(Default)]
+    struct Stub {
+        failing: AtomicBool,
+        probes: AtomicUsize,
+    }
+
+    #[tonic::async_trait]
+    impl Prober for Stub {
+        async fn probe(&self, _: &ServiceId, _: &str, _: u32, _: Duration) -> Result<(), String> {
+            self.probes.fetch_add(1, Ordering::Relaxed);
+            match self.failing.load(Ordering::Relaxed) {
+                true => Err("connection refused".to_string()),
+                false => Ok(()),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn it_marks_instances_unhealthy_after_failed_probes() {
+        let key = ("default".to_string(), "math".to_string(), "add".to_string());
+        let registry = MemoryRegistry::new(ServiceMap::new());
+        let record = ServiceRecord {
+            addr: ("[::1]".to_string(), 50052),
+            ..Default::default()
+        };
+        registry.insert(key.clone(), "a".into(), record).unwrap();
+
+        let stub = Arc::new(Stub::default());
+        let health = HealthChecks::new(Duration::from_millis(10), 3).with_prober(stub.clone());
+        assert!(health.is_healthy(&key, "a"));
+
+        stub.failing.store(true, Ordering::Relaxed);
+        for _ in 0..2 {
+            health.check(&registry).await;
+            assert!(health.is_healthy(&key, "a"));
+        }
+        health.check(&registry).await;
+        assert!(!health.is_healthy(&key, "a"));
+        health.check(&registry).await;
+        assert!(!health.is_healthy(&key, "a"));
+
+        // One passing probe is enough to come back
+        stub.failing.store(false, Ordering::Relaxed);
+        health.check(&registry).await;
+        assert!(health.is_healthy(&key, "a"));
+
+        // A failure after recovering starts counting from scratch
+        stub.failing.store(true, Ordering::Relaxed);
+        health.check(&registry).await;
+        assert!(health.is_healthy(&key, "a"));
+        assert_eq!(stub.probes.load(Ordering::Relaxed), 6);
+    }
+
+    #[test]
+    fn it_keeps_answering_after_a_panic() {
+        let key = ("default".to_string(), "math".to_string(), "add".to_string());
+        let health = HealthChecks::new(Duration::from_millis(10), 1);
+        let failures = Arc::clone(&health.failures);
+        let _ = std::thread::spawn(move || {
+            let _guard = failures.write().unwrap();
+            panic!("poisoning the failures lock");
+        })
+        .join();
+
+        assert!(health.failures.is_poisoned());
+        assert!(health.is_healthy(&key, "a"));
+    }
+}
@@ -0,0 +1,196 @@
+use serde::{Deserialize, Serialize};
+
+use crate::svc_dsc::gen::{KvEntry, Precondition};
+
+use super::lock::LockTable;
+
+use std::collections::BTreeMap;
+
+// (namespace, key)
+pub type KvKey = (String, String);
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct KvRecord {
+    pub value: String,
+    pub create_revision: u64,
+    pub mod_revision: u64,
+    pub version: u64,
+}
+
+impl KvRecord {
+    pub fn to_entry(&self, key: &str) -> KvEntry {
+        KvEntry {
+            key: key.to_string(),
+            value: self.value.clone(),
+            create_revision: self.create_revision,
+            mod_revision: self.mod_revision,
+            version: self.version,
+        }
+    }
+}
+
+// The group ACL rules are checked against for a key, its first path segment
+pub fn key_group(key: &str) -> &str {
+    match key.split_once('/') {
+        Some((group, _)) => group,
+        None => key,
+    }
+}
+
+// Configuration entries of every namespace, and the revision of the last change to any of them
+#[derive(Debug, Default, Clone)]
+pub struct KvMap {
+    pub revision: u64,
+    pub entries: BTreeMap<KvKey, KvRecord>,
+    // Taking a lock is a change too, its revision fences off writes by earlier holders
+    pub locks: LockTable,
+}
+
+impl KvMap {
+    pub fn get(&self, namespace: &str, key: &str) -> Option<&KvRecord> {
+        self.entries.get(&(namespace.to_string(), key.to_string()))
+    }
+
+    // Entries of namespace whose key starts with prefix, sorted by key
+    pub fn list<'a>(
+        &'a self,
+        namespace: &'a str,
+        prefix: &'a str,
+    ) -> impl Iterator<Item = (&'a str, &'a KvRecord)> + 'a {
+        self.entries
+            .range((namespace.to_string(), prefix.to_string())..)
+            .take_while(move |((key_namespace, key), _)| {
+                key_namespace == namespace && key.starts_with(prefix)
+            })
+            .map(|((_, key), record)| (key.as_str(), record))
+    }
+
+    // Whether key is where precondition expects it, always true without one
+    fn holds(&self, namespace: &str, key: &str, precondition: Option<&Precondition>) -> bool {
+        let revision = self
+            .get(namespace, key)
+            .map(|record| record.mod_revision)
+            .unwrap_or(0);
+        match precondition {
+            Some(precondition) => precondition.revision == revision,
+            None => true,
+        }
+    }
+
+    // The record putting value leaves at the next revision, or the current one when the
+    // precondition doesn't hold. Nothing changes until the record is inserted.
+    pub fn prepare_put(
+        &self,
+        namespace: &str,
+        key: &str,
+        value: String,
+        precondition: Option<&Precondition>,
+    ) -> Result<KvRecord, Option<KvRecord>> {
+        let current = self.get(namespace, key);
+        if !self.holds(namespace, key, precondition) {
+            return Err(current.cloned());
+        }
+
+        let revision = self.revision + 1;
+        Ok(match current {
+            Some(current) => KvRecord {
+                value,
+                create_revision: current.create_revision,
+                mod_revision: revision,
+                version: current.version + 1,
+            },
+            None => KvRecord {
+                value,
+                create_revision: revision,
+                mod_revision: revision,
+                version: 1,
+            },
+        })
+    }
+
+    // The record deleting key removes, with the revision that deletes it as its mod_revision.
+    // The current record when the precondition doesn't hold, nothing when there is no key.
+    pub fn prepare_delete(
+        &self,
+        namespace: &str,
+        key: &str,
+        precondition: Option<&Precondition>,
+    ) -> Result<KvRecord, Option<KvRecord>> {
+        let current = self.get(namespace, key);
+        match current {
+            Some(current) if self.holds(namespace, key, precondition) => Ok(KvRecord {
+                mod_revision: self.revision + 1,
+                ..current.clone()
+            }),
+            _ => Err(current.cloned()),
+        }
+    }
+
+    // Applies a put as logged. Inserting the same record twice is harmless.
+    pub fn insert(&mut self, namespace: String, key: String, record: KvRecord) {
+        self.revision = self.revision.max(record.mod_revision);
+        self.entries.insert((namespace, key), record);
+    }
+
+    // Applies a delete as logged, at revision
+    pub fn remove(&mut self, namespace: String, key: String, revision: u64) -> Option<KvRecord> {
+        self.revision = self.revision.max(revision);
+        self.entries.remove(&(namespace, key))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{key_group, KvMap};
+    use crate::svc_dsc::gen::Precondition;
+
+    fn put(kv: &mut KvMap, key: &str, value: &str, revision: Option<u64>) -> Result<u64, ()> {
+        let precondition = revision.map(|revision| Precondition { revision });
+        let record = kv
+            .prepare_put("default", key, value.into(), precondition.as_ref())
+            .map_err(drop)?;
+        let revision = record.mod_revision;
+        kv.insert("default".into(), key.into(), record);
+        Ok(revision)
+    }
+
+    #[test]
+    fn it_compares_revisions_and_lists_prefixes() {
+        let mut kv = KvMap::default();
+        assert_eq!(put(&mut kv, "math/div/timeout", "100", Some(0)), Ok(1));
+        assert_eq!(put(&mut kv, "math/div/timeout", "200", Some(0)), Err(()));
+        assert_eq!(put(&mut kv, "math/add/timeout", "50", None), Ok(2));
+        assert_eq!(put(&mut kv, "math/div/timeout", "300", Some(2)), Err(()));
+        assert_eq!(put(&mut kv, "math/div/timeout", "300", Some(1)), Ok(3));
+        assert_eq!(put(&mut kv, "hello/greeting", "hi", None), Ok(4));
+
+        let timeout = kv.get("default", "math/div/timeout").unwrap();
+        assert_eq!((timeout.create_revision, timeout.version), (1, 2));
+        let keys = kv.list("default", "math/").map(|(key, _)| key);
+        assert_eq!(
+            keys.collect::<Vec<_>>(),
+            vec!["math/add/timeout", "math/div/timeout"]
+        );
+        assert_eq!(kv.list("staging", "").count(), 0);
+
+        assert!(kv
+            .prepare_delete(
+                "default",
+                "math/div/timeout",
+                Some(&Precondition { revision: 1 })
+            )
+            .is_err());
+        let deleted = kv
+            .prepare_delete("default", "math/div/timeout", None)
+            .unwrap();
+        assert_eq!(deleted.mod_revision, 5);
+        kv.remove(
+            "default".into(),
+            "math/div/timeout".into(),
+            deleted.mod_revision,
+        );
+        assert_eq!((kv.revision, kv.list("default", "math/").count()), (5, 1));
+
+        assert_eq!(key_group("math/div/timeout"), "math");
+    }
+}
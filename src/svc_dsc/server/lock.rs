@@ -0,0 +1,170 @@
+use serde::{Deserialize, Serialize};
+
+use crate::svc_dsc::gen::Lock;
+
+use super::{kv::KvKey, serdict::LeaseId};
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    time::{Duration, SystemTime},
+};
+
+// A lease granted for holding locks, renewed over KeepAlive like the lease of an instance
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Session {
+    // Wall-clock time, so it still means something after a restart
+    pub last_updated: SystemTime,
+    // in millis
+    pub ttl: u64,
+    // The principal the lease was granted to, empty without an ACL
+    #[serde(default)]
+    pub owner: String,
+    #[serde(default)]
+    pub namespace: String,
+}
+
+impl Session {
+    pub fn new(ttl: u64, owner: String, namespace: String) -> Session {
+        Self {
+            last_updated: SystemTime::now(),
+            ttl,
+            owner,
+            namespace,
+        }
+    }
+
+    // Time left before the lease runs out
+    pub fn remaining(&self) -> Duration {
+        let age = self.last_updated.elapsed().unwrap_or(Duration::ZERO);
+        Duration::from_millis(self.ttl).saturating_sub(age)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct LockRecord {
+    pub lease_id: LeaseId,
+    // Who holds the lock, as told by them
+    pub holder: String,
+    // Revision of the key/value store when the lock was taken, higher for every later holder
+    pub token: u64,
+}
+
+impl LockRecord {
+    pub fn to_lock(&self, name: &str) -> Lock {
+        Lock {
+            name: name.to_string(),
+            lease_id: self.lease_id,
+            holder: self.holder.clone(),
+            token: self.token,
+        }
+    }
+}
+
+// A lock changing hands, None once it is free
+#[derive(Debug, Clone)]
+pub struct LockEvent {
+    pub key: KvKey,
+    pub holder: Option<LockRecord>,
+}
+
+// Locks of every namespace, by (namespace, name), and the leases holding them. A lease is held
+// until its expiry is applied, whatever the clock says, so every node agrees on who holds what.
+#[derive(Debug, Default, Clone)]
+pub struct LockTable {
+    pub sessions: HashMap<LeaseId, Session>,
+    pub locks: BTreeMap<KvKey, LockRecord>,
+}
+
+impl LockTable {
+    pub fn remaining(&self, lease_id: LeaseId) -> Duration {
+        match self.sessions.get(&lease_id) {
+            Some(session) => session.remaining(),
+            None => Duration::ZERO,
+        }
+    }
+
+    pub fn is_live(&self, lease_id: LeaseId) -> bool {
+        self.sessions.contains_key(&lease_id)
+    }
+
+    pub fn holder(&self, key: &KvKey) -> Option<&LockRecord> {
+        self.locks.get(key)
+    }
+
+    // Renews a lease not expired yet, returns its TTL or 0 when it is gone
+    pub fn renew(&mut self, lease_id: LeaseId) -> u64 {
+        match self.sessions.get_mut(&lease_id) {
+            Some(session) => {
+                session.last_updated = SystemTime::now();
+                session.ttl
+            }
+            None => 0,
+        }
+    }
+
+    // The leases that ran out by this node's clock, for the leader to expire
+    pub fn due(&self) -> Vec<LeaseId> {
+        self.sessions
+            .iter()
+            .filter(|(_, session)| session.remaining().is_zero())
+            .map(|(lease_id, _)| *lease_id)
+            .collect()
+    }
+
+    // Forgets the leases, and releases their locks. Returns the released ones.
+    pub fn expire(&mut self, lease_ids: &[LeaseId]) -> Vec<KvKey> {
+        for lease_id in lease_ids {
+            self.sessions.remove(lease_id);
+        }
+        let released = self
+            .locks
+            .iter()
+            .filter(|(_, lock)| !self.sessions.contains_key(&lock.lease_id))
+            .map(|(key, _)| key.clone())
+            .collect::<Vec<_>>();
+        for key in &released {
+            self.locks.remove(key);
+        }
+        released
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{LockRecord, LockTable, Session};
+
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn it_releases_locks_of_expired_leases() {
+        let mut table = LockTable::default();
+        let session = Session::new(60000, String::new(), "default".into());
+        table.sessions.insert(1, session.clone());
+        table.sessions.insert(
+            2,
+            Session {
+                last_updated: SystemTime::now() - Duration::from_secs(60),
+                ttl: 5000,
+                ..session
+            },
+        );
+        for (name, lease_id) in [("math/jobs", 1), ("hello/jobs", 2)] {
+            let lock = LockRecord {
+                lease_id,
+                holder: format!("instance-{lease_id}"),
+                token: lease_id,
+            };
+            table.locks.insert(("default".into(), name.into()), lock);
+        }
+
+        // Held until the expiry is applied
+        let hello = ("default".to_string(), "hello/jobs".to_string());
+        assert_eq!(table.holder(&hello).map(|lock| lock.lease_id), Some(2));
+        assert_eq!(table.due(), vec![2]);
+
+        assert_eq!(table.expire(&[2]), vec![hello.clone()]);
+        assert_eq!(table.locks.len(), 1);
+        assert!(table.holder(&hello).is_none());
+        assert_eq!((table.renew(1), table.renew(2)), (60000, 0));
+    }
+}
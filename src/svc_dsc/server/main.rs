@@ -1,32 +1,364 @@
-#![feature(hash_drain_filter)]
-#![feature(exact_size_is_empty)]
-
 use dotenv::dotenv;
-use tokio::sync::oneshot::{self, error::TryRecvError};
+use tokio::sync::broadcast;
+use tonic::{codegen::InterceptedService, transport::Server};
 
 use dist_rust_buted::{
-    dst_pfm::{serve_with_shutdown, ServiceConfig},
+    dst_pfm::{serve_with_shutdown, tls, ServiceConfig},
     svc_dsc::{
-        gen::ser_dict_server::SerDictServer, server::serdict::SerDictImpl,
-        server::serdict::ServiceRecord, HEARTBEAT_INTERVAL, SERVICE_GROUP, SERVICE_NAME,
+        self,
+        client::Identity,
+        gen::{
+            import_registry_request::Mode, ser_dict_server::SerDictServer, ExportRegistryRequest,
+            ImportRegistryRequest, RegistryFormat,
+        },
+        server::acl::{Acl, Authenticator},
+        server::admin,
+        server::audit::{AuditLog, EVENTS_FILE},
+        server::dns,
+        server::federation::Federation,
+        server::health::HealthChecks,
+        server::kv::KvMap,
+        server::pinned::StaticEntries,
+        server::raft::{gen::raft_server::RaftServer, PeerAuthenticator, RaftConfig, RaftNode},
+        server::registry::{Backend, MemoryRegistry, Registry, SqliteRegistry},
+        server::serdict::{Cluster, SerDictImpl, ServiceMap},
+        server::store::{FileStore, NoopStore, RegistryStore},
+        EVENT_HISTORY_SIZE, FEDERATION_SYNC_INTERVAL, HEALTH_CHECK_FAILURES, HEALTH_CHECK_INTERVAL,
+        HEARTBEAT_INTERVAL, SERVICE_GROUP, SERVICE_NAME, SNAPSHOT_INTERVAL, SQLITE_PATH,
+        STATIC_FILE_POLL_INTERVAL, WATCH_BUFFER_SIZE,
     },
 };
 
-use std::collections::HashMap;
 use std::env;
+use std::path::Path;
 use std::sync::Arc;
-use std::sync::RwLock;
+use std::sync::{Mutex, PoisonError, RwLock};
+use std::time::Duration;
+
+const USAGE: &str = "usage:
+  svc-dsc                                        serve
+  svc-dsc export [--yaml] [--namespace <ns>]     print the registry as JSON or YAML
+  svc-dsc import <file> [--replace] [--dry-run]  load an export, YAML for .yaml and .yml files";
+
+// Writes the whole registry and key/value store to the store. The store stays locked meanwhile,
+// so no change can reach the log between the snapshot and its truncation.
+fn snapshot(registry: &dyn Registry, kv: &RwLock<KvMap>, store: &Mutex<dyn RegistryStore>) {
+    let mut store = store.lock().unwrap_or_else(PoisonError::into_inner);
+    let service_map = match registry.list() {
+        Ok(service_map) => service_map,
+        Err(e) => {
+            println!("svc_dsc::snapshot: failed to read registry: {}", e);
+            return;
+        }
+    };
+    let kv_lock = kv.read().unwrap_or_else(PoisonError::into_inner);
+    match store.snapshot(&service_map, &kv_lock) {
+        Ok(_) => println!(
+            "svc_dsc::snapshot: saved {} service(s) and {} key(s)",
+            service_map.len(),
+            kv_lock.entries.len()
+        ),
+        Err(e) => println!("svc_dsc::snapshot: failed to save registry: {}", e),
+    }
+}
+
+// Keeps the instances where SERVICE_DISCOVERY_REGISTRY says, starting with those loaded from the
+// store
+fn registry(service_map: ServiceMap) -> Result<Arc<dyn Registry>, Box<dyn std::error::Error>> {
+    let backend = match env::var("SERVICE_DISCOVERY_REGISTRY") {
+        Ok(backend) => backend.parse()?,
+        Err(_) => Backend::Memory,
+    };
+
+    match backend {
+        Backend::Memory => Ok(Arc::new(MemoryRegistry::new(service_map))),
+        Backend::Sqlite => {
+            let path =
+                env::var("SERVICE_DISCOVERY_SQLITE_PATH").unwrap_or_else(|_| SQLITE_PATH.into());
+            let registry = SqliteRegistry::open(&path)?;
+            // The database already has what it was told before a restart, but the store may
+            // have logged changes it didn't see
+            for (key, instances) in service_map {
+                for (instance_id, record) in instances {
+                    registry.insert(key.clone(), instance_id, record)?;
+                }
+            }
+            println!("svc_dsc::registry: keeping instances in {}", path);
+            Ok(Arc::new(registry))
+        }
+    }
+}
+
+// Keeps the latest SERVICE_DISCOVERY_EVENT_HISTORY_SIZE registry events, in the data dir when
+// there is one
+fn audit_log() -> Result<AuditLog, Box<dyn std::error::Error>> {
+    let capacity = match env::var("SERVICE_DISCOVERY_EVENT_HISTORY_SIZE") {
+        Ok(capacity) => capacity.parse()?,
+        Err(_) => EVENT_HISTORY_SIZE,
+    };
+
+    match env::var("SERVICE_DISCOVERY_DATA_DIR") {
+        Ok(dir) => Ok(AuditLog::open(Path::new(&dir).join(EVENTS_FILE), capacity)?),
+        Err(_) => Ok(AuditLog::new(capacity)),
+    }
+}
+
+// Starts replicating with the other svc-dsc nodes when SERVICE_DISCOVERY_CLUSTER is set
+fn join_cluster(serdict: &SerDictImpl) -> Result<Option<Cluster>, Box<dyn std::error::Error>> {
+    let nodes = match env::var("SERVICE_DISCOVERY_CLUSTER") {
+        Ok(nodes) => nodes,
+        Err(_) => return Ok(None),
+    };
+    let node_id = env::var("SERVICE_DISCOVERY_NODE_ID")
+        .expect("SERVICE_DISCOVERY_NODE_ID must be set to run in a cluster")
+        .parse()?;
+    let read_consistency = env::var("SERVICE_DISCOVERY_READ_CONSISTENCY")
+        .unwrap_or_else(|_| "linearizable".to_string())
+        .parse()?;
+
+    // The raft log is what a node restarts from, the registry's own store is left out
+    let data_dir = env::var("SERVICE_DISCOVERY_DATA_DIR")
+        .expect("SERVICE_DISCOVERY_DATA_DIR must be set to run in a cluster");
+
+    // Nodes talk to each other as svc-dsc, and turn away anyone else
+    if tls::mode()? != tls::Mode::Mtls {
+        return Err(
+            "SERVICE_DISCOVERY_CLUSTER needs TLS_MODE=mtls, so that only svc-dsc nodes can join"
+                .into(),
+        );
+    }
+    tls::set_identity(SERVICE_GROUP, SERVICE_NAME);
+
+    let raft_cfg = RaftConfig::parse(node_id, &nodes, data_dir)?;
+    let node = Arc::new(RaftNode::new(&raft_cfg, Arc::new(serdict.clone()))?);
+
+    let raft_addr = raft_cfg.addr().parse()?;
+    let raft_service =
+        InterceptedService::new(RaftServer::from_arc(Arc::clone(&node)), PeerAuthenticator);
+    let mut server = Server::builder();
+    if let Some(tls_config) = tls::server_config(SERVICE_GROUP, SERVICE_NAME)? {
+        server = server.tls_config(tls_config)?;
+    }
+    tokio::spawn(async move {
+        println!(
+            "svc_dsc::join_cluster: serving raft node {} at {}",
+            node_id, raft_addr
+        );
+        if let Err(e) = server.add_service(raft_service).serve(raft_addr).await {
+            println!("svc_dsc::join_cluster: raft server error {}", e);
+        }
+    });
+    node.start();
+
+    Ok(Some(Cluster {
+        node,
+        read_consistency,
+    }))
+}
+
+// Probes registered instances as set by SERVICE_DISCOVERY_HEALTH_CHECK_*. An interval of 0 turns
+// the probes off.
+fn health_checks() -> Result<Option<HealthChecks>, Box<dyn std::error::Error>> {
+    let interval = match env::var("SERVICE_DISCOVERY_HEALTH_CHECK_INTERVAL") {
+        Ok(interval) => interval.parse()?,
+        Err(_) => HEALTH_CHECK_INTERVAL,
+    };
+    let failures = match env::var("SERVICE_DISCOVERY_HEALTH_CHECK_FAILURES") {
+        Ok(failures) => failures.parse()?,
+        Err(_) => HEALTH_CHECK_FAILURES,
+    };
+    if interval == 0 {
+        return Ok(None);
+    }
+
+    Ok(Some(HealthChecks::new(
+        Duration::from_millis(interval),
+        failures,
+    )))
+}
+
+// Looks services up in other datacenters as set by SERVICE_DISCOVERY_DATACENTER and
+// SERVICE_DISCOVERY_FEDERATION
+fn federation() -> Result<Option<Federation>, Box<dyn std::error::Error>> {
+    let datacenter = match env::var("SERVICE_DISCOVERY_DATACENTER") {
+        Ok(datacenter) => datacenter,
+        Err(_) => return Ok(None),
+    };
+    let peers = env::var("SERVICE_DISCOVERY_FEDERATION").unwrap_or_default();
+
+    let federation = Federation::new(datacenter, Duration::from_millis(FEDERATION_SYNC_INTERVAL));
+    // Peers are asked with this svc-dsc's own credentials
+    Ok(Some(federation.with_peers(&peers, Identity::from_env()?)?))
+}
+
+// Prints the registry of the running svc-dsc
+async fn export(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let mut request = ExportRegistryRequest::default();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--yaml" => request.set_format(RegistryFormat::Yaml),
+            "--namespace" => {
+                request.namespace = args.next().ok_or("--namespace needs a value")?.clone()
+            }
+            _ => return Err(format!("unknown argument {:?}", arg).into()),
+        }
+    }
+
+    let mut client = svc_dsc::client::client().await?;
+    let res = client.export_registry(request).await?.into_inner();
+    println!("{}", res.data.trim_end());
+    Ok(())
+}
+
+// Loads an export into the running svc-dsc, printing what changes
+async fn import(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let mut request = ImportRegistryRequest::default();
+    let mut path = None;
+    for arg in args {
+        match arg.as_str() {
+            "--replace" => request.set_mode(Mode::Replace),
+            "--dry-run" => request.dry_run = true,
+            _ if path.is_none() && !arg.starts_with("--") => path = Some(Path::new(arg)),
+            _ => return Err(format!("unknown argument {:?}", arg).into()),
+        }
+    }
+    let path = path.ok_or("import needs a file")?;
+    request.data = std::fs::read_to_string(path)?;
+    if matches!(
+        path.extension().and_then(|ext| ext.to_str()),
+        Some("yaml" | "yml")
+    ) {
+        request.set_format(RegistryFormat::Yaml);
+    }
+
+    let mut client = svc_dsc::client::client().await?;
+    let res = client.import_registry(request).await?.into_inner();
+    for change in &res.changes {
+        let instance_id = change
+            .instance
+            .as_ref()
+            .map(|instance| instance.instance_id.as_str())
+            .unwrap_or_default();
+        println!(
+            "{:<8} {}/{}/{} {}",
+            change.kind().as_str_name().to_lowercase(),
+            change.namespace,
+            change.group,
+            change.name,
+            instance_id
+        );
+    }
+    match res.applied {
+        true => println!("svc_dsc::import: applied {} change(s)", res.changes.len()),
+        false => println!(
+            "svc_dsc::import: dry run, {} change(s) not applied",
+            res.changes.len()
+        ),
+    }
+    Ok(())
+}
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     dotenv().expect("missing .env file. Create .env or run from the root of project");
 
+    let args = env::args().skip(1).collect::<Vec<_>>();
+    match args.split_first() {
+        None => {}
+        Some((command, args)) if command == "export" => return export(args).await,
+        Some((command, args)) if command == "import" => return import(args).await,
+        Some(_) => {
+            eprintln!("{}", USAGE);
+            std::process::exit(2);
+        }
+    }
+
     let host = env::var("SERVICE_DISCOVERY_HOST").expect("SERVICE_DISCOVERY_HOST must be set");
     let port = env::var("SERVICE_DISCOVERY_PORT").expect("SERVICE_DISCOVERY_PORT must be set");
 
-    let service_map = Arc::new(RwLock::new(HashMap::new()));
-    let serdict = SerDictImpl::new(Arc::clone(&service_map));
-    let service = SerDictServer::new(serdict);
+    // Persist the registry only when given somewhere to keep it. Cluster nodes start from their
+    // raft log instead, which the other nodes agreed on.
+    let clustered = env::var("SERVICE_DISCOVERY_CLUSTER").is_ok();
+    let store: Arc<Mutex<dyn RegistryStore>> = match env::var("SERVICE_DISCOVERY_DATA_DIR") {
+        Ok(dir) if !clustered => Arc::new(Mutex::new(FileStore::open(dir)?)),
+        _ => Arc::new(Mutex::new(NoopStore)),
+    };
+    let (service_map, kv) = store
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .load()?;
+    let registry = registry(service_map)?;
+    let kv = Arc::new(RwLock::new(kv));
+
+    let (events, _) = broadcast::channel(WATCH_BUFFER_SIZE);
+    let mut serdict = SerDictImpl::new(Arc::clone(&registry), events, Arc::clone(&store))
+        .with_kv(Arc::clone(&kv))
+        .with_audit(audit_log()?);
+    // Without a policy file anyone may read and change the registry
+    if let Ok(acl_file) = env::var("SERVICE_DISCOVERY_ACL_FILE") {
+        serdict = serdict.with_acl(Acl::load(acl_file)?);
+    }
+    if let Some(cluster) = join_cluster(&serdict)? {
+        serdict = serdict.with_cluster(cluster);
+    }
+    let federation_task = match federation()? {
+        Some(federation) => {
+            serdict = serdict.with_federation(federation.clone());
+            Some(tokio::spawn(federation.run()))
+        }
+        None => None,
+    };
+    // Releases the locks of leases that ran out while their KeepAlive stream was still open
+    let lock_task = tokio::spawn(
+        serdict
+            .clone()
+            .expire_locks(Duration::from_millis(HEARTBEAT_INTERVAL)),
+    );
+    let health_task = match health_checks()? {
+        Some(health) => {
+            serdict = serdict.with_health_checks(health.clone());
+            Some(tokio::spawn(health.run(Arc::clone(&registry))))
+        }
+        None => None,
+    };
+    // Pinned instances from a previous run go away with the file
+    let static_task = match env::var("SERVICE_DISCOVERY_STATIC_FILE") {
+        Ok(path) => {
+            let interval = Duration::from_millis(STATIC_FILE_POLL_INTERVAL);
+            let mut entries = StaticEntries::new(path, interval);
+            serdict.pin(entries.load()?)?;
+            Some(tokio::spawn(entries.run(serdict.clone())))
+        }
+        Err(_) => {
+            serdict.pin(ServiceMap::new())?;
+            None
+        }
+    };
+    // Answers svc.local lookups for tools that don't speak gRPC
+    if let Ok(dns_addr) = env::var("SERVICE_DISCOVERY_DNS_ADDR") {
+        let dns_addr = dns_addr.parse()?;
+        let serdict = serdict.clone();
+        tokio::spawn(async move {
+            if let Err(e) = dns::serve(dns_addr, serdict).await {
+                println!("svc_dsc::dns: stopped serving: {}", e);
+            }
+        });
+    }
+    // JSON API and status page for operators, on the same host as the gRPC server
+    if let Ok(admin_port) = env::var("SERVICE_DISCOVERY_ADMIN_PORT") {
+        let admin = admin::bind(format!("{}:{}", host, admin_port).parse()?)?;
+        let serdict = serdict.clone();
+        tokio::spawn(async move {
+            if let Err(e) = admin::serve(admin, serdict).await {
+                println!("svc_dsc::admin: stopped serving: {}", e);
+            }
+        });
+    }
+    // Expires instances as their leases run out
+    let reaper_task = tokio::spawn(serdict.clone().reap());
+    let authenticator = Authenticator::new(serdict.acl.clone());
+    let service = SerDictServer::with_interceptor(serdict, authenticator);
 
     let cfg = ServiceConfig {
         service_group: SERVICE_GROUP.to_string(),
@@ -34,62 +366,38 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         host,
         port: port.parse()?,
         should_register: false,
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        ..Default::default()
     };
 
-    let (shutdown_send, mut shutdown_recv) = oneshot::channel::<()>();
-
-    tokio::spawn(async move {
-        loop {
-            std::thread::sleep(std::time::Duration::from_millis(HEARTBEAT_INTERVAL));
-
-            match shutdown_recv.try_recv() {
-                Ok(_) | Err(TryRecvError::Closed) => {
-                    println!("svc_dsc::heartbeat_task: terminating...");
-                    break;
-                }
-                Err(TryRecvError::Empty) => {}
-            }
-            println!("svc_dsc::heartbeat_task: beating...");
-
-            let mut map_lock = service_map
-                .write()
-                .expect("svc_dsc::heartbeat_task: service_map lock is poisoned");
-            let registered_services = map_lock.keys();
-            let registered_services_count = registered_services.clone().count();
-            if registered_services.is_empty() {
-                println!("svc_dsc::heartbeat_task: no service registered");
-                continue;
-            }
-
-            let drained = map_lock
-                .drain_filter(|_, ServiceRecord { last_updated, .. }| {
-                    last_updated.elapsed().as_millis() >= (HEARTBEAT_INTERVAL as u128)
-                })
-                .collect::<HashMap<_, _>>();
-            if drained.keys().is_empty() {
-                println!(
-                    "svc_dsc::heartbeat_task: all {} registered service(s) are still alive",
-                    registered_services_count
-                );
-                continue;
+    let snapshot_task = {
+        let registry = Arc::clone(&registry);
+        let kv = Arc::clone(&kv);
+        let store = Arc::clone(&store);
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(Duration::from_millis(SNAPSHOT_INTERVAL)).await;
+                snapshot(registry.as_ref(), &kv, &store);
             }
-            let drained_services = drained
-                .keys()
-                .map(|(group, name)| format!("{}/{}", group, name))
-                .collect::<Vec<_>>();
-            println!(
-                "svc_dsc::heartbeat_task: bye bye dead services: {:?}",
-                drained_services
-            );
-        }
-    });
+        })
+    };
 
     if let Err(e) = serve_with_shutdown(service, &cfg).await {
         println!("svc-dsc: error {}", e);
     };
-    shutdown_send
-        .send(())
-        .expect("svc-dsc: failed at sending shutdown signal");
+    reaper_task.abort();
+    snapshot_task.abort();
+    lock_task.abort();
+    if let Some(health_task) = health_task {
+        health_task.abort();
+    }
+    if let Some(federation_task) = federation_task {
+        federation_task.abort();
+    }
+    if let Some(static_task) = static_task {
+        static_task.abort();
+    }
+    snapshot(registry.as_ref(), &kv, &store);
 
     Ok(())
 }
@@ -1 +1,17 @@
+pub mod acl;
+pub mod admin;
+pub mod audit;
+pub mod dns;
+pub mod expiry;
+pub mod export;
+pub mod federation;
+pub mod health;
+pub mod kv;
+pub mod lock;
+pub mod page;
+pub mod pinned;
+pub mod raft;
+pub mod raft_store;
+pub mod registry;
 pub mod serdict;
+pub mod store;
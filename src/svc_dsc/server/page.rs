@@ -0,0 +1,211 @@
+use thiserror::Error;
+
+use crate::svc_dsc::{gen::HealthFilter, DEFAULT_PAGE_SIZE, MAX_PAGE_SIZE};
+
+use super::{
+    registry::ScannedService,
+    serdict::{ServiceId, ServiceInstances},
+};
+
+#[derive(Debug, Error)]
+pub enum PageError {
+    #[error("invalid page token")]
+    Token,
+}
+
+// Which services a list call returns
+#[derive(Debug, Default)]
+pub struct ServiceFilter {
+    // Exactly this group, for ListServiceByGroupName
+    pub group: Option<String>,
+    pub group_prefix: String,
+    pub name_glob: String,
+    pub health: HealthFilter,
+    pub include_inactive: bool,
+}
+
+impl ServiceFilter {
+    pub fn matches(&self, (_, group, name): &ServiceId) -> bool {
+        let in_group = match &self.group {
+            Some(only) => group == only,
+            None => true,
+        };
+        in_group
+            && group.starts_with(&self.group_prefix)
+            && (self.name_glob.is_empty() || glob_match(&self.name_glob, name))
+    }
+
+    // Whether an instance passing its health checks, or not, is returned
+    pub fn keeps(&self, healthy: bool) -> bool {
+        match self.health {
+            HealthFilter::Healthy => healthy,
+            HealthFilter::Unhealthy => !healthy,
+            HealthFilter::AnyHealth => true,
+        }
+    }
+}
+
+// `*` matches any run of characters, `?` any one character
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern = pattern.chars().collect::<Vec<_>>();
+    let text = text.chars().collect::<Vec<_>>();
+    let (mut p, mut t) = (0, 0);
+    // Where the last * was, and the text it stood for up to then
+    let mut star = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            // Let the last * take one more character
+            _ => match star {
+                Some((star_p, star_t)) => {
+                    star = Some((star_p, star_t + 1));
+                    p = star_p + 1;
+                    t = star_t + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+// A page of services sorted by group then name. Tokens name the last service of the previous
+// page, so services registered or removed in between don't shift the pages after them.
+#[derive(Debug)]
+pub struct Page {
+    size: usize,
+    after: Option<(String, String)>,
+}
+
+impl Page {
+    pub fn new(page_size: u32, page_token: &str) -> Result<Page, PageError> {
+        let size = match page_size as usize {
+            0 => DEFAULT_PAGE_SIZE,
+            size => size.min(MAX_PAGE_SIZE),
+        };
+        let after = match page_token {
+            "" => None,
+            token => Some(decode_token(token).ok_or(PageError::Token)?),
+        };
+        Ok(Self { size, after })
+    }
+
+    // Fills the page from `scan`, which returns up to a number of services sorted by group then
+    // name after a group and name, and builds each with `build`, which skips a service by
+    // returning None. Also returns the token of the next page, empty when this one is the last.
+    pub fn fill<T, E>(
+        &self,
+        mut scan: impl FnMut(Option<(&str, &str)>, usize) -> Result<Vec<ScannedService>, E>,
+        mut build: impl FnMut(&ServiceId, &ServiceInstances) -> Option<T>,
+    ) -> Result<(Vec<T>, String), E> {
+        let mut after = self.after.clone();
+        let mut page = Vec::new();
+        let mut last: Option<(String, String)> = None;
+        loop {
+            let cursor = after
+                .as_ref()
+                .map(|(group, name)| (group.as_str(), name.as_str()));
+            let services = scan(cursor, self.size)?;
+            for (key, instances) in &services {
+                let Some(item) = build(key, instances) else {
+                    continue;
+                };
+                if page.len() == self.size {
+                    // A service is left for the next page
+                    let next = match &last {
+                        Some((group, name)) => encode_token(group, name),
+                        None => String::new(),
+                    };
+                    return Ok((page, next));
+                }
+                page.push(item);
+                last = Some((key.1.clone(), key.2.clone()));
+            }
+            match services.last() {
+                Some((key, _)) if services.len() == self.size => {
+                    after = Some((key.1.clone(), key.2.clone()))
+                }
+                _ => return Ok((page, String::new())),
+            }
+        }
+    }
+}
+
+// Hex of the JSON of the group and name, opaque to callers
+fn encode_token(group: &str, name: &str) -> String {
+    let json = serde_json::to_string(&(group, name)).unwrap_or_default();
+    json.bytes().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn decode_token(token: &str) -> Option<(String, String)> {
+    let bytes = (0..token.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(token.get(i..i + 2)?, 16).ok())
+        .collect::<Option<Vec<_>>>()?;
+    serde_json::from_slice(&bytes).ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{glob_match, Page};
+    use crate::svc_dsc::{server::serdict::ServiceInstances, DEFAULT_PAGE_SIZE, MAX_PAGE_SIZE};
+    use std::cell::Cell;
+
+    #[test]
+    fn it_pages_through_services_in_order() {
+        assert!(glob_match("add-*", "add-v2"));
+        assert!(glob_match("*d?v*", "math-div"));
+        assert!(!glob_match("add-*", "sub-v2"));
+        assert!(!glob_match("a?d", "add2"));
+
+        let mut keys = [
+            ("math", "sub"),
+            ("math", "add"),
+            ("calc", "calc"),
+            ("math", "div"),
+        ]
+        .map(|(group, name)| ("default".to_string(), group.to_string(), name.to_string()));
+        keys.sort();
+        let scans = Cell::new(0);
+        let names = |page: &Page| {
+            let scan = |after: Option<(&str, &str)>, limit| {
+                scans.set(scans.get() + 1);
+                let services = keys
+                    .iter()
+                    .filter(|key| match after {
+                        Some(after) => (key.1.as_str(), key.2.as_str()) > after,
+                        None => true,
+                    })
+                    .take(limit)
+                    .map(|key| (key.clone(), ServiceInstances::new()))
+                    .collect();
+                Ok::<_, ()>(services)
+            };
+            page.fill(scan, |key, _| match key.2.as_str() {
+                // Has no instance left
+                "div" => None,
+                name => Some(name.to_string()),
+            })
+            .unwrap()
+        };
+
+        let (first, token) = names(&Page::new(2, "").unwrap());
+        assert_eq!(first, vec!["calc", "add"]);
+        let (second, token) = names(&Page::new(2, &token).unwrap());
+        assert_eq!(second, vec!["sub"]);
+        assert!(token.is_empty());
+        // Full scans are followed by another, to find whether a service is left for the next page
+        assert_eq!(scans.get(), 4);
+        assert_eq!(names(&Page::new(0, "").unwrap()).0.len(), 3);
+        assert_eq!(Page::new(0, "").unwrap().size, DEFAULT_PAGE_SIZE);
+        assert_eq!(Page::new(u32::MAX, "").unwrap().size, MAX_PAGE_SIZE);
+        assert!(Page::new(2, "not a token").is_err());
+    }
+}
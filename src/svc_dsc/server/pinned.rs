@@ -0,0 +1,123 @@
+use thiserror::Error;
+
+use crate::svc_dsc::gen::RegistryFormat;
+
+use super::{
+    export::{ExportError, RegistryDump},
+    serdict::{SerDictImpl, ServiceMap},
+};
+
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+#[derive(Debug, Error)]
+pub enum StaticEntriesError {
+    #[error("cannot read static entries: {0}")]
+    Io(#[from] io::Error),
+    #[error("invalid static entries: {0}")]
+    Format(#[from] ExportError),
+}
+
+// Instances pinned by hand in a file, e.g. to point a service at a debug instance that doesn't
+// heartbeat. The file is in the format of `svc-dsc export`, YAML for .yaml and .yml files, and is
+// read again whenever it changes.
+pub struct StaticEntries {
+    path: PathBuf,
+    interval: Duration,
+    // When the file was last read
+    modified: Option<SystemTime>,
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|meta| meta.modified()).ok()
+}
+
+impl StaticEntries {
+    pub fn new(path: impl AsRef<Path>, interval: Duration) -> StaticEntries {
+        Self {
+            path: path.as_ref().to_path_buf(),
+            interval,
+            modified: None,
+        }
+    }
+
+    // The pinned instances the file holds. Ages, TTLs and leases in it are ignored.
+    pub fn load(&mut self) -> Result<ServiceMap, StaticEntriesError> {
+        self.modified = modified(&self.path);
+        let format = match self.path.extension().and_then(|ext| ext.to_str()) {
+            Some("yaml" | "yml") => RegistryFormat::Yaml,
+            _ => RegistryFormat::Json,
+        };
+        let data = fs::read_to_string(&self.path)?;
+        let mut entries = RegistryDump::decode(&data, format)?.into_map(SystemTime::now());
+        for record in entries
+            .values_mut()
+            .flat_map(|instances| instances.values_mut())
+        {
+            record.pinned = true;
+            record.lease_id = 0;
+        }
+        Ok(entries)
+    }
+
+    // Pins the entries again every time the file changes, forever. A file that can't be read
+    // leaves the pinned instances as they are.
+    pub async fn run(mut self, serdict: SerDictImpl) {
+        loop {
+            tokio::time::sleep(self.interval).await;
+            if modified(&self.path) == self.modified {
+                continue;
+            }
+
+            let entries = match self.load() {
+                Ok(entries) => entries,
+                Err(e) => {
+                    println!("svc_dsc::pinned: keeping the previous entries: {}", e);
+                    continue;
+                }
+            };
+            println!("svc_dsc::pinned: reloaded {:?}", self.path);
+            if let Err(e) = serdict.pin(entries) {
+                println!("svc_dsc::pinned: failed to pin entries: {}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::StaticEntries;
+
+    use std::time::Duration;
+
+    #[test]
+    fn it_loads_entries_that_never_expire() {
+        let path = std::env::temp_dir().join(format!("svc-dsc-static-{}.yaml", std::process::id()));
+        std::fs::write(
+            &path,
+            "services:
+- group: math
+  name: div
+  instances:
+  - instance_id: debug
+    ip: '[::1]'
+    port: 50099
+    age_ms: 600000
+    ttl_ms: 1000
+",
+        )
+        .unwrap();
+
+        let entries = StaticEntries::new(&path, Duration::from_secs(1))
+            .load()
+            .unwrap();
+        let key = ("default".to_string(), "math".to_string(), "div".to_string());
+        let record = &entries[&key]["debug"];
+        assert_eq!(record.addr, ("[::1]".to_string(), 50099));
+        assert!(record.pinned && !record.is_expired());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,1294 @@
+pub mod gen {
+    tonic::include_proto!("raft");
+}
+
+use futures::{stream::FuturesUnordered, StreamExt};
+use rand::Rng;
+use thiserror::Error;
+use tokio::sync::{oneshot, watch, Notify};
+use tonic::{service::Interceptor, transport::Channel, Request, Response, Status};
+
+use crate::{
+    dst_pfm::tls,
+    svc_dsc::{server::raft_store::RaftStore, SERVICE_GROUP, SERVICE_NAME},
+};
+
+use gen::{
+    raft_client::RaftClient, raft_server::Raft, AppendEntriesRequest, AppendEntriesResponse,
+    CallRequest, CallResponse, InstallSnapshotRequest, InstallSnapshotResponse, LogEntry,
+    ProposeRequest, ProposeResponse, ReadIndexRequest, ReadIndexResponse, StoredSnapshot,
+    VoteRequest, VoteResponse,
+};
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    io,
+    path::PathBuf,
+    str::FromStr,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    time::{Duration, Instant},
+};
+
+// How often the leader replicates to (or heartbeats) its followers
+const HEARTBEAT_TICK: Duration = Duration::from_millis(100);
+// A follower that hears nothing from the leader for this long (randomized) starts an election
+const ELECTION_TIMEOUT_MIN: u64 = 500;
+const ELECTION_TIMEOUT_MAX: u64 = 1000;
+const RPC_TIMEOUT: Duration = Duration::from_millis(400);
+// How long a proposal or a linearizable read may wait for the cluster
+const COMMIT_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_BATCH: usize = 256;
+// Applied entries are compacted into a state machine snapshot past this length
+const MAX_LOG_ENTRIES: usize = 1024;
+
+// What a cluster replicates. Commands are applied in the same order on every node.
+pub trait StateMachine: Send + Sync + 'static {
+    // Applies a committed command, the result is handed back to whoever proposed it
+    fn apply(&self, command: &[u8]) -> Result<Vec<u8>, String>;
+
+    // The whole state, as restore takes it. Compaction waits for another time when it fails.
+    fn snapshot(&self) -> Result<Vec<u8>, String>;
+
+    // Answers a request on the leader, about state it keeps outside the log like lease renewals.
+    // Nothing of it is replicated, a new leader starts over.
+    fn call(&self, request: &[u8]) -> Result<Vec<u8>, String>;
+
+    // Replaces the whole state with a snapshot, taken on this node before a restart or on another
+    fn restore(&self, snapshot: &[u8]) -> Result<(), String>;
+
+    // Drops the whole state, for a node starting without a snapshot to replay its log onto
+    fn clear(&self) -> Result<(), String>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReadConsistency {
+    // Served from whatever this node applied so far
+    Stale,
+    // Confirmed with the leader and a quorum before being served
+    Linearizable,
+}
+
+impl FromStr for ReadConsistency {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "stale" => Ok(Self::Stale),
+            "linearizable" => Ok(Self::Linearizable),
+            _ => Err(format!(
+                "unknown read consistency {s:?}, expected stale or linearizable"
+            )),
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum RaftError {
+    #[error("no leader is elected yet")]
+    NoLeader,
+    #[error("this node is not the leader")]
+    NotLeader,
+    #[error("leadership changed before the command was committed")]
+    LeadershipLost,
+    #[error("timed out waiting for the cluster")]
+    Timeout,
+    #[error("leader unreachable: {0}")]
+    Forward(String),
+    #[error("{0}")]
+    Rejected(String),
+}
+
+impl From<RaftError> for Status {
+    fn from(e: RaftError) -> Self {
+        match e {
+            RaftError::Rejected(msg) => Status::internal(msg),
+            e => Status::unavailable(e.to_string()),
+        }
+    }
+}
+
+// Lets only other svc-dsc nodes in. Whoever talks to the raft port can change the registry
+// without going through the ACL, so it is served with mtls only.
+#[derive(Clone)]
+pub struct PeerAuthenticator;
+
+impl Interceptor for PeerAuthenticator {
+    fn call(&mut self, req: Request<()>) -> Result<Request<()>, Status> {
+        match tls::peer_identity(&req) {
+            Some((group, name)) if group == SERVICE_GROUP && name == SERVICE_NAME => Ok(req),
+            Some((group, name)) => Err(Status::permission_denied(format!(
+                "{}/{} is not a svc-dsc node",
+                group, name
+            ))),
+            None => Err(Status::unauthenticated(
+                "raft peers must present a svc-dsc certificate",
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RaftConfig {
+    pub node_id: u64,
+    // Raft address of every node of the cluster, this one included
+    pub nodes: BTreeMap<u64, String>,
+    // Where the node keeps its term, vote and log
+    pub data_dir: PathBuf,
+}
+
+impl RaftConfig {
+    // Parses a node list like "1=[::1]:50060,2=[::1]:50061,3=[::1]:50062"
+    pub fn parse(
+        node_id: u64,
+        nodes: &str,
+        data_dir: impl Into<PathBuf>,
+    ) -> Result<RaftConfig, String> {
+        let nodes = nodes
+            .split(',')
+            .map(|node| {
+                let (id, addr) = node
+                    .trim()
+                    .split_once('=')
+                    .ok_or(format!("expected id=host:port, got {node:?}"))?;
+                // 0 stands for no vote in the stored hard state
+                let id = id
+                    .parse()
+                    .ok()
+                    .filter(|id| *id != 0)
+                    .ok_or(format!("invalid node id {id:?}"))?;
+                Ok((id, addr.to_string()))
+            })
+            .collect::<Result<BTreeMap<u64, String>, String>>()?;
+        if !nodes.contains_key(&node_id) {
+            return Err(format!("node {node_id} is not part of the cluster"));
+        }
+
+        Ok(Self {
+            node_id,
+            nodes,
+            data_dir: data_dir.into(),
+        })
+    }
+
+    pub fn addr(&self) -> &str {
+        &self.nodes[&self.node_id]
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+type Waiter = (u64, oneshot::Sender<Result<Vec<u8>, RaftError>>);
+type Proposal = oneshot::Receiver<Result<Vec<u8>, RaftError>>;
+
+struct State {
+    role: Role,
+    term: u64,
+    voted_for: Option<u64>,
+    leader_id: Option<u64>,
+    // log[i] holds the entry at index snapshot_index + 1 + i
+    log: Vec<LogEntry>,
+    snapshot_index: u64,
+    snapshot_term: u64,
+    snapshot: Vec<u8>,
+    commit_index: u64,
+    last_applied: u64,
+    // Leader only
+    next_index: HashMap<u64, u64>,
+    match_index: HashMap<u64, u64>,
+    election_deadline: Instant,
+    // When this node last became the leader
+    elected_at: Instant,
+    // Proposals of this node waiting for their index to be applied, with the term they were made in
+    waiters: HashMap<u64, Waiter>,
+    store: RaftStore,
+    // The term and vote last written to the store
+    stored_term: u64,
+    stored_vote: Option<u64>,
+    // The log is on disk up to this index
+    persisted_index: u64,
+}
+
+fn election_deadline() -> Instant {
+    let timeout = rand::thread_rng().gen_range(ELECTION_TIMEOUT_MIN..ELECTION_TIMEOUT_MAX);
+    Instant::now() + Duration::from_millis(timeout)
+}
+
+impl State {
+    fn last_index(&self) -> u64 {
+        self.snapshot_index + self.log.len() as u64
+    }
+
+    fn last_term(&self) -> u64 {
+        self.log
+            .last()
+            .map_or(self.snapshot_term, |entry| entry.term)
+    }
+
+    // None when the index is compacted away or not in the log yet
+    fn term_at(&self, index: u64) -> Option<u64> {
+        if index == self.snapshot_index {
+            return Some(self.snapshot_term);
+        }
+        if index < self.snapshot_index {
+            return None;
+        }
+        self.log
+            .get((index - self.snapshot_index - 1) as usize)
+            .map(|entry| entry.term)
+    }
+
+    fn entry(&self, index: u64) -> &LogEntry {
+        &self.log[(index - self.snapshot_index - 1) as usize]
+    }
+
+    // Writes the term, vote and new entries to disk. Has to succeed before the node tells anyone
+    // about them.
+    fn persist(&mut self) -> io::Result<()> {
+        if (self.term, self.voted_for) != (self.stored_term, self.stored_vote) {
+            self.store.save_hard_state(self.term, self.voted_for)?;
+            self.stored_term = self.term;
+            self.stored_vote = self.voted_for;
+        }
+        if self.persisted_index < self.last_index() {
+            let first_index = self.persisted_index.max(self.snapshot_index) + 1;
+            let entries = &self.log[(first_index - self.snapshot_index - 1) as usize..];
+            self.store.append(first_index, entries)?;
+            self.persisted_index = self.last_index();
+        }
+        Ok(())
+    }
+
+    fn become_follower(&mut self, term: u64, leader_id: Option<u64>) {
+        if term > self.term {
+            self.term = term;
+            self.voted_for = None;
+        }
+        self.role = Role::Follower;
+        self.leader_id = leader_id;
+        self.election_deadline = election_deadline();
+    }
+}
+
+pub struct RaftNode {
+    id: u64,
+    // Every other node of the cluster
+    peers: HashMap<u64, RaftClient<Channel>>,
+    state: Mutex<State>,
+    state_machine: Arc<dyn StateMachine>,
+    // Wakes replication up when the leader has new entries
+    replicate: Notify,
+    // Last applied index, for reads waiting to catch up
+    applied: watch::Sender<u64>,
+}
+
+impl RaftNode {
+    pub fn new(
+        cfg: &RaftConfig,
+        state_machine: Arc<dyn StateMachine>,
+    ) -> Result<RaftNode, Box<dyn std::error::Error>> {
+        let mut peers = HashMap::new();
+        for (id, addr) in cfg.nodes.iter().filter(|(id, _)| **id != cfg.node_id) {
+            let channel = tls::endpoint(addr, SERVICE_GROUP, SERVICE_NAME)?
+                .connect_timeout(RPC_TIMEOUT)
+                .timeout(RPC_TIMEOUT)
+                .connect_lazy();
+            peers.insert(*id, RaftClient::new(channel));
+        }
+
+        // The state machine starts from the last snapshot, entries after it are applied again as
+        // the leader says they are committed
+        let (store, saved) = RaftStore::open(&cfg.data_dir)?;
+        match saved.snapshot.index {
+            0 => state_machine.clear()?,
+            _ => state_machine.restore(&saved.snapshot.data)?,
+        }
+        let state = State {
+            role: Role::Follower,
+            term: saved.term,
+            voted_for: saved.voted_for,
+            leader_id: None,
+            persisted_index: saved.snapshot.index + saved.log.len() as u64,
+            log: saved.log,
+            snapshot_index: saved.snapshot.index,
+            snapshot_term: saved.snapshot.term,
+            snapshot: saved.snapshot.data,
+            commit_index: saved.snapshot.index,
+            last_applied: saved.snapshot.index,
+            next_index: HashMap::new(),
+            match_index: HashMap::new(),
+            election_deadline: election_deadline(),
+            elected_at: Instant::now(),
+            waiters: HashMap::new(),
+            store,
+            stored_term: saved.term,
+            stored_vote: saved.voted_for,
+        };
+
+        Ok(Self {
+            id: cfg.node_id,
+            peers,
+            applied: watch::channel(state.last_applied).0,
+            state: Mutex::new(state),
+            state_machine,
+            replicate: Notify::new(),
+        })
+    }
+
+    // Spawns the election timer and one replication task per peer
+    pub fn start(self: &Arc<Self>) {
+        let node = Arc::clone(self);
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(HEARTBEAT_TICK).await;
+                let should_campaign = {
+                    let state = node.lock();
+                    state.role != Role::Leader && Instant::now() >= state.election_deadline
+                };
+                if should_campaign {
+                    node.campaign().await;
+                }
+            }
+        });
+
+        for peer in self.peers.keys().copied() {
+            let node = Arc::clone(self);
+            tokio::spawn(async move {
+                loop {
+                    tokio::select! {
+                        _ = tokio::time::sleep(HEARTBEAT_TICK) => {}
+                        _ = node.replicate.notified() => {}
+                    }
+                    node.replicate_to(peer).await;
+                }
+            });
+        }
+    }
+
+    pub fn leader_id(&self) -> Option<u64> {
+        self.lock().leader_id
+    }
+
+    pub fn is_leader(&self) -> bool {
+        self.lock().role == Role::Leader
+    }
+
+    // How long this node has been the leader, None when it isn't
+    pub fn leading_for(&self) -> Option<Duration> {
+        let state = self.lock();
+        (state.role == Role::Leader).then(|| state.elected_at.elapsed())
+    }
+
+    // Runs a request on the leader's state machine, without logging it.
+    // Followers forward the request to the leader.
+    pub async fn call(&self, request: Vec<u8>) -> Result<Vec<u8>, RaftError> {
+        let leader_id = {
+            let state = self.lock();
+            match state.role {
+                Role::Leader => None,
+                _ => Some(state.leader_id.ok_or(RaftError::NoLeader)?),
+            }
+        };
+        let Some(leader_id) = leader_id else {
+            return self
+                .state_machine
+                .call(&request)
+                .map_err(RaftError::Rejected);
+        };
+
+        let mut client = self.peer(leader_id)?;
+        let res = tokio::time::timeout(COMMIT_TIMEOUT, client.call(CallRequest { request }))
+            .await
+            .map_err(|_| RaftError::Timeout)?
+            .map_err(|e| RaftError::Forward(e.message().to_string()))?
+            .into_inner();
+        if !res.error.is_empty() {
+            return Err(RaftError::Rejected(res.error));
+        }
+        Ok(res.response)
+    }
+
+    // Replicates a command, resolves once it is applied on the leader.
+    // Followers forward the command to the leader.
+    pub async fn propose(&self, command: Vec<u8>) -> Result<Vec<u8>, RaftError> {
+        let recv = match self.append(command) {
+            Ok(recv) => recv,
+            Err((leader_id, command)) => return self.forward(leader_id, command).await,
+        };
+        self.replicate.notify_waiters();
+
+        match tokio::time::timeout(COMMIT_TIMEOUT, recv).await {
+            Ok(Ok(result)) => result,
+            Ok(Err(_)) => Err(RaftError::LeadershipLost),
+            Err(_) => Err(RaftError::Timeout),
+        }
+    }
+
+    // Waits until this node applied everything committed before the call, so that reading its
+    // state afterwards is linearizable
+    pub async fn read_barrier(&self) -> Result<(), RaftError> {
+        let leader_id = {
+            let state = self.lock();
+            match state.role {
+                Role::Leader => None,
+                _ => Some(state.leader_id.ok_or(RaftError::NoLeader)?),
+            }
+        };
+
+        let index = match leader_id {
+            None => self.read_index().await?,
+            Some(leader_id) => {
+                self.peer(leader_id)?
+                    .read_index(ReadIndexRequest {})
+                    .await
+                    .map_err(|e| RaftError::Forward(e.message().to_string()))?
+                    .into_inner()
+                    .index
+            }
+        };
+
+        let mut applied = self.applied.subscribe();
+        let caught_up = async {
+            while *applied.borrow_and_update() < index {
+                if applied.changed().await.is_err() {
+                    break;
+                }
+            }
+        };
+        tokio::time::timeout(COMMIT_TIMEOUT, caught_up)
+            .await
+            .map_err(|_| RaftError::Timeout)
+    }
+
+    // Appends a command to the leader's log. Hands it back along with the known leader when this
+    // node isn't the leader.
+    fn append(&self, command: Vec<u8>) -> Result<Proposal, (Option<u64>, Vec<u8>)> {
+        let mut state = self.lock();
+        if state.role != Role::Leader {
+            return Err((state.leader_id, command));
+        }
+
+        let term = state.term;
+        state.log.push(LogEntry { term, command });
+        let index = state.last_index();
+        let (send, recv) = oneshot::channel();
+        state.waiters.insert(index, (term, send));
+        self.persist(&mut state);
+        // A single node cluster commits right away
+        self.advance_commit(&mut state);
+
+        Ok(recv)
+    }
+
+    // The leader only counts itself towards a quorum for entries it persisted, so a failed write
+    // is retried as it replicates
+    fn persist(&self, state: &mut State) {
+        if let Err(e) = state.persist() {
+            println!(
+                "svc_dsc::raft: node {} failed to persist its log: {}",
+                self.id, e
+            );
+        }
+    }
+
+    fn persist_failed(&self, e: io::Error) -> Status {
+        println!("svc_dsc::raft: node {} failed to persist: {}", self.id, e);
+        Status::internal(format!("failed to persist raft state: {}", e))
+    }
+
+    // Handlers change the state a step at a time and persist before answering, so a panic in one
+    // of them leaves nothing worse than a step not taken
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn majority(&self) -> usize {
+        let nodes = self.peers.len() + 1;
+        nodes / 2 + 1
+    }
+
+    // A leader this node has no address for is one from another cluster configuration
+    fn peer(&self, id: u64) -> Result<RaftClient<Channel>, RaftError> {
+        self.peers
+            .get(&id)
+            .cloned()
+            .ok_or_else(|| RaftError::Forward(format!("node {} is not a known peer", id)))
+    }
+
+    async fn forward(
+        &self,
+        leader_id: Option<u64>,
+        command: Vec<u8>,
+    ) -> Result<Vec<u8>, RaftError> {
+        let leader_id = leader_id.ok_or(RaftError::NoLeader)?;
+        let mut client = self.peer(leader_id)?;
+        let res = tokio::time::timeout(COMMIT_TIMEOUT, client.propose(ProposeRequest { command }))
+            .await
+            .map_err(|_| RaftError::Timeout)?
+            .map_err(|e| RaftError::Forward(e.message().to_string()))?
+            .into_inner();
+        if !res.error.is_empty() {
+            return Err(RaftError::Rejected(res.error));
+        }
+
+        Ok(res.result)
+    }
+
+    // The commit index, once a quorum confirmed this node still leads
+    async fn read_index(&self) -> Result<u64, RaftError> {
+        let (index, heartbeat) = {
+            let state = self.lock();
+            if state.role != Role::Leader {
+                return Err(RaftError::NotLeader);
+            }
+            // Until the no-op of this term is committed, the commit index may lag behind what
+            // the previous leader committed
+            if state.term_at(state.commit_index) != Some(state.term) {
+                return Err(RaftError::NoLeader);
+            }
+            let heartbeat = AppendEntriesRequest {
+                term: state.term,
+                leader_id: self.id,
+                prev_log_index: 0,
+                prev_log_term: 0,
+                entries: Vec::new(),
+                leader_commit: state.commit_index,
+            };
+            (state.commit_index, heartbeat)
+        };
+
+        let mut acks = 1;
+        let mut pending = self
+            .peers
+            .values()
+            .map(|client| {
+                let mut client = client.clone();
+                let heartbeat = heartbeat.clone();
+                async move { client.append_entries(heartbeat).await }
+            })
+            .collect::<FuturesUnordered<_>>();
+        while acks < self.majority() {
+            match pending.next().await {
+                Some(Ok(res)) => {
+                    let res = res.into_inner();
+                    if res.term > heartbeat.term {
+                        self.lock().become_follower(res.term, None);
+                        return Err(RaftError::LeadershipLost);
+                    }
+                    acks += 1;
+                }
+                Some(Err(_)) => {}
+                None => return Err(RaftError::Timeout),
+            }
+        }
+
+        Ok(index)
+    }
+
+    async fn campaign(&self) {
+        let req = {
+            let mut state = self.lock();
+            state.role = Role::Candidate;
+            state.term += 1;
+            state.voted_for = Some(self.id);
+            state.leader_id = None;
+            state.election_deadline = election_deadline();
+            println!(
+                "svc_dsc::raft: node {} campaigning for term {}",
+                self.id, state.term
+            );
+            // Voting for itself counts as much as any other vote
+            if let Err(e) = state.persist() {
+                println!(
+                    "svc_dsc::raft: node {} failed to persist its vote: {}",
+                    self.id, e
+                );
+                return;
+            }
+
+            if self.majority() == 1 {
+                self.become_leader(&mut state);
+                return;
+            }
+            VoteRequest {
+                term: state.term,
+                candidate_id: self.id,
+                last_log_index: state.last_index(),
+                last_log_term: state.last_term(),
+            }
+        };
+
+        let mut votes = 1;
+        let mut pending = self
+            .peers
+            .values()
+            .map(|client| {
+                let mut client = client.clone();
+                let req = req.clone();
+                async move { client.request_vote(req).await }
+            })
+            .collect::<FuturesUnordered<_>>();
+        while let Some(res) = pending.next().await {
+            let Ok(res) = res else {
+                continue;
+            };
+            let res = res.into_inner();
+
+            let mut state = self.lock();
+            if res.term > state.term {
+                state.become_follower(res.term, None);
+                return;
+            }
+            if state.role != Role::Candidate || state.term != req.term {
+                return;
+            }
+            if res.vote_granted {
+                votes += 1;
+                if votes >= self.majority() {
+                    self.become_leader(&mut state);
+                    return;
+                }
+            }
+        }
+    }
+
+    fn become_leader(&self, state: &mut State) {
+        println!(
+            "svc_dsc::raft: node {} is the leader of term {}",
+            self.id, state.term
+        );
+        state.role = Role::Leader;
+        state.leader_id = Some(self.id);
+        state.elected_at = Instant::now();
+        let next_index = state.last_index() + 1;
+        for peer in self.peers.keys() {
+            state.next_index.insert(*peer, next_index);
+            state.match_index.insert(*peer, 0);
+        }
+
+        // Committing an entry of its own term also commits what previous leaders left behind
+        let term = state.term;
+        state.log.push(LogEntry {
+            term,
+            command: Vec::new(),
+        });
+        self.persist(state);
+        self.advance_commit(state);
+        self.replicate.notify_waiters();
+    }
+
+    async fn replicate_to(&self, peer: u64) {
+        enum Message {
+            Append(AppendEntriesRequest),
+            Snapshot(InstallSnapshotRequest),
+        }
+
+        let message = {
+            let mut state = self.lock();
+            if state.role != Role::Leader {
+                return;
+            }
+            if state.persisted_index < state.last_index() {
+                self.persist(&mut state);
+                self.advance_commit(&mut state);
+            }
+            let next_index = state.next_index[&peer];
+            if next_index <= state.snapshot_index {
+                Message::Snapshot(InstallSnapshotRequest {
+                    term: state.term,
+                    leader_id: self.id,
+                    last_included_index: state.snapshot_index,
+                    last_included_term: state.snapshot_term,
+                    data: state.snapshot.clone(),
+                })
+            } else {
+                let prev_log_index = next_index - 1;
+                let last = state.last_index().min(prev_log_index + MAX_BATCH as u64);
+                Message::Append(AppendEntriesRequest {
+                    term: state.term,
+                    leader_id: self.id,
+                    prev_log_index,
+                    prev_log_term: state.term_at(prev_log_index).unwrap_or_default(),
+                    entries: (next_index..=last)
+                        .map(|index| state.entry(index).clone())
+                        .collect(),
+                    leader_commit: state.commit_index,
+                })
+            }
+        };
+
+        let Some(mut client) = self.peers.get(&peer).cloned() else {
+            return;
+        };
+        match message {
+            Message::Append(req) => {
+                let Ok(res) = client.append_entries(req.clone()).await else {
+                    return;
+                };
+                let res = res.into_inner();
+
+                let mut state = self.lock();
+                if res.term > state.term {
+                    state.become_follower(res.term, None);
+                    return;
+                }
+                if state.role != Role::Leader || state.term != req.term {
+                    return;
+                }
+                if res.success {
+                    let match_index = state.match_index[&peer].max(res.last_log_index);
+                    state.match_index.insert(peer, match_index);
+                    state.next_index.insert(peer, match_index + 1);
+                    self.advance_commit(&mut state);
+                } else {
+                    let next_index = state.next_index[&peer];
+                    let retry_from = (res.last_log_index + 1).min(next_index - 1).max(1);
+                    state.next_index.insert(peer, retry_from);
+                }
+            }
+            Message::Snapshot(req) => {
+                let Ok(res) = client.install_snapshot(req.clone()).await else {
+                    return;
+                };
+                let res = res.into_inner();
+
+                let mut state = self.lock();
+                if res.term > state.term {
+                    state.become_follower(res.term, None);
+                    return;
+                }
+                if state.role != Role::Leader || state.term != req.term {
+                    return;
+                }
+                let match_index = state.match_index[&peer].max(req.last_included_index);
+                state.match_index.insert(peer, match_index);
+                state.next_index.insert(peer, match_index + 1);
+            }
+        }
+    }
+
+    // Commits the highest entry of the current term stored on a majority
+    fn advance_commit(&self, state: &mut State) {
+        let majority = self.majority();
+        for index in (state.commit_index + 1..=state.last_index()).rev() {
+            if state.term_at(index) != Some(state.term) {
+                break;
+            }
+            let stored_here = usize::from(state.persisted_index >= index);
+            let stored_on = stored_here
+                + state
+                    .match_index
+                    .values()
+                    .filter(|match_index| **match_index >= index)
+                    .count();
+            if stored_on >= majority {
+                state.commit_index = index;
+                break;
+            }
+        }
+        self.apply_committed(state);
+    }
+
+    fn apply_committed(&self, state: &mut State) {
+        while state.last_applied < state.commit_index {
+            let index = state.last_applied + 1;
+            let entry = state.entry(index).clone();
+            let result = if entry.command.is_empty() {
+                Ok(Vec::new())
+            } else {
+                self.state_machine
+                    .apply(&entry.command)
+                    .map_err(RaftError::Rejected)
+            };
+            state.last_applied = index;
+
+            if let Some((term, send)) = state.waiters.remove(&index) {
+                let result = if term == entry.term {
+                    result
+                } else {
+                    Err(RaftError::LeadershipLost)
+                };
+                let _ = send.send(result);
+            }
+        }
+        self.applied.send_replace(state.last_applied);
+
+        if state.log.len() > MAX_LOG_ENTRIES && state.last_applied > state.snapshot_index {
+            let data = match self.state_machine.snapshot() {
+                Ok(data) => data,
+                Err(e) => {
+                    println!(
+                        "svc_dsc::raft: node {} failed to take a snapshot, not compacting: {}",
+                        self.id, e
+                    );
+                    return;
+                }
+            };
+            let last_applied = state.last_applied;
+            state.snapshot_term = state.term_at(last_applied).unwrap_or_default();
+            state.snapshot = data;
+            state
+                .log
+                .drain(..(last_applied - state.snapshot_index) as usize);
+            state.snapshot_index = last_applied;
+            // Until this succeeds the stored log still replays onto the older snapshot
+            let snapshot = StoredSnapshot {
+                index: state.snapshot_index,
+                term: state.snapshot_term,
+                data: state.snapshot.clone(),
+            };
+            let log = state.log.clone();
+            match state.store.save_snapshot(&snapshot, &log) {
+                Ok(()) => state.persisted_index = state.last_index(),
+                Err(e) => println!(
+                    "svc_dsc::raft: node {} failed to persist its snapshot: {}",
+                    self.id, e
+                ),
+            }
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl Raft for RaftNode {
+    async fn request_vote(
+        &self,
+        request: Request<VoteRequest>,
+    ) -> Result<Response<VoteResponse>, Status> {
+        let req = request.into_inner();
+
+        let mut state = self.lock();
+        if req.term > state.term {
+            state.become_follower(req.term, None);
+        }
+        let up_to_date =
+            (req.last_log_term, req.last_log_index) >= (state.last_term(), state.last_index());
+        let can_vote = state.voted_for.is_none() || state.voted_for == Some(req.candidate_id);
+        let vote_granted = req.term == state.term && can_vote && up_to_date;
+        if vote_granted {
+            state.voted_for = Some(req.candidate_id);
+            state.election_deadline = election_deadline();
+        }
+        state.persist().map_err(|e| self.persist_failed(e))?;
+
+        Ok(Response::new(VoteResponse {
+            term: state.term,
+            vote_granted,
+        }))
+    }
+
+    async fn append_entries(
+        &self,
+        request: Request<AppendEntriesRequest>,
+    ) -> Result<Response<AppendEntriesResponse>, Status> {
+        let req = request.into_inner();
+
+        let mut state = self.lock();
+        if req.term < state.term {
+            state.persist().map_err(|e| self.persist_failed(e))?;
+            return Ok(Response::new(AppendEntriesResponse {
+                term: state.term,
+                success: false,
+                last_log_index: state.last_index(),
+            }));
+        }
+        state.become_follower(req.term, Some(req.leader_id));
+
+        // Entries up to the snapshot are committed, so they match the leader's anyway
+        if req.prev_log_index >= state.snapshot_index
+            && state.term_at(req.prev_log_index) != Some(req.prev_log_term)
+        {
+            let last_log_index = state.last_index().min(req.prev_log_index - 1);
+            state.persist().map_err(|e| self.persist_failed(e))?;
+            return Ok(Response::new(AppendEntriesResponse {
+                term: state.term,
+                success: false,
+                last_log_index,
+            }));
+        }
+
+        let last_new_index = req.prev_log_index + req.entries.len() as u64;
+        for (index, entry) in (req.prev_log_index + 1..).zip(req.entries) {
+            if index <= state.snapshot_index {
+                continue;
+            }
+            match state.term_at(index) {
+                Some(term) if term == entry.term => continue,
+                Some(_) => {
+                    // Conflicting entries were never committed, drop them and what follows
+                    let keep = (index - state.snapshot_index - 1) as usize;
+                    state.log.truncate(keep);
+                    state.log.push(entry);
+                    state.persisted_index = state.persisted_index.min(index - 1);
+                }
+                None => state.log.push(entry),
+            }
+        }
+
+        // The leader counts the entries as stored once this answers
+        state.persist().map_err(|e| self.persist_failed(e))?;
+        let commit_index = req.leader_commit.min(last_new_index);
+        if commit_index > state.commit_index {
+            state.commit_index = commit_index;
+            self.apply_committed(&mut state);
+        }
+
+        Ok(Response::new(AppendEntriesResponse {
+            term: state.term,
+            success: true,
+            last_log_index: last_new_index,
+        }))
+    }
+
+    async fn install_snapshot(
+        &self,
+        request: Request<InstallSnapshotRequest>,
+    ) -> Result<Response<InstallSnapshotResponse>, Status> {
+        let req = request.into_inner();
+
+        let mut state = self.lock();
+        if req.term >= state.term {
+            state.become_follower(req.term, Some(req.leader_id));
+        }
+        state.persist().map_err(|e| self.persist_failed(e))?;
+        if req.term < state.term || req.last_included_index <= state.commit_index {
+            return Ok(Response::new(InstallSnapshotResponse { term: state.term }));
+        }
+
+        println!(
+            "svc_dsc::raft: node {} installing snapshot up to index {}",
+            self.id, req.last_included_index
+        );
+        // Entries after the snapshot are kept when they agree with it
+        let log = match state.term_at(req.last_included_index) {
+            Some(term) if term == req.last_included_term => {
+                let compacted = (req.last_included_index - state.snapshot_index) as usize;
+                state.log[compacted..].to_vec()
+            }
+            _ => Vec::new(),
+        };
+        let snapshot = StoredSnapshot {
+            index: req.last_included_index,
+            term: req.last_included_term,
+            data: req.data,
+        };
+        state
+            .store
+            .save_snapshot(&snapshot, &log)
+            .map_err(|e| self.persist_failed(e))?;
+        self.state_machine
+            .restore(&snapshot.data)
+            .map_err(Status::internal)?;
+        state.log = log;
+        state.snapshot_index = snapshot.index;
+        state.snapshot_term = snapshot.term;
+        state.snapshot = snapshot.data;
+        state.persisted_index = state.last_index();
+        state.commit_index = req.last_included_index;
+        state.last_applied = req.last_included_index;
+        self.applied.send_replace(state.last_applied);
+
+        Ok(Response::new(InstallSnapshotResponse { term: state.term }))
+    }
+
+    async fn propose(
+        &self,
+        request: Request<ProposeRequest>,
+    ) -> Result<Response<ProposeResponse>, Status> {
+        let ProposeRequest { command } = request.into_inner();
+
+        // Only accept writes as the leader, so a stale leader id can't bounce them around
+        if !self.is_leader() {
+            return Err(RaftError::NotLeader.into());
+        }
+
+        let res = match RaftNode::propose(self, command).await {
+            Ok(result) => ProposeResponse {
+                result,
+                error: String::new(),
+            },
+            Err(RaftError::Rejected(error)) => ProposeResponse {
+                result: Vec::new(),
+                error,
+            },
+            Err(e) => return Err(e.into()),
+        };
+
+        Ok(Response::new(res))
+    }
+
+    async fn read_index(
+        &self,
+        _request: Request<ReadIndexRequest>,
+    ) -> Result<Response<ReadIndexResponse>, Status> {
+        let index = RaftNode::read_index(self).await?;
+
+        Ok(Response::new(ReadIndexResponse { index }))
+    }
+
+    async fn call(&self, request: Request<CallRequest>) -> Result<Response<CallResponse>, Status> {
+        // As for writes, a stale leader id can't bounce requests around
+        if !self.is_leader() {
+            return Err(RaftError::NotLeader.into());
+        }
+
+        let res = match RaftNode::call(self, request.into_inner().request).await {
+            Ok(response) => CallResponse {
+                response,
+                error: String::new(),
+            },
+            Err(RaftError::Rejected(error)) => CallResponse {
+                response: Vec::new(),
+                error,
+            },
+            Err(e) => return Err(e.into()),
+        };
+
+        Ok(Response::new(res))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        gen::{raft_server::RaftServer, LogEntry, StoredSnapshot},
+        PeerAuthenticator, RaftConfig, RaftNode, StateMachine, MAX_LOG_ENTRIES,
+    };
+    use crate::svc_dsc::server::raft_store::RaftStore;
+
+    use futures::stream;
+    use tokio::net::TcpListener;
+    use tonic::{service::Interceptor, transport::Server, Code, Request};
+
+    use std::{
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc, Mutex,
+        },
+        time::Duration,
+    };
+
+    #[derive(Default)]
+    struct Log(Mutex<Vec<Vec<u8>>>);
+
+    impl StateMachine for Log {
+        fn apply(&self, command: &[u8]) -> Result<Vec<u8>, String> {
+            let mut log = self.0.lock().unwrap();
+            log.push(command.to_vec());
+            Ok(log.len().to_be_bytes().to_vec())
+        }
+
+        fn snapshot(&self) -> Result<Vec<u8>, String> {
+            Ok(self.0.lock().unwrap().concat())
+        }
+
+        fn call(&self, request: &[u8]) -> Result<Vec<u8>, String> {
+            Ok(request.to_vec())
+        }
+
+        fn restore(&self, snapshot: &[u8]) -> Result<(), String> {
+            *self.0.lock().unwrap() = snapshot.chunks(1).map(<[u8]>::to_vec).collect();
+            Ok(())
+        }
+
+        fn clear(&self) -> Result<(), String> {
+            self.0.lock().unwrap().clear();
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn it_turns_away_peers_without_a_certificate() {
+        let err = PeerAuthenticator.call(Request::new(())).unwrap_err();
+        assert_eq!(err.code(), Code::Unauthenticated);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn it_replicates_on_localhost() {
+        // Every node has to know the others' addresses before it starts
+        let mut listeners = Vec::new();
+        for _ in 1..=3 {
+            listeners.push(TcpListener::bind("127.0.0.1:0").await.unwrap());
+        }
+        let nodes = (1..)
+            .zip(&listeners)
+            .map(|(node_id, listener)| format!("{}={}", node_id, listener.local_addr().unwrap()))
+            .collect::<Vec<_>>()
+            .join(",");
+        let nodes = nodes.as_str();
+
+        let dir = std::env::temp_dir().join(format!("svc-dsc-raft-{}", std::process::id()));
+        let mut cluster = Vec::new();
+        for (node_id, listener) in (1..).zip(listeners) {
+            let cfg = RaftConfig::parse(node_id, nodes, dir.join(node_id.to_string())).unwrap();
+            let log = Arc::new(Log::default());
+            let node = Arc::new(RaftNode::new(&cfg, log.clone()).unwrap());
+
+            let incoming = stream::unfold(listener, |listener| async move {
+                let conn = listener.accept().await.map(|(conn, _)| conn);
+                Some((conn, listener))
+            });
+            let service = RaftServer::from_arc(Arc::clone(&node));
+            tokio::spawn(
+                Server::builder()
+                    .add_service(service)
+                    .serve_with_incoming(incoming),
+            );
+            node.start();
+            cluster.push((node, log));
+        }
+
+        let mut leader = None;
+        for _ in 0..100 {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            leader = cluster.iter().position(|(node, _)| node.is_leader());
+            if leader.is_some() {
+                break;
+            }
+        }
+        let leader = leader.expect("no leader elected");
+
+        // Writes sent to a follower are forwarded to the leader
+        let (follower, _) = &cluster[(leader + 1) % 3];
+        for command in [b"a", b"b", b"c"] {
+            follower.propose(command.to_vec()).await.unwrap();
+        }
+
+        // Every node catches up on a linearizable read
+        for (node, log) in &cluster {
+            node.read_barrier().await.unwrap();
+            assert_eq!(*log.0.lock().unwrap(), vec![b"a", b"b", b"c"]);
+        }
+
+        // Calls sent to a follower are answered by the leader, without logging them
+        assert_eq!(follower.call(b"d".to_vec()).await.unwrap(), b"d");
+        let (node, log) = &cluster[leader];
+        node.read_barrier().await.unwrap();
+        assert_eq!(log.0.lock().unwrap().len(), 3);
+
+        // A node restarting from disk remembers its term and log, and catches up again
+        let (node, _) = &cluster[(leader + 2) % 3];
+        let cfg = RaftConfig::parse(node.id, nodes, dir.join(node.id.to_string())).unwrap();
+        let log = Arc::new(Log::default());
+        let restarted = RaftNode::new(&cfg, log.clone()).unwrap();
+        let state = restarted.lock();
+        assert!(state.term > 0);
+        // The leader's no-op and the three commands
+        assert!(state.last_index() >= 4);
+        assert!(log.0.lock().unwrap().is_empty());
+        drop(state);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn it_restarts_from_a_snapshot() {
+        let dir = std::env::temp_dir().join(format!("svc-dsc-raft-restart-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let cfg = RaftConfig::parse(1, "1=127.0.0.1:1", &dir).unwrap();
+
+        // Left over from before the node stopped, and not in any snapshot
+        let log = Arc::new(Log::default());
+        log.apply(b"z").unwrap();
+        RaftNode::new(&cfg, log.clone()).unwrap();
+        assert!(log.0.lock().unwrap().is_empty());
+
+        let (mut store, _) = RaftStore::open(&dir).unwrap();
+        let snapshot = StoredSnapshot {
+            index: 2,
+            term: 1,
+            data: b"ab".to_vec(),
+        };
+        let entry = LogEntry {
+            term: 1,
+            command: b"c".to_vec(),
+        };
+        store.save_snapshot(&snapshot, &[entry]).unwrap();
+        drop(store);
+
+        log.apply(b"z").unwrap();
+        let node = Arc::new(RaftNode::new(&cfg, log.clone()).unwrap());
+        assert_eq!(*log.0.lock().unwrap(), vec![b"a", b"b"]);
+        {
+            let state = node.lock();
+            assert_eq!((state.commit_index, state.last_index()), (2, 3));
+        }
+
+        // Entries after the snapshot are applied once they are committed again
+        node.start();
+        for _ in 0..100 {
+            if node.is_leader() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        node.read_barrier().await.unwrap();
+        assert_eq!(*log.0.lock().unwrap(), vec![b"a", b"b", b"c"]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // A state machine whose snapshots fail while told to
+    #[derive(Default)]
+    struct Unreadable(AtomicBool);
+
+    impl StateMachine for Unreadable {
+        fn apply(&self, _command: &[u8]) -> Result<Vec<u8>, String> {
+            Ok(Vec::new())
+        }
+
+        fn snapshot(&self) -> Result<Vec<u8>, String> {
+            match self.0.load(Ordering::Relaxed) {
+                true => Err("disk I/O error".to_string()),
+                false => Ok(b"snapshot".to_vec()),
+            }
+        }
+
+        fn call(&self, _request: &[u8]) -> Result<Vec<u8>, String> {
+            Ok(Vec::new())
+        }
+
+        fn restore(&self, _snapshot: &[u8]) -> Result<(), String> {
+            Ok(())
+        }
+
+        fn clear(&self) -> Result<(), String> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn it_compacts_only_when_the_snapshot_is_taken() {
+        let dir = std::env::temp_dir().join(format!("svc-dsc-raft-compact-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let cfg = RaftConfig::parse(1, "1=127.0.0.1:1", &dir).unwrap();
+        let state_machine = Arc::new(Unreadable(AtomicBool::new(true)));
+        let node = RaftNode::new(&cfg, state_machine.clone()).unwrap();
+
+        let mut state = node.lock();
+        let entries = MAX_LOG_ENTRIES as u64 + 10;
+        state.log = (0..entries)
+            .map(|_| LogEntry {
+                term: 1,
+                command: Vec::new(),
+            })
+            .collect();
+        state.commit_index = entries;
+        node.apply_committed(&mut state);
+        // Applied, but the log is kept whole for a later try
+        assert_eq!(state.last_applied, entries);
+        assert_eq!(
+            (state.snapshot_index, state.log.len()),
+            (0, entries as usize)
+        );
+
+        state_machine.0.store(false, Ordering::Relaxed);
+        node.apply_committed(&mut state);
+        assert_eq!((state.snapshot_index, state.log.len()), (entries, 0));
+        assert_eq!(state.snapshot, b"snapshot");
+        drop(state);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
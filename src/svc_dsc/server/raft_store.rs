@@ -0,0 +1,222 @@
+use prost::Message;
+
+use super::raft::gen::{HardState, LogEntry, StoredEntry, StoredSnapshot};
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+const HARD_STATE_FILE: &str = "raft-state.pb";
+const LOG_FILE: &str = "raft-log.pb";
+const SNAPSHOT_FILE: &str = "raft-snapshot.pb";
+
+// Where a raft node keeps its term and vote, its log, and the snapshot the log starts after, so
+// that a restart doesn't make it vote twice in a term or forget entries it acknowledged. Every
+// write is synced before it returns.
+#[derive(Debug)]
+pub struct RaftStore {
+    dir: PathBuf,
+    log: File,
+}
+
+// What a node had stored when it stopped
+#[derive(Debug, Default)]
+pub struct Saved {
+    pub term: u64,
+    pub voted_for: Option<u64>,
+    pub snapshot: StoredSnapshot,
+    // The entries after the snapshot
+    pub log: Vec<LogEntry>,
+}
+
+impl RaftStore {
+    pub fn open(dir: impl AsRef<Path>) -> io::Result<(RaftStore, Saved)> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+
+        let mut saved = Saved::default();
+        if let Some(bytes) = read(&dir.join(HARD_STATE_FILE))? {
+            let hard_state = HardState::decode(bytes.as_slice())?;
+            saved.term = hard_state.term;
+            saved.voted_for = Some(hard_state.voted_for).filter(|id| *id != 0);
+        }
+        if let Some(bytes) = read(&dir.join(SNAPSHOT_FILE))? {
+            saved.snapshot = StoredSnapshot::decode(bytes.as_slice())?;
+        }
+
+        let log_path = dir.join(LOG_FILE);
+        let bytes = read(&log_path)?.unwrap_or_default();
+        let valid = replay(&bytes, &mut saved);
+        let log = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&log_path)?;
+        // What a crash in the middle of an append left behind
+        if valid < bytes.len() {
+            println!(
+                "svc_dsc::raft_store: dropping {} bytes of torn entries from {}",
+                bytes.len() - valid,
+                log_path.display()
+            );
+            log.set_len(valid as u64)?;
+        }
+        println!(
+            "svc_dsc::raft_store: loaded term {}, snapshot up to {} and {} entries from {}",
+            saved.term,
+            saved.snapshot.index,
+            saved.log.len(),
+            dir.display()
+        );
+
+        Ok((Self { dir, log }, saved))
+    }
+
+    pub fn save_hard_state(&mut self, term: u64, voted_for: Option<u64>) -> io::Result<()> {
+        let hard_state = HardState {
+            term,
+            voted_for: voted_for.unwrap_or_default(),
+        };
+        write_atomically(&self.dir.join(HARD_STATE_FILE), &hard_state.encode_to_vec())
+    }
+
+    // Stores entries from first_index on, in place of those stored at and after it
+    pub fn append(&mut self, first_index: u64, entries: &[LogEntry]) -> io::Result<()> {
+        let mut bytes = Vec::new();
+        for (index, entry) in (first_index..).zip(entries) {
+            let stored = StoredEntry {
+                index,
+                entry: Some(entry.clone()),
+            };
+            stored.encode_length_delimited(&mut bytes)?;
+        }
+        self.log.write_all(&bytes)?;
+        self.log.sync_data()
+    }
+
+    // Replaces the snapshot, and the log with the entries that follow it
+    pub fn save_snapshot(&mut self, snapshot: &StoredSnapshot, log: &[LogEntry]) -> io::Result<()> {
+        write_atomically(&self.dir.join(SNAPSHOT_FILE), &snapshot.encode_to_vec())?;
+
+        // The old log still replays onto the new snapshot if this is cut short
+        let log_path = self.dir.join(LOG_FILE);
+        let tmp_path = self.dir.join(format!("{}.tmp", LOG_FILE));
+        let mut tmp = File::create(&tmp_path)?;
+        let mut bytes = Vec::new();
+        for (index, entry) in (snapshot.index + 1..).zip(log) {
+            let stored = StoredEntry {
+                index,
+                entry: Some(entry.clone()),
+            };
+            stored.encode_length_delimited(&mut bytes)?;
+        }
+        tmp.write_all(&bytes)?;
+        tmp.sync_all()?;
+        fs::rename(&tmp_path, &log_path)?;
+        self.log = OpenOptions::new().append(true).open(&log_path)?;
+        Ok(())
+    }
+}
+
+fn read(path: &Path) -> io::Result<Option<Vec<u8>>> {
+    match fs::read(path) {
+        Ok(bytes) => Ok(Some(bytes)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+fn write_atomically(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    let tmp_path = path.with_extension("tmp");
+    let mut tmp = File::create(&tmp_path)?;
+    tmp.write_all(bytes)?;
+    tmp.sync_all()?;
+    fs::rename(&tmp_path, path)
+}
+
+// Replays stored entries onto the log after the snapshot, returns how many bytes were whole
+// entries. An entry replaces the one stored at its index before, and all those after it.
+fn replay(bytes: &[u8], saved: &mut Saved) -> usize {
+    let mut buf = bytes;
+    let mut valid = 0;
+    while !buf.is_empty() {
+        let Ok(stored) = StoredEntry::decode_length_delimited(&mut buf) else {
+            break;
+        };
+        valid = bytes.len() - buf.len();
+
+        let Some(entry) = stored.entry else {
+            continue;
+        };
+        if stored.index <= saved.snapshot.index {
+            continue;
+        }
+        let position = (stored.index - saved.snapshot.index - 1) as usize;
+        if position > saved.log.len() {
+            println!(
+                "svc_dsc::raft_store: entry {} follows a gap, ignoring it",
+                stored.index
+            );
+            continue;
+        }
+        saved.log.truncate(position);
+        saved.log.push(entry);
+    }
+    valid
+}
+
+#[cfg(test)]
+mod test {
+    use super::{RaftStore, LOG_FILE};
+    use crate::svc_dsc::server::raft::gen::{LogEntry, StoredSnapshot};
+
+    use std::{fs::OpenOptions, io::Write};
+
+    fn entry(term: u64, command: &str) -> LogEntry {
+        LogEntry {
+            term,
+            command: command.as_bytes().to_vec(),
+        }
+    }
+
+    #[test]
+    fn it_remembers_votes_and_entries() {
+        let dir = std::env::temp_dir().join(format!("svc-dsc-raft-store-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let (mut store, saved) = RaftStore::open(&dir).unwrap();
+        assert_eq!((saved.term, saved.voted_for), (0, None));
+        store.save_hard_state(3, Some(2)).unwrap();
+        store
+            .append(1, &[entry(1, "a"), entry(1, "b"), entry(2, "c")])
+            .unwrap();
+        // A new leader overwrote b and c
+        store.append(2, &[entry(3, "d")]).unwrap();
+        drop(store);
+        // Torn by a crash
+        let mut log = OpenOptions::new()
+            .append(true)
+            .open(dir.join(LOG_FILE))
+            .unwrap();
+        log.write_all(&[42, 1, 2]).unwrap();
+
+        let (mut store, saved) = RaftStore::open(&dir).unwrap();
+        assert_eq!((saved.term, saved.voted_for), (3, Some(2)));
+        assert_eq!(saved.log, vec![entry(1, "a"), entry(3, "d")]);
+        store.append(3, &[entry(3, "e")]).unwrap();
+        let snapshot = StoredSnapshot {
+            index: 2,
+            term: 3,
+            data: b"ad".to_vec(),
+        };
+        store.save_snapshot(&snapshot, &[entry(3, "e")]).unwrap();
+        store.append(4, &[entry(3, "f")]).unwrap();
+        drop(store);
+
+        let (_, saved) = RaftStore::open(&dir).unwrap();
+        assert_eq!(saved.snapshot, snapshot);
+        assert_eq!(saved.log, vec![entry(3, "e"), entry(3, "f")]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,987 @@
+use rusqlite::{params, params_from_iter, Connection, OptionalExtension, Row, ToSql, Transaction};
+use thiserror::Error;
+use tokio::{
+    runtime::{Handle, RuntimeFlavor},
+    task,
+};
+
+use crate::svc_dsc::REGISTRY_SHARDS;
+
+use super::{
+    expiry::Deadlines,
+    serdict::{InstanceId, LeaseId, ServiceId, ServiceInstances, ServiceMap, ServiceRecord},
+};
+
+use std::{
+    collections::{hash_map::DefaultHasher, BTreeMap, BTreeSet, HashMap},
+    hash::{Hash, Hasher},
+    io,
+    ops::Bound,
+    path::Path,
+    str::FromStr,
+    sync::{Mutex, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+// An instance, along with the service it is registered under
+pub type Registration = (ServiceId, InstanceId, ServiceRecord);
+
+// A service, along with every instance of it
+pub type ScannedService = (ServiceId, ServiceInstances);
+
+#[derive(Error, Debug)]
+pub enum RegistryError {
+    #[error("registry database error: {0}")]
+    Sqlite(#[from] rusqlite::Error),
+    #[error("unreadable instance record: {0}")]
+    Record(#[from] serde_json::Error),
+    #[error("failed to log registry change: {0}")]
+    Store(#[from] io::Error),
+}
+
+// Where the registered instances are kept, chosen by SERVICE_DISCOVERY_REGISTRY.
+// Calls are synchronous and short: raft applies replicated changes from under its own lock, and
+// handlers call in without holding anything across an await.
+pub trait Registry: Send + Sync {
+    // Adds or replaces an instance, returns the record it replaced
+    fn insert(
+        &self,
+        key: ServiceId,
+        instance_id: InstanceId,
+        record: ServiceRecord,
+    ) -> Result<Option<ServiceRecord>, RegistryError>;
+
+    // Returns the removed record, None when there was no such instance
+    fn remove(
+        &self,
+        key: &ServiceId,
+        instance_id: &str,
+    ) -> Result<Option<ServiceRecord>, RegistryError>;
+
+    // The instances of a service, expired ones included. Empty when it has none.
+    fn get(&self, key: &ServiceId) -> Result<ServiceInstances, RegistryError>;
+
+    // Every service, expired instances included
+    fn list(&self) -> Result<ServiceMap, RegistryError>;
+
+    // Up to `limit` services of the namespace, sorted by group then name, that come after the
+    // group and name of `after`. Only those of `group` when given, expired instances included.
+    fn scan(
+        &self,
+        namespace: &str,
+        group: Option<&str>,
+        after: Option<(&str, &str)>,
+        limit: usize,
+    ) -> Result<Vec<ScannedService>, RegistryError>;
+
+    // The instance holding a lease
+    fn find_lease(&self, lease_id: LeaseId) -> Result<Option<Registration>, RegistryError>;
+
+    // Renews a live lease, returns its TTL or None when no live instance holds it
+    fn renew(&self, lease_id: LeaseId) -> Result<Option<u64>, RegistryError>;
+
+    // Removes the instances whose lease ran out, and returns them
+    fn expire(&self) -> Result<Vec<Registration>, RegistryError>;
+
+    // The instances whose lease ran out, left in place for the cluster's leader to expire them
+    // on every node
+    fn due(&self) -> Result<Vec<Registration>, RegistryError>;
+
+    // When the soonest lease runs out, None when no instance expires
+    fn next_deadline(&self) -> Result<Option<SystemTime>, RegistryError>;
+
+    // Replaces every instance, with a snapshot taken on another node
+    fn replace(&self, map: ServiceMap) -> Result<(), RegistryError>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    Memory,
+    Sqlite,
+}
+
+impl FromStr for Backend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "memory" => Ok(Self::Memory),
+            "sqlite" => Ok(Self::Sqlite),
+            _ => Err(format!(
+                "unknown registry {s:?}, expected \"memory\" or \"sqlite\""
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Instances {
+    map: ServiceMap,
+    // The services of map in order, for scans
+    keys: BTreeSet<ServiceId>,
+    // The instance holding each lease
+    leases: HashMap<LeaseId, (ServiceId, InstanceId)>,
+    deadlines: Deadlines,
+}
+
+impl Instances {
+    fn insert(
+        &mut self,
+        key: ServiceId,
+        instance_id: InstanceId,
+        record: ServiceRecord,
+    ) -> Option<ServiceRecord> {
+        self.deadlines.schedule(&key, &instance_id, &record);
+        // Pinned records have no lease
+        if record.lease_id != 0 {
+            self.leases
+                .insert(record.lease_id, (key.clone(), instance_id.clone()));
+        }
+        if !self.map.contains_key(&key) {
+            self.keys.insert(key.clone());
+        }
+        let previous = self.map.entry(key).or_default().insert(instance_id, record);
+        if let Some(old) = &previous {
+            self.forget_lease(old.lease_id);
+        }
+        previous
+    }
+
+    fn remove(&mut self, key: &ServiceId, instance_id: &str) -> Option<ServiceRecord> {
+        let instances = self.map.get_mut(key)?;
+        let removed = instances.remove(instance_id)?;
+        if instances.is_empty() {
+            self.map.remove(key);
+            self.keys.remove(key);
+        }
+        self.deadlines.cancel(key, instance_id);
+        self.forget_lease(removed.lease_id);
+        Some(removed)
+    }
+
+    // Forgets every instance, along with the leases and deadlines scheduled for them
+    fn clear(&mut self) {
+        self.map.clear();
+        self.keys.clear();
+        self.leases.clear();
+        self.deadlines.clear();
+    }
+
+    // Drops a lease from the index, unless its instance still holds it
+    fn forget_lease(&mut self, lease_id: LeaseId) {
+        let Some((key, instance_id)) = self.leases.get(&lease_id) else {
+            return;
+        };
+        let held = self
+            .map
+            .get(key)
+            .and_then(|instances| instances.get(instance_id))
+            .map(|record| record.lease_id == lease_id);
+        if !matches!(held, Some(true)) {
+            self.leases.remove(&lease_id);
+        }
+    }
+}
+
+// Keeps the instances in maps, they only outlive svc-dsc through the RegistryStore. Services are
+// spread over REGISTRY_SHARDS shards, so calls about different services rarely wait on each
+// other. Leases and deadlines are indexed in each shard, so renewing and expiring don't go through
+// every instance.
+#[derive(Debug)]
+pub struct MemoryRegistry {
+    shards: Vec<RwLock<Instances>>,
+    // The shard holding each lease, for KeepAlive. Only locked after the shards it is about.
+    lease_shards: RwLock<HashMap<LeaseId, usize>>,
+}
+
+impl Default for MemoryRegistry {
+    fn default() -> Self {
+        Self {
+            shards: (0..REGISTRY_SHARDS)
+                .map(|_| RwLock::new(Instances::default()))
+                .collect(),
+            lease_shards: RwLock::new(HashMap::new()),
+        }
+    }
+}
+
+// Shards are changed a step at a time, so one left poisoned by a panic is still consistent enough
+// to keep serving rather than failing every call after it
+fn read<T>(lock: &RwLock<T>) -> RwLockReadGuard<'_, T> {
+    lock.read().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn write<T>(lock: &RwLock<T>) -> RwLockWriteGuard<'_, T> {
+    lock.write().unwrap_or_else(PoisonError::into_inner)
+}
+
+impl MemoryRegistry {
+    pub fn new(map: ServiceMap) -> MemoryRegistry {
+        let registry = Self::default();
+        // Nobody else can see it yet
+        let _ = registry.replace(map);
+        registry
+    }
+
+    fn shard_index(&self, key: &ServiceId) -> usize {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        hasher.finish() as usize % self.shards.len()
+    }
+
+    fn shard(&self, key: &ServiceId) -> &RwLock<Instances> {
+        &self.shards[self.shard_index(key)]
+    }
+
+    // The shard holding the lease, if any
+    fn lease_shard(&self, lease_id: LeaseId) -> Option<&RwLock<Instances>> {
+        let index = *read(&self.lease_shards).get(&lease_id)?;
+        Some(&self.shards[index])
+    }
+
+    // Points the leases at the shard when it holds them, and drops those it no longer holds.
+    // Called with the shard still locked, so the index never runs behind it.
+    fn index_leases(&self, index: usize, shard: &Instances, lease_ids: &[LeaseId]) {
+        let mut lease_shards = write(&self.lease_shards);
+        for &lease_id in lease_ids {
+            if shard.leases.contains_key(&lease_id) {
+                lease_shards.insert(lease_id, index);
+            } else if lease_shards.get(&lease_id) == Some(&index) {
+                lease_shards.remove(&lease_id);
+            }
+        }
+    }
+}
+
+impl Registry for MemoryRegistry {
+    fn insert(
+        &self,
+        key: ServiceId,
+        instance_id: InstanceId,
+        record: ServiceRecord,
+    ) -> Result<Option<ServiceRecord>, RegistryError> {
+        let index = self.shard_index(&key);
+        let mut shard = write(&self.shards[index]);
+        let lease_id = record.lease_id;
+        let previous = shard.insert(key, instance_id, record);
+        let old_lease_id = previous
+            .as_ref()
+            .map(|old| old.lease_id)
+            .unwrap_or(lease_id);
+        self.index_leases(index, &shard, &[lease_id, old_lease_id]);
+        Ok(previous)
+    }
+
+    fn remove(
+        &self,
+        key: &ServiceId,
+        instance_id: &str,
+    ) -> Result<Option<ServiceRecord>, RegistryError> {
+        let index = self.shard_index(key);
+        let mut shard = write(&self.shards[index]);
+        let removed = shard.remove(key, instance_id);
+        if let Some(record) = &removed {
+            self.index_leases(index, &shard, &[record.lease_id]);
+        }
+        Ok(removed)
+    }
+
+    fn get(&self, key: &ServiceId) -> Result<ServiceInstances, RegistryError> {
+        let shard = read(self.shard(key));
+        Ok(shard.map.get(key).cloned().unwrap_or_default())
+    }
+
+    fn list(&self) -> Result<ServiceMap, RegistryError> {
+        // Every shard is held, in order, until all of them are copied, so exports and snapshots
+        // see the registry as it was at one point in time
+        let shards = self.shards.iter().map(read).collect::<Vec<_>>();
+        let mut map = ServiceMap::new();
+        for shard in &shards {
+            map.extend(
+                shard
+                    .map
+                    .iter()
+                    .map(|(key, instances)| (key.clone(), instances.clone())),
+            );
+        }
+        Ok(map)
+    }
+
+    fn scan(
+        &self,
+        namespace: &str,
+        group: Option<&str>,
+        after: Option<(&str, &str)>,
+        limit: usize,
+    ) -> Result<Vec<ScannedService>, RegistryError> {
+        let first = (namespace, group.unwrap_or_default(), "");
+        let start = match after {
+            Some((group, name)) if (namespace, group, name) >= first => {
+                Bound::Excluded(service_id(namespace, group, name))
+            }
+            _ => Bound::Included(service_id(first.0, first.1, first.2)),
+        };
+        let in_scan = |key: &&ServiceId| {
+            key.0 == namespace && (group.is_none() || group == Some(key.1.as_str()))
+        };
+
+        // The first `limit` of each shard are enough to find the first `limit` of them all
+        let shards = self.shards.iter().map(read).collect::<Vec<_>>();
+        let mut keys = shards
+            .iter()
+            .flat_map(|shard| {
+                shard
+                    .keys
+                    .range((start.clone(), Bound::Unbounded))
+                    .take_while(in_scan)
+                    .take(limit)
+            })
+            .collect::<Vec<_>>();
+        keys.sort();
+        keys.truncate(limit);
+        let services = keys
+            .into_iter()
+            .map(|key| {
+                let instances = shards[self.shard_index(key)].map[key].clone();
+                (key.clone(), instances)
+            })
+            .collect();
+        Ok(services)
+    }
+
+    fn find_lease(&self, lease_id: LeaseId) -> Result<Option<Registration>, RegistryError> {
+        let Some(shard) = self.lease_shard(lease_id) else {
+            return Ok(None);
+        };
+        let shard = read(shard);
+        let found = shard.leases.get(&lease_id).and_then(|(key, instance_id)| {
+            let record = shard.map.get(key)?.get(instance_id)?;
+            Some((key.clone(), instance_id.clone(), record.clone()))
+        });
+        Ok(found)
+    }
+
+    fn renew(&self, lease_id: LeaseId) -> Result<Option<u64>, RegistryError> {
+        let Some(shard) = self.lease_shard(lease_id) else {
+            return Ok(None);
+        };
+        let mut shard = write(shard);
+        let Instances {
+            map,
+            leases,
+            deadlines,
+            ..
+        } = &mut *shard;
+        // Gone if the instance was removed since its shard was found
+        let Some((key, instance_id)) = leases.get(&lease_id) else {
+            return Ok(None);
+        };
+        let Some(record) = map
+            .get_mut(key)
+            .and_then(|instances| instances.get_mut(instance_id))
+        else {
+            return Ok(None);
+        };
+        if record.is_expired() {
+            return Ok(None);
+        }
+        record.last_updated = SystemTime::now();
+        deadlines.schedule(key, instance_id, record);
+        Ok(Some(record.ttl))
+    }
+
+    fn expire(&self) -> Result<Vec<Registration>, RegistryError> {
+        let now = SystemTime::now();
+        let mut expired = Vec::new();
+        for (index, shard) in self.shards.iter().enumerate() {
+            let mut shard = write(shard);
+            let mut lease_ids = Vec::new();
+            for (key, instance_id) in shard.deadlines.pop_due(now) {
+                if let Some(record) = shard.remove(&key, &instance_id) {
+                    lease_ids.push(record.lease_id);
+                    expired.push((key, instance_id, record));
+                }
+            }
+            if !lease_ids.is_empty() {
+                self.index_leases(index, &shard, &lease_ids);
+            }
+        }
+        Ok(expired)
+    }
+
+    fn due(&self) -> Result<Vec<Registration>, RegistryError> {
+        let now = SystemTime::now();
+        let mut due = Vec::new();
+        for shard in &self.shards {
+            let shard = read(shard);
+            for (key, instance_id) in shard.deadlines.due(now) {
+                if let Some(record) = shard.map.get(&key).and_then(|map| map.get(&instance_id)) {
+                    let record = record.clone();
+                    due.push((key, instance_id, record));
+                }
+            }
+        }
+        Ok(due)
+    }
+
+    fn next_deadline(&self) -> Result<Option<SystemTime>, RegistryError> {
+        let next = self
+            .shards
+            .iter()
+            .filter_map(|shard| read(shard).deadlines.next())
+            .min();
+        Ok(next)
+    }
+
+    fn replace(&self, map: ServiceMap) -> Result<(), RegistryError> {
+        // Every shard is held until all of them are replaced, so lookups never see half of each
+        let mut shards = self.shards.iter().map(write).collect::<Vec<_>>();
+        for shard in &mut shards {
+            shard.clear();
+        }
+        for (key, instances) in map {
+            let shard = &mut shards[self.shard_index(&key)];
+            for (instance_id, record) in instances {
+                shard.insert(key.clone(), instance_id, record);
+            }
+        }
+        let mut lease_shards = write(&self.lease_shards);
+        *lease_shards = shards
+            .iter()
+            .enumerate()
+            .flat_map(|(index, shard)| shard.leases.keys().map(move |&lease_id| (lease_id, index)))
+            .collect();
+        Ok(())
+    }
+}
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS instances (
+    namespace TEXT NOT NULL,
+    service_group TEXT NOT NULL,
+    name TEXT NOT NULL,
+    instance_id TEXT NOT NULL,
+    lease_id INTEGER NOT NULL,
+    record TEXT NOT NULL,
+    -- When the lease runs out, NULL for pinned instances
+    deadline_millis INTEGER,
+    PRIMARY KEY (namespace, service_group, name, instance_id)
+);
+CREATE INDEX IF NOT EXISTS instances_by_lease ON instances (lease_id);
+CREATE TABLE IF NOT EXISTS history (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    at_millis INTEGER NOT NULL,
+    event TEXT NOT NULL,
+    namespace TEXT NOT NULL,
+    service_group TEXT NOT NULL,
+    name TEXT NOT NULL,
+    instance_id TEXT NOT NULL,
+    record TEXT NOT NULL
+);
+";
+
+const COLUMNS: &str = "namespace, service_group, name, instance_id, record";
+
+// Keeps the instances in a SQLite database, so they survive restarts on their own. Every
+// registration, deregistration and expiry is also appended to its history table, e.g.
+//   sqlite3 svc-dsc.db "SELECT * FROM history WHERE service_group = 'math' ORDER BY id"
+#[derive(Debug)]
+pub struct SqliteRegistry {
+    conn: Mutex<Connection>,
+}
+
+impl SqliteRegistry {
+    pub fn open(path: impl AsRef<Path>) -> Result<SqliteRegistry, RegistryError> {
+        let mut conn = Connection::open(path)?;
+        conn.execute_batch(SCHEMA)?;
+        add_deadlines(&mut conn)?;
+        conn.execute_batch(
+            "CREATE INDEX IF NOT EXISTS instances_by_deadline ON instances (deadline_millis)",
+        )?;
+
+        Ok(Self {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    // Runs statements on the connection. Registry calls are synchronous, so they can't be moved
+    // to spawn_blocking, but a worker of a multi-threaded runtime hands its other tasks over to
+    // another thread before it waits on the disk.
+    fn with_conn<T>(
+        &self,
+        f: impl FnOnce(&mut Connection) -> Result<T, RegistryError>,
+    ) -> Result<T, RegistryError> {
+        let run = || {
+            // A panic mid-statement rolls its transaction back, the connection is still usable
+            let mut conn = self.conn.lock().unwrap_or_else(PoisonError::into_inner);
+            f(&mut conn)
+        };
+        match Handle::try_current() {
+            Ok(handle) if handle.runtime_flavor() == RuntimeFlavor::MultiThread => {
+                task::block_in_place(run)
+            }
+            _ => run(),
+        }
+    }
+}
+
+fn service_id(namespace: &str, group: &str, name: &str) -> ServiceId {
+    (namespace.to_string(), group.to_string(), name.to_string())
+}
+
+fn to_millis(time: SystemTime) -> i64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as i64
+}
+
+// Databases from before instances had a deadline column get one, worked out from their records
+fn add_deadlines(conn: &mut Connection) -> Result<(), RegistryError> {
+    let has_deadlines: bool = conn.query_row(
+        "SELECT COUNT(*) > 0 FROM pragma_table_info('instances') WHERE name = 'deadline_millis'",
+        [],
+        |row| row.get(0),
+    )?;
+    if has_deadlines {
+        return Ok(());
+    }
+
+    println!("svc_dsc::registry: adding deadlines to the instances table");
+    let tx = conn.transaction()?;
+    tx.execute(
+        "ALTER TABLE instances ADD COLUMN deadline_millis INTEGER",
+        [],
+    )?;
+    let sql = format!("SELECT {COLUMNS} FROM instances");
+    for (key, instance_id, record) in query(&tx, &sql, [])? {
+        write_instance(&tx, &key, &instance_id, &record)?;
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+fn read_row(row: &Row) -> rusqlite::Result<(ServiceId, InstanceId, String)> {
+    Ok((
+        (row.get(0)?, row.get(1)?, row.get(2)?),
+        row.get(3)?,
+        row.get(4)?,
+    ))
+}
+
+// Runs a query over COLUMNS and decodes its rows
+fn query(
+    tx: &Connection,
+    sql: &str,
+    params: impl rusqlite::Params,
+) -> Result<Vec<Registration>, RegistryError> {
+    let mut stmt = tx.prepare(sql)?;
+    let rows = stmt.query_map(params, read_row)?;
+    let mut registrations = Vec::new();
+    for row in rows {
+        let (key, instance_id, record) = row?;
+        registrations.push((key, instance_id, serde_json::from_str(&record)?));
+    }
+    Ok(registrations)
+}
+
+fn write_instance(
+    tx: &Transaction,
+    (namespace, group, name): &ServiceId,
+    instance_id: &str,
+    record: &ServiceRecord,
+) -> Result<(), RegistryError> {
+    tx.execute(
+        "INSERT OR REPLACE INTO instances
+         (namespace, service_group, name, instance_id, lease_id, record, deadline_millis)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        params![
+            namespace,
+            group,
+            name,
+            instance_id,
+            // SQLite integers are signed, lease ids keep their bits
+            record.lease_id as i64,
+            serde_json::to_string(record)?,
+            record.deadline().map(to_millis)
+        ],
+    )?;
+    Ok(())
+}
+
+fn delete_instance(
+    tx: &Transaction,
+    (namespace, group, name): &ServiceId,
+    instance_id: &str,
+) -> Result<(), RegistryError> {
+    tx.execute(
+        "DELETE FROM instances
+         WHERE namespace = ?1 AND service_group = ?2 AND name = ?3 AND instance_id = ?4",
+        params![namespace, group, name, instance_id],
+    )?;
+    Ok(())
+}
+
+fn record_history(
+    tx: &Transaction,
+    event: &str,
+    (namespace, group, name): &ServiceId,
+    instance_id: &str,
+    record: &ServiceRecord,
+) -> Result<(), RegistryError> {
+    let at_millis = to_millis(SystemTime::now());
+    tx.execute(
+        "INSERT INTO history
+         (at_millis, event, namespace, service_group, name, instance_id, record)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        params![
+            at_millis,
+            event,
+            namespace,
+            group,
+            name,
+            instance_id,
+            serde_json::to_string(record)?
+        ],
+    )?;
+    Ok(())
+}
+
+fn find_instance(
+    tx: &Connection,
+    (namespace, group, name): &ServiceId,
+    instance_id: &str,
+) -> Result<Option<ServiceRecord>, RegistryError> {
+    let record: Option<String> = tx
+        .query_row(
+            "SELECT record FROM instances
+             WHERE namespace = ?1 AND service_group = ?2 AND name = ?3 AND instance_id = ?4",
+            params![namespace, group, name, instance_id],
+            |row| row.get(0),
+        )
+        .optional()?;
+    match record {
+        Some(record) => Ok(Some(serde_json::from_str(&record)?)),
+        None => Ok(None),
+    }
+}
+
+impl Registry for SqliteRegistry {
+    fn insert(
+        &self,
+        key: ServiceId,
+        instance_id: InstanceId,
+        record: ServiceRecord,
+    ) -> Result<Option<ServiceRecord>, RegistryError> {
+        self.with_conn(|conn| {
+            let tx = conn.transaction()?;
+            let previous = find_instance(&tx, &key, &instance_id)?;
+            write_instance(&tx, &key, &instance_id, &record)?;
+            // Heartbeats re-register the same instance, they aren't history
+            if !matches!(&previous, Some(old) if old.same_instance(&record)) {
+                record_history(&tx, "registered", &key, &instance_id, &record)?;
+            }
+            tx.commit()?;
+            Ok(previous)
+        })
+    }
+
+    fn remove(
+        &self,
+        key: &ServiceId,
+        instance_id: &str,
+    ) -> Result<Option<ServiceRecord>, RegistryError> {
+        self.with_conn(|conn| {
+            let tx = conn.transaction()?;
+            let removed = find_instance(&tx, key, instance_id)?;
+            if let Some(record) = &removed {
+                delete_instance(&tx, key, instance_id)?;
+                record_history(&tx, "deregistered", key, instance_id, record)?;
+            }
+            tx.commit()?;
+            Ok(removed)
+        })
+    }
+
+    fn get(&self, key: &ServiceId) -> Result<ServiceInstances, RegistryError> {
+        let (namespace, group, name) = key;
+        let sql = format!(
+            "SELECT {COLUMNS} FROM instances
+             WHERE namespace = ?1 AND service_group = ?2 AND name = ?3"
+        );
+        let registrations =
+            self.with_conn(|conn| query(conn, &sql, params![namespace, group, name]))?;
+        Ok(registrations
+            .into_iter()
+            .map(|(_, instance_id, record)| (instance_id, record))
+            .collect())
+    }
+
+    fn list(&self) -> Result<ServiceMap, RegistryError> {
+        let sql = format!("SELECT {COLUMNS} FROM instances");
+        let mut map = ServiceMap::new();
+        for (key, instance_id, record) in self.with_conn(|conn| query(conn, &sql, []))? {
+            map.entry(key).or_default().insert(instance_id, record);
+        }
+        Ok(map)
+    }
+
+    fn scan(
+        &self,
+        namespace: &str,
+        group: Option<&str>,
+        after: Option<(&str, &str)>,
+        limit: usize,
+    ) -> Result<Vec<ScannedService>, RegistryError> {
+        // The services of the page are picked off the primary key, then only their rows are read
+        let limit = limit as i64;
+        let mut services = String::from(
+            "SELECT DISTINCT namespace, service_group, name FROM instances WHERE namespace = ?",
+        );
+        let mut args: Vec<&dyn ToSql> = vec![&namespace];
+        if let Some(group) = &group {
+            services += " AND service_group = ?";
+            args.push(group);
+        }
+        if let Some((group, name)) = &after {
+            services += " AND (service_group, name) > (?, ?)";
+            args.extend([group as &dyn ToSql, name]);
+        }
+        services += " ORDER BY service_group, name LIMIT ?";
+        args.push(&limit);
+        let sql = format!(
+            "SELECT {COLUMNS} FROM instances WHERE (namespace, service_group, name) IN ({services})"
+        );
+
+        let mut map = BTreeMap::<ServiceId, ServiceInstances>::new();
+        let found = self.with_conn(|conn| query(conn, &sql, params_from_iter(args)))?;
+        for (key, instance_id, record) in found {
+            map.entry(key).or_default().insert(instance_id, record);
+        }
+        Ok(map.into_iter().collect())
+    }
+
+    fn find_lease(&self, lease_id: LeaseId) -> Result<Option<Registration>, RegistryError> {
+        let sql = format!("SELECT {COLUMNS} FROM instances WHERE lease_id = ?1 LIMIT 1");
+        let mut found = self.with_conn(|conn| query(conn, &sql, [lease_id as i64]))?;
+        Ok(found.pop())
+    }
+
+    fn renew(&self, lease_id: LeaseId) -> Result<Option<u64>, RegistryError> {
+        self.with_conn(|conn| {
+            let tx = conn.transaction()?;
+            let sql = format!("SELECT {COLUMNS} FROM instances WHERE lease_id = ?1");
+            let live = query(&tx, &sql, [lease_id as i64])?
+                .into_iter()
+                .find(|(_, _, record)| !record.is_expired());
+            let Some((key, instance_id, mut record)) = live else {
+                return Ok(None);
+            };
+            record.last_updated = SystemTime::now();
+            write_instance(&tx, &key, &instance_id, &record)?;
+            tx.commit()?;
+            Ok(Some(record.ttl))
+        })
+    }
+
+    fn expire(&self) -> Result<Vec<Registration>, RegistryError> {
+        self.with_conn(|conn| {
+            let tx = conn.transaction()?;
+            let sql = format!("SELECT {COLUMNS} FROM instances WHERE deadline_millis <= ?1");
+            let expired = query(&tx, &sql, [to_millis(SystemTime::now())])?;
+            for (key, instance_id, record) in &expired {
+                delete_instance(&tx, key, instance_id)?;
+                record_history(&tx, "expired", key, instance_id, record)?;
+            }
+            tx.commit()?;
+            Ok(expired)
+        })
+    }
+
+    fn due(&self) -> Result<Vec<Registration>, RegistryError> {
+        let sql = format!("SELECT {COLUMNS} FROM instances WHERE deadline_millis <= ?1");
+        self.with_conn(|conn| query(conn, &sql, [to_millis(SystemTime::now())]))
+    }
+
+    fn next_deadline(&self) -> Result<Option<SystemTime>, RegistryError> {
+        let deadline: Option<i64> = self.with_conn(|conn| {
+            let sql = "SELECT MIN(deadline_millis) FROM instances";
+            Ok(conn.query_row(sql, [], |row| row.get(0))?)
+        })?;
+        Ok(deadline.map(|millis| UNIX_EPOCH + Duration::from_millis(millis.max(0) as u64)))
+    }
+
+    fn replace(&self, map: ServiceMap) -> Result<(), RegistryError> {
+        self.with_conn(|conn| {
+            let tx = conn.transaction()?;
+            tx.execute("DELETE FROM instances", [])?;
+            for (key, instances) in &map {
+                for (instance_id, record) in instances {
+                    write_instance(&tx, key, instance_id, record)?;
+                }
+            }
+            tx.commit()?;
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{write, MemoryRegistry, Registry, SqliteRegistry};
+    use crate::svc_dsc::server::serdict::ServiceRecord;
+
+    use std::time::{Duration, SystemTime};
+
+    fn record(lease_id: u64, age: Duration) -> ServiceRecord {
+        ServiceRecord {
+            addr: ("[::1]".to_string(), 50052),
+            last_updated: SystemTime::now() - age,
+            lease_id,
+            ttl: 5000,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn it_keeps_instances_in_sqlite() {
+        let path = std::env::temp_dir().join(format!("svc-dsc-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let key = ("default".to_string(), "math".to_string(), "add".to_string());
+
+        let registry = SqliteRegistry::open(&path).unwrap();
+        for (instance_id, lease_id, age) in [("a", u64::MAX, 0), ("b", 2, 60)] {
+            let record = record(lease_id, Duration::from_secs(age));
+            let replaced = registry.insert(key.clone(), instance_id.into(), record);
+            assert!(replaced.unwrap().is_none());
+        }
+        assert_eq!(registry.renew(u64::MAX).unwrap(), Some(5000));
+        assert_eq!(registry.renew(2).unwrap(), None);
+        drop(registry);
+
+        // Reopened as after a restart
+        let registry = SqliteRegistry::open(&path).unwrap();
+        assert_eq!(registry.get(&key).unwrap().len(), 2);
+        let expired = registry.expire().unwrap();
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].1, "b");
+        let next = registry.next_deadline().unwrap().unwrap();
+        assert!(next > SystemTime::now() + Duration::from_secs(4));
+        let (_, instance_id, _) = registry.find_lease(u64::MAX).unwrap().unwrap();
+        assert_eq!(instance_id, "a");
+
+        let history: i64 = registry
+            .with_conn(|conn| {
+                Ok(conn.query_row("SELECT COUNT(*) FROM history", [], |row| row.get(0))?)
+            })
+            .unwrap();
+        assert_eq!(history, 3);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn it_scans_services_in_order() {
+        let path = std::env::temp_dir().join(format!("svc-dsc-scan-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let registries: [Box<dyn Registry>; 2] = [
+            Box::new(MemoryRegistry::default()),
+            Box::new(SqliteRegistry::open(&path).unwrap()),
+        ];
+        for registry in &registries {
+            for (namespace, group, name) in [
+                ("default", "math", "sub"),
+                ("default", "math", "add"),
+                ("default", "calc", "calc"),
+                ("staging", "math", "mul"),
+            ] {
+                let key = (namespace.into(), group.into(), name.into());
+                for instance_id in ["a", "b"] {
+                    registry
+                        .insert(key.clone(), instance_id.into(), record(0, Duration::ZERO))
+                        .unwrap();
+                }
+            }
+
+            let names = |group, after, limit| {
+                let services = registry.scan("default", group, after, limit).unwrap();
+                assert!(services.iter().all(|(_, instances)| instances.len() == 2));
+                services
+                    .into_iter()
+                    .map(|(key, _)| key.2)
+                    .collect::<Vec<_>>()
+            };
+            assert_eq!(names(None, None, 2), ["calc", "add"]);
+            assert_eq!(names(None, Some(("math", "add")), 2), ["sub"]);
+            assert_eq!(names(Some("math"), None, 5), ["add", "sub"]);
+            // A cursor before the group starts at the group
+            assert_eq!(
+                names(Some("math"), Some(("calc", "calc")), 5),
+                ["add", "sub"]
+            );
+            assert!(names(Some("calc"), Some(("math", "add")), 5).is_empty());
+        }
+        drop(registries);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn it_keeps_serving_after_a_panic() {
+        let key = ("default".to_string(), "math".to_string(), "add".to_string());
+        let registry = MemoryRegistry::default();
+        let record = record(1, Duration::ZERO);
+        registry
+            .insert(key.clone(), "a".into(), record.clone())
+            .unwrap();
+
+        std::thread::scope(|scope| {
+            let panicked = scope.spawn(|| {
+                let _shard = write(registry.shard(&key));
+                panic!("poisons the shard");
+            });
+            assert!(panicked.join().is_err());
+        });
+        assert!(registry.shard(&key).is_poisoned());
+
+        registry.insert(key.clone(), "b".into(), record).unwrap();
+        assert_eq!(registry.get(&key).unwrap().len(), 2);
+        assert_eq!(registry.renew(1).unwrap(), Some(5000));
+
+        // The lease follows its instance to another service's shard, and goes with it
+        let moved = ("default".to_string(), "math".to_string(), "sub".to_string());
+        let lease = ServiceRecord {
+            lease_id: 2,
+            ..Default::default()
+        };
+        registry
+            .insert(key.clone(), "c".into(), lease.clone())
+            .unwrap();
+        registry.remove(&key, "c").unwrap();
+        registry.insert(moved.clone(), "c".into(), lease).unwrap();
+        assert_eq!(registry.find_lease(2).unwrap().unwrap().0, moved);
+        registry.remove(&moved, "c").unwrap();
+        assert_eq!(registry.renew(2).unwrap(), None);
+        assert_eq!(registry.list().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn it_forgets_deadlines_when_replaced() {
+        let key = ("default".to_string(), "math".to_string(), "add".to_string());
+        let registry = MemoryRegistry::default();
+        registry
+            .insert(key.clone(), "a".into(), record(1, Duration::from_secs(60)))
+            .unwrap();
+        assert_eq!(registry.due().unwrap().len(), 1);
+
+        // A snapshot from another node, where the instance was renewed
+        let renewed = record(1, Duration::ZERO);
+        let deadline = renewed.deadline();
+        registry
+            .replace([(key.clone(), [("a".to_string(), renewed)].into())].into())
+            .unwrap();
+        assert!(registry.due().unwrap().is_empty());
+        assert_eq!(registry.next_deadline().unwrap(), deadline);
+
+        registry.replace(Default::default()).unwrap();
+        assert_eq!(registry.next_deadline().unwrap(), None);
+        assert!(registry.expire().unwrap().is_empty());
+    }
+}
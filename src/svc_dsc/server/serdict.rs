@@ -2,181 +2,2622 @@ pub mod gen {
     tonic::include_proto!("serdict");
 }
 
-use tonic::{Request, Response, Status};
+use futures::Stream;
+use prost::Message;
+use rand::Rng;
+use semver::{Version, VersionReq};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::sync::{broadcast, mpsc, Notify};
+use tokio_stream::{
+    wrappers::{errors::BroadcastStreamRecvError, BroadcastStream, ReceiverStream},
+    StreamExt,
+};
+use tonic::{Request, Response, Status, Streaming};
+
+use crate::svc_dsc::{
+    gen::{
+        import_registry_request::Mode, kv_event, registry_change, registry_command::Command,
+        ser_dict_server::SerDict, service_event::Kind, AcquireLockRequest, AcquireLockResponse,
+        CampaignRequest, DeregisterServiceRequest, ExpireLeasesRequest, ExpireLocksRequest,
+        ExportRegistryRequest, ExportRegistryResponse, FindServicesRequest, GetServiceRequest,
+        GetServiceResponse, GrantLeaseRequest, GrantLeaseResponse, ImportRegistryRequest,
+        ImportRegistryResponse, InstanceState as ProtoInstanceState, KeepAliveRequest,
+        KeepAliveResponse, KvDeleteRequest, KvDeleteResponse, KvEntry, KvEvent, KvGetRequest,
+        KvListRequest, KvListResponse, KvPutRequest, KvPutResponse, KvWatchRequest, LeaderEvent,
+        ListEventsRequest, ListEventsResponse, ListNamespacesResponse,
+        ListServiceByGroupNameRequest, ListServiceRequest, ListServiceResponse, Lock,
+        ObserveRequest, RegisterServiceRequest, RegisterServiceResponse, RegistryCommand,
+        RegistrySummary, ReleaseLockRequest, ServiceEvent, ServiceInstance,
+        SetInstanceStateRequest, SetInstanceStateResponse, WatchServicesRequest,
+    },
+    DEFAULT_NAMESPACE, EVENT_HISTORY_SIZE, HEARTBEAT_INTERVAL, LEASE_TTL, MAX_NAMESPACE_LEN,
+    NAMESPACE_HEADER, WATCH_BUFFER_SIZE,
+};
 
-use crate::svc_dsc::gen::{
-    ser_dict_server::SerDict, DeregisterServiceRequest, GetServiceRequest, GetServiceResponse,
-    ListServiceByGroupNameRequest, ListServiceResponse, RegisterServiceRequest,
-    RegisterServiceResponse,
+use super::{
+    acl::{self, authorize, Acl, AuthError, Permission, Principal},
+    audit::{AuditKind, AuditLog},
+    export::{self, RegistryDump},
+    federation::Federation,
+    health::HealthChecks,
+    kv::{key_group, KvKey, KvMap, KvRecord},
+    lock::{LockEvent, LockRecord, Session},
+    page::{Page, ServiceFilter},
+    raft::{RaftError, RaftNode, ReadConsistency, StateMachine},
+    registry::{Registration, Registry, RegistryError},
+    store::{decode_map, encode_map, RegistryStore, WalEntry},
 };
 
 use std::{
-    collections::HashMap,
-    sync::{Arc, RwLock},
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    pin::Pin,
+    sync::{Arc, Mutex, MutexGuard, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard},
+    time::{Duration, SystemTime},
 };
 
-type ServiceId = (String, String);
+// (namespace, group, name)
+pub type ServiceId = (String, String, String);
+pub type InstanceId = String;
+pub type LeaseId = u64;
 type ServiceAddr = (String, u32);
 
-#[derive(Debug)]
+// Records from before leases expired after a heartbeat interval
+fn default_ttl() -> u64 {
+    HEARTBEAT_INTERVAL
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ServiceRecord {
     pub addr: ServiceAddr,
-    pub last_updated: std::time::Instant,
+    // Wall-clock time, so it still means something after a restart
+    pub last_updated: SystemTime,
+    #[serde(default)]
+    pub metadata: BTreeMap<String, String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    // A semantic version, or empty when the instance didn't give one
+    #[serde(default)]
+    pub version: String,
+    // 0 for records from before leases
+    #[serde(default)]
+    pub lease_id: LeaseId,
+    // in millis
+    #[serde(default = "default_ttl")]
+    pub ttl: u64,
+    #[serde(default)]
+    pub state: InstanceState,
+    // From the static entries file rather than registered, never expires
+    #[serde(default)]
+    pub pinned: bool,
+}
+
+// Only active instances are handed out by lookups
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum InstanceState {
+    #[default]
+    Active,
+    Draining,
+    Maintenance,
+}
+
+impl InstanceState {
+    fn from_proto(state: ProtoInstanceState) -> InstanceState {
+        match state {
+            ProtoInstanceState::Active => InstanceState::Active,
+            ProtoInstanceState::Draining => InstanceState::Draining,
+            ProtoInstanceState::Maintenance => InstanceState::Maintenance,
+        }
+    }
+
+    fn to_proto(self) -> ProtoInstanceState {
+        match self {
+            InstanceState::Active => ProtoInstanceState::Active,
+            InstanceState::Draining => ProtoInstanceState::Draining,
+            InstanceState::Maintenance => ProtoInstanceState::Maintenance,
+        }
+    }
+}
+
+impl std::fmt::Display for InstanceState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let state = match self {
+            InstanceState::Active => "active",
+            InstanceState::Draining => "draining",
+            InstanceState::Maintenance => "maintenance",
+        };
+        write!(f, "{}", state)
+    }
+}
+
+// A fresh, active record with no address, labels or lease, to fill in with struct update syntax
+impl Default for ServiceRecord {
+    fn default() -> Self {
+        Self {
+            addr: (String::new(), 0),
+            last_updated: SystemTime::now(),
+            metadata: BTreeMap::new(),
+            tags: Vec::new(),
+            version: String::new(),
+            lease_id: 0,
+            ttl: LEASE_TTL,
+            state: InstanceState::Active,
+            pinned: false,
+        }
+    }
 }
 
 impl ServiceRecord {
-    fn new(addr: ServiceAddr) -> ServiceRecord {
+    fn new(request: RegisterServiceRequest) -> ServiceRecord {
         Self {
-            addr,
-            last_updated: std::time::Instant::now(),
+            addr: (request.ip, request.port),
+            metadata: request.metadata.into_iter().collect(),
+            tags: request.tags,
+            version: request.version,
+            lease_id: request.lease_id,
+            ttl: match request.ttl {
+                0 => LEASE_TTL,
+                ttl => ttl,
+            },
+            ..Default::default()
+        }
+    }
+
+    // Whether a re-registration changed anything watchers care about
+    pub fn same_instance(&self, other: &ServiceRecord) -> bool {
+        self.addr == other.addr
+            && self.metadata == other.metadata
+            && self.tags == other.tags
+            && self.version == other.version
+    }
+
+    // Time left before the lease runs out
+    pub fn remaining(&self) -> Duration {
+        // A clock that went backwards makes the record look fresh rather than dead
+        let age = self.last_updated.elapsed().unwrap_or(Duration::ZERO);
+        Duration::from_millis(self.ttl).saturating_sub(age)
+    }
+
+    // When the lease runs out without a renewal, None for pinned records
+    pub fn deadline(&self) -> Option<SystemTime> {
+        match self.pinned {
+            true => None,
+            false => Some(self.last_updated + Duration::from_millis(self.ttl)),
+        }
+    }
+
+    // A record is expired when its lease wasn't renewed within its TTL
+    pub fn is_expired(&self) -> bool {
+        !self.pinned && self.remaining().is_zero()
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.state == InstanceState::Active
+    }
+
+    pub fn to_instance(&self, instance_id: &str) -> ServiceInstance {
+        let (ip, port) = self.addr.to_owned();
+        ServiceInstance {
+            instance_id: instance_id.to_string(),
+            ip,
+            port,
+            metadata: self.metadata.clone().into_iter().collect(),
+            tags: self.tags.clone(),
+            version: self.version.clone(),
+            state: self.state.to_proto() as i32,
+            pinned: self.pinned,
         }
     }
 }
 
-type ServiceMap = HashMap<ServiceId, ServiceRecord>;
+// What FindServices looks for in an instance
+pub struct InstanceFilter {
+    pub tags: Vec<String>,
+    pub metadata: HashMap<String, String>,
+    pub version: Option<VersionReq>,
+}
+
+impl InstanceFilter {
+    fn from_request(request: &FindServicesRequest) -> Result<InstanceFilter, semver::Error> {
+        let version = match request.version.trim() {
+            "" => None,
+            version => Some(VersionReq::parse(version)?),
+        };
+
+        Ok(Self {
+            tags: request.tags.clone(),
+            metadata: request.metadata.clone(),
+            version,
+        })
+    }
+
+    pub fn matches(&self, record: &ServiceRecord) -> bool {
+        let has_tags = self.tags.iter().all(|tag| record.tags.contains(tag));
+        let has_metadata = self
+            .metadata
+            .iter()
+            .all(|(key, value)| record.metadata.get(key) == Some(value));
+        // Instances without a version never satisfy a version requirement
+        let has_version = match &self.version {
+            Some(req) => Version::parse(&record.version)
+                .map(|version| req.matches(&version))
+                .unwrap_or(false),
+            None => true,
+        };
+
+        has_tags && has_metadata && has_version
+    }
+}
+
+pub fn service_event(
+    kind: Kind,
+    (namespace, group, name): &ServiceId,
+    instance_id: &str,
+    record: &ServiceRecord,
+) -> ServiceEvent {
+    let mut event = ServiceEvent {
+        namespace: namespace.clone(),
+        group: group.clone(),
+        name: name.clone(),
+        instance: Some(record.to_instance(instance_id)),
+        ..Default::default()
+    };
+    event.set_kind(kind);
+    event
+}
+
+fn key_event(kind: kv_event::Kind, namespace: &str, key: &str, record: &KvRecord) -> KvEvent {
+    let mut event = KvEvent {
+        entry: Some(record.to_entry(key)),
+        namespace: namespace.to_string(),
+        ..Default::default()
+    };
+    event.set_kind(kind);
+    event
+}
+
+// Every replica of a service, keyed by its instance id
+pub type ServiceInstances = HashMap<InstanceId, ServiceRecord>;
+
+pub type ServiceMap = HashMap<ServiceId, ServiceInstances>;
+
+// Builds the response for a service out of its live instances that pass `keep`, sorted by
+// instance id. Draining and maintenance instances are left out unless `include_inactive`.
+// Returns None when no instance is left.
+fn service_response(
+    key: &ServiceId,
+    instances: &ServiceInstances,
+    include_inactive: bool,
+    keep: impl Fn(&str, &ServiceRecord) -> bool,
+) -> Option<GetServiceResponse> {
+    let mut instances = instances
+        .iter()
+        .filter(|(instance_id, record)| {
+            !record.is_expired()
+                && (include_inactive || record.is_active())
+                && keep(instance_id, record)
+        })
+        .map(|(instance_id, record)| record.to_instance(instance_id))
+        .collect::<Vec<_>>();
+    if instances.is_empty() {
+        return None;
+    }
+    instances.sort_by(|a, b| a.instance_id.cmp(&b.instance_id));
+
+    let (namespace, group, name) = key.to_owned();
+    let ServiceInstance { ip, port, .. } = instances
+        .iter()
+        .find(|instance| instance.state() == ProtoInstanceState::Active)
+        .unwrap_or(&instances[0])
+        .clone();
+
+    Some(GetServiceResponse {
+        namespace,
+        group,
+        name,
+        ip,
+        port,
+        instances,
+        // Set by the caller, who knows which datacenter it answers for
+        datacenter: String::new(),
+    })
+}
+
+#[derive(Error, Debug)]
+#[error("invalid namespace {0:?}, expected lowercase letters, digits and dashes in between")]
+pub struct InvalidNamespace(String);
+
+impl From<InvalidNamespace> for Status {
+    fn from(e: InvalidNamespace) -> Self {
+        Status::invalid_argument(e.to_string())
+    }
+}
+
+// The namespace a request is about: its own field, then the svc-dsc-namespace header, then the
+// default namespace
+fn resolve_namespace<T>(request: &Request<T>, namespace: &str) -> Result<String, InvalidNamespace> {
+    let namespace = match (namespace, request.metadata().get(NAMESPACE_HEADER)) {
+        ("", Some(header)) => match header.to_str() {
+            Ok("") => DEFAULT_NAMESPACE,
+            Ok(namespace) => namespace,
+            Err(_) => return Err(InvalidNamespace(format!("{:?}", header))),
+        },
+        ("", None) => DEFAULT_NAMESPACE,
+        (namespace, _) => namespace,
+    };
+    if !valid_namespace(namespace) {
+        return Err(InvalidNamespace(namespace.to_string()));
+    }
+    Ok(namespace.to_string())
+}
+
+// Namespaces are DNS labels, svc.local names end with them
+fn valid_namespace(namespace: &str) -> bool {
+    let bytes = namespace.as_bytes();
+    (1..=MAX_NAMESPACE_LEN).contains(&bytes.len())
+        && bytes
+            .iter()
+            .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || *b == b'-')
+        && bytes[0] != b'-'
+        && bytes[bytes.len() - 1] != b'-'
+}
+
+// Where a request came from, empty when unknown
+fn caller_addr<T>(request: &Request<T>) -> String {
+    match request.remote_addr() {
+        Some(addr) => addr.to_string(),
+        None => String::new(),
+    }
+}
+
+fn persist_failed(e: RegistryError) -> Status {
+    Status::internal(format!("Failed to persist registry change: {e}"))
+}
+
+// For registry errors on the way to replicating a command
+fn raft_rejected(e: RegistryError) -> RaftError {
+    RaftError::Rejected(persist_failed(e).message().to_string())
+}
+
+fn read_failed(e: RegistryError) -> Status {
+    Status::internal(format!("Failed to read registry: {e}"))
+}
+
+type WatchServicesStream = Pin<Box<dyn Stream<Item = Result<ServiceEvent, Status>> + Send>>;
+type KeepAliveStream = Pin<Box<dyn Stream<Item = Result<KeepAliveResponse, Status>> + Send>>;
+type KvWatchStream = Pin<Box<dyn Stream<Item = Result<KvEvent, Status>> + Send>>;
+type ObserveStream = Pin<Box<dyn Stream<Item = Result<LeaderEvent, Status>> + Send>>;
+
+// The svc-dsc nodes this one replicates registry changes with
+#[derive(Clone)]
+pub struct Cluster {
+    pub node: Arc<RaftNode>,
+    pub read_consistency: ReadConsistency,
+}
+
+impl Cluster {
+    async fn propose(&self, command: Command) -> Result<Vec<u8>, RaftError> {
+        let command = RegistryCommand {
+            command: Some(command),
+        };
+        self.node.propose(command.encode_to_vec()).await
+    }
+
+    async fn read_barrier(&self) -> Result<(), RaftError> {
+        match self.read_consistency {
+            ReadConsistency::Stale => Ok(()),
+            ReadConsistency::Linearizable => self.node.read_barrier().await,
+        }
+    }
+}
 
-#[derive(Debug, Default)]
+#[derive(Clone)]
 pub struct SerDictImpl {
-    pub service_registry: Arc<RwLock<ServiceMap>>,
+    pub registry: Arc<dyn Registry>,
+    // Registry changes pushed to every WatchServices stream
+    pub events: broadcast::Sender<ServiceEvent>,
+    // Configuration entries, replicated and logged like registrations
+    pub kv: Arc<RwLock<KvMap>>,
+    // Key/value changes pushed to every KvWatch stream
+    pub kv_events: broadcast::Sender<KvEvent>,
+    // Locks changing hands, for campaigns waiting on them and observers
+    pub lock_events: broadcast::Sender<LockEvent>,
+    // Registrations, deregistrations and expiries applied by this node, for ListEvents
+    pub audit: Arc<AuditLog>,
+    // Every change is logged here before it is applied to the registry. Held until it is, so
+    // snapshots see every logged change.
+    pub store: Arc<Mutex<dyn RegistryStore>>,
+    // Changes go through the cluster's leader when svc-dsc is replicated
+    pub cluster: Option<Cluster>,
+    // Instances failing their health checks are hidden from lookups
+    pub health: Option<HealthChecks>,
+    // Who may read and change which groups, everyone may do anything without one
+    pub acl: Option<Arc<Acl>>,
+    // The datacenter of this svc-dsc, and those it looks services up in when it has none live
+    pub federation: Option<Federation>,
+    // Wakes the reaper up when a registration may have brought the next deadline forward
+    pub expiry: Arc<Notify>,
 }
 
 impl SerDictImpl {
-    pub fn new(service_registry: Arc<RwLock<ServiceMap>>) -> SerDictImpl {
-        Self { service_registry }
+    pub fn new(
+        registry: Arc<dyn Registry>,
+        events: broadcast::Sender<ServiceEvent>,
+        store: Arc<Mutex<dyn RegistryStore>>,
+    ) -> SerDictImpl {
+        let (kv_events, _) = broadcast::channel(WATCH_BUFFER_SIZE);
+        let (lock_events, _) = broadcast::channel(WATCH_BUFFER_SIZE);
+        Self {
+            registry,
+            events,
+            kv: Arc::new(RwLock::new(KvMap::default())),
+            kv_events,
+            lock_events,
+            audit: Arc::new(AuditLog::new(EVENT_HISTORY_SIZE)),
+            store,
+            cluster: None,
+            health: None,
+            acl: None,
+            federation: None,
+            expiry: Arc::new(Notify::new()),
+        }
     }
-}
 
-#[tonic::async_trait]
-impl SerDict for SerDictImpl {
-    async fn register_service(
-        &self,
-        request: Request<RegisterServiceRequest>,
-    ) -> Result<Response<RegisterServiceResponse>, Status> {
-        println!("serdict::register_service: Got a request: {:?}", request);
+    // Starts from the key/value store loaded along with the registry
+    pub fn with_kv(mut self, kv: Arc<RwLock<KvMap>>) -> SerDictImpl {
+        self.kv = kv;
+        self
+    }
 
-        let request = request.into_inner();
+    // Keeps the events from before a restart, see AuditLog::open
+    pub fn with_audit(mut self, audit: AuditLog) -> SerDictImpl {
+        self.audit = Arc::new(audit);
+        self
+    }
 
-        let mut services_map = self.service_registry.write().unwrap();
+    pub fn with_cluster(mut self, cluster: Cluster) -> SerDictImpl {
+        self.cluster = Some(cluster);
+        self
+    }
 
-        let key = (request.group, request.name);
-        services_map.insert(key.clone(), ServiceRecord::new((request.ip, request.port)));
+    pub fn with_health_checks(mut self, health: HealthChecks) -> SerDictImpl {
+        self.health = Some(health);
+        self
+    }
 
-        if let Some(record) = services_map.get(&key) {
-            let (ip, port) = record.addr.to_owned();
-            let res = RegisterServiceResponse { ip, port };
+    pub fn with_acl(mut self, acl: Acl) -> SerDictImpl {
+        self.acl = Some(Arc::new(acl));
+        self
+    }
 
-            return Ok(Response::new(res));
+    pub fn with_federation(mut self, federation: Federation) -> SerDictImpl {
+        self.federation = Some(federation);
+        self
+    }
+
+    // Empty without a federation
+    pub fn datacenter(&self) -> &str {
+        match &self.federation {
+            Some(federation) => &federation.datacenter,
+            None => "",
         }
+    }
+
+    // The page of the services of the namespace that pass the filter and that the principal may
+    // read, for ListService and ListServiceByGroupName
+    fn list_page(
+        &self,
+        namespace: &str,
+        principal: Option<&Arc<Principal>>,
+        filter: &ServiceFilter,
+        page: &Page,
+    ) -> Result<ListServiceResponse, RegistryError> {
+        let scan = |after: Option<(&str, &str)>, limit| {
+            self.registry
+                .scan(namespace, filter.group.as_deref(), after, limit)
+        };
+        let (services, next_page_token) = page.fill(scan, |key, instances| {
+            if !filter.matches(key) || acl::check(principal, &key.1, Permission::Read).is_err() {
+                return None;
+            }
+            let service =
+                service_response(key, instances, filter.include_inactive, |instance_id, _| {
+                    filter.keeps(self.is_healthy(key, instance_id))
+                })?;
+            Some(GetServiceResponse {
+                datacenter: self.datacenter().to_string(),
+                ..service
+            })
+        })?;
 
-        return Err(Status::internal("Failed to register service"));
+        Ok(ListServiceResponse {
+            services,
+            next_page_token,
+        })
     }
 
-    async fn deregister_service(
+    pub fn is_healthy(&self, key: &ServiceId, instance_id: &str) -> bool {
+        match &self.health {
+            Some(health) => health.is_healthy(key, instance_id),
+            None => true,
+        }
+    }
+
+    // Live and healthy instances of namespace/group/name, as read by this node
+    pub fn live_instances(
         &self,
-        request: Request<DeregisterServiceRequest>,
-    ) -> Result<Response<()>, Status> {
-        println!("serdict::deregister_service: Got a request: {:?}", request);
+        namespace: &str,
+        group: &str,
+        name: &str,
+    ) -> Result<Vec<ServiceInstance>, RegistryError> {
+        let key = (namespace.to_string(), group.to_string(), name.to_string());
+        let instances = self.registry.get(&key)?;
+        let res = service_response(&key, &instances, false, |instance_id, _| {
+            self.is_healthy(&key, instance_id)
+        });
+        Ok(res.map(|res| res.instances).unwrap_or_default())
+    }
 
-        let request = request.into_inner();
+    async fn read_barrier(&self) -> Result<(), RaftError> {
+        match &self.cluster {
+            Some(cluster) => cluster.read_barrier().await,
+            None => Ok(()),
+        }
+    }
 
-        {
-            let mut services_map = self.service_registry.write().unwrap();
+    // Key/values and locks are changed a step at a time, so a panic in one handler leaves them
+    // consistent enough for the next rather than failing every call after it
+    pub fn read_kv(&self) -> RwLockReadGuard<'_, KvMap> {
+        self.kv.read().unwrap_or_else(PoisonError::into_inner)
+    }
 
-            let key = (request.group, request.name);
-            services_map.remove(&key);
-        };
+    pub fn write_kv(&self) -> RwLockWriteGuard<'_, KvMap> {
+        self.kv.write().unwrap_or_else(PoisonError::into_inner)
+    }
 
-        Ok(Response::new(()))
+    // A panic while logging a change leaves the store as usable as any failed write does
+    fn lock_store(&self) -> MutexGuard<'_, dyn RegistryStore + 'static> {
+        self.store.lock().unwrap_or_else(PoisonError::into_inner)
     }
 
-    async fn get_service(
+    fn publish(&self, event: ServiceEvent) {
+        // Only fails when nobody is watching
+        let _ = self.events.send(event);
+    }
+
+    fn apply_register(
         &self,
-        request: Request<GetServiceRequest>,
-    ) -> Result<Response<GetServiceResponse>, Status> {
-        println!("serdict::get_service: Got a request: {:?}", request);
+        request: RegisterServiceRequest,
+    ) -> Result<RegisterServiceResponse, RegistryError> {
+        let instance_id = request.instance_id.clone();
+        let caller = request.caller.clone();
+        let key = (
+            request.namespace.clone(),
+            request.group.clone(),
+            request.name.clone(),
+        );
+        let mut record = ServiceRecord::new(request);
 
-        let request = request.into_inner();
-        let GetServiceRequest { group, name } = request;
+        let mut store = self.lock_store();
+        // Heartbeats re-register, which mustn't put a draining instance back in rotation, nor
+        // take the lease its KeepAlive stream renews away from it
+        if let Some(current) = self.registry.get(&key)?.get(&instance_id) {
+            record.state = current.state;
+            if current.lease_id != 0 {
+                record.lease_id = current.lease_id;
+            }
+        }
+        store.append(&WalEntry::Register {
+            namespace: key.0.clone(),
+            group: key.1.clone(),
+            name: key.2.clone(),
+            instance_id: instance_id.clone(),
+            record: record.clone(),
+        })?;
+        let previous = self
+            .registry
+            .insert(key.clone(), instance_id.clone(), record.clone())?;
+        drop(store);
+        self.expiry.notify_one();
+
+        let (kind, reason) = match &previous {
+            None => (AuditKind::Registered, "new instance"),
+            Some(old) if old.same_instance(&record) => (AuditKind::Reregistered, "unchanged"),
+            Some(_) => (
+                AuditKind::Reregistered,
+                "address, metadata, tags or version changed",
+            ),
+        };
+        self.audit.record(
+            kind,
+            &key,
+            &instance_id,
+            &record,
+            &caller,
+            reason.to_string(),
+        );
+        // Heartbeats re-register the same instance, only announce new or changed ones
+        if !matches!(&previous, Some(old) if old.same_instance(&record)) {
+            self.publish(service_event(Kind::Registered, &key, &instance_id, &record));
+        }
 
-        let services_map = self.service_registry.read().unwrap();
+        let (ip, port) = record.addr;
+        Ok(RegisterServiceResponse {
+            ip,
+            port,
+            instance_id,
+            lease_id: record.lease_id,
+            ttl: record.ttl,
+        })
+    }
 
-        if group.is_empty() || name.is_empty() {
-            return Err(Status::invalid_argument(
-                "group and name parameter cannot be empty",
-            ));
+    // Renewals aren't logged nor replicated, only the leader keeps them. A restarted svc-dsc or a
+    // new leader only knows when an instance last registered, and gives it a full TTL from its
+    // election before expiring it.
+    fn renew_lease(&self, request: KeepAliveRequest) -> Result<KeepAliveResponse, RegistryError> {
+        let ttl = match self.registry.renew(request.lease_id)? {
+            Some(ttl) => ttl,
+            // Not an instance's, maybe one holding locks
+            None => self.write_kv().locks.renew(request.lease_id),
+        };
+
+        Ok(KeepAliveResponse {
+            lease_id: request.lease_id,
+            ttl,
+        })
+    }
+
+    fn lease_group(&self, lease_id: LeaseId) -> Result<Option<String>, RegistryError> {
+        let found = self.registry.find_lease(lease_id)?;
+        Ok(found.map(|((_, group, _), _, _)| group))
+    }
+
+    async fn keep_alive_lease(&self, lease_id: LeaseId) -> Result<KeepAliveResponse, RaftError> {
+        let request = KeepAliveRequest { lease_id };
+        match &self.cluster {
+            None => self.renew_lease(request).map_err(raft_rejected),
+            Some(cluster) => {
+                let res = cluster.node.call(request.encode_to_vec()).await?;
+                KeepAliveResponse::decode(res.as_slice())
+                    .map_err(|e| RaftError::Rejected(e.to_string()))
+            }
+        }
+    }
+
+    // Expires the instance holding the lease once its TTL runs out, unless it is renewed by then.
+    // Takes every other expired instance along.
+    async fn expire_lease(&self, lease_id: LeaseId) -> Result<(), RaftError> {
+        if self.read_kv().locks.sessions.contains_key(&lease_id) {
+            return self.expire_session(lease_id).await;
+        }
+        loop {
+            let found = self.registry.find_lease(lease_id).map_err(raft_rejected)?;
+            let remaining = match found {
+                Some((_, _, record)) => record.remaining(),
+                None => return Ok(()),
+            };
+            if remaining.is_zero() {
+                break;
+            }
+            tokio::time::sleep(remaining).await;
+        }
+
+        for ((namespace, group, name), instance_id, record) in self.expire_instances().await? {
+            if record.lease_id == lease_id {
+                println!(
+                    "serdict::expire_lease: lease {} of {}/{}/{}/{} ran out",
+                    lease_id, namespace, group, name, instance_id
+                );
+            }
+        }
+        Ok(())
+    }
+
+    // Removes the instances whose lease ran out and announces them, returns them. In a cluster
+    // only the leader finds them, and replicates their expiry so every node removes the same ones.
+    pub async fn expire_instances(&self) -> Result<Vec<Registration>, RaftError> {
+        let cluster = match &self.cluster {
+            None => {
+                let expired = self.registry.expire().map_err(raft_rejected)?;
+                for (key, instance_id, record) in &expired {
+                    self.announce_expired(key, instance_id, record);
+                }
+                return Ok(expired);
+            }
+            Some(cluster) => cluster,
+        };
+        let Some(leading_for) = cluster.node.leading_for() else {
+            return Ok(Vec::new());
+        };
+
+        let mut due = self.registry.due().map_err(raft_rejected)?;
+        // Renewals sent to the previous leader never reached this one
+        due.retain(|(_, _, record)| leading_for >= Duration::from_millis(record.ttl));
+        if !due.is_empty() {
+            let lease_ids = due.iter().map(|(_, _, record)| record.lease_id).collect();
+            let request = ExpireLeasesRequest { lease_ids };
+            cluster.propose(Command::ExpireLeases(request)).await?;
+        }
+        Ok(due)
+    }
+
+    fn apply_expire_leases(&self, request: ExpireLeasesRequest) -> Result<(), RegistryError> {
+        for lease_id in request.lease_ids {
+            let mut store = self.lock_store();
+            // Renewed leases are expired all the same, the leader saw them run out first
+            let Some((key, instance_id, _)) = self.registry.find_lease(lease_id)? else {
+                continue;
+            };
+            store.append(&WalEntry::Deregister {
+                namespace: key.0.clone(),
+                group: key.1.clone(),
+                name: key.2.clone(),
+                instance_id: instance_id.clone(),
+            })?;
+            let removed = self.registry.remove(&key, &instance_id)?;
+            drop(store);
+            if let Some(record) = removed {
+                self.announce_expired(&key, &instance_id, &record);
+            }
         }
+        Ok(())
+    }
 
-        let key = (group.clone(), name.clone());
-        if let Some(record) = services_map.get(&key) {
-            let (group, name) = key.to_owned();
-            let (ip, port) = record.addr.to_owned();
+    fn announce_expired(&self, key: &ServiceId, instance_id: &str, record: &ServiceRecord) {
+        let reason = format!("not renewed within its {}ms TTL", record.ttl);
+        self.audit
+            .record(AuditKind::Expired, key, instance_id, record, "", reason);
+        self.publish(service_event(Kind::Expired, key, instance_id, record));
+    }
 
-            let res = GetServiceResponse {
-                group,
-                name,
-                ip,
-                port,
+    // Expires instances as their leases run out, forever. Sleeps until the soonest deadline, or
+    // until a registration may have brought it forward, and never longer than a heartbeat
+    // interval so a missed wake-up doesn't keep dead instances around.
+    pub async fn reap(self) {
+        let interval = Duration::from_millis(HEARTBEAT_INTERVAL);
+        loop {
+            let follower = matches!(&self.cluster, Some(cluster) if !cluster.node.is_leader());
+            let wait = match self.registry.next_deadline() {
+                // Waits for the leader to expire its instances, or to be elected itself
+                _ if follower => interval,
+                Ok(Some(deadline)) => deadline
+                    .duration_since(SystemTime::now())
+                    .unwrap_or(Duration::ZERO)
+                    .min(interval),
+                Ok(None) => interval,
+                Err(e) => {
+                    println!("serdict::reap: failed to read deadlines: {}", e);
+                    interval
+                }
             };
+            tokio::select! {
+                _ = tokio::time::sleep(wait) => {}
+                _ = self.expiry.notified() => {}
+            }
 
-            return Ok(Response::new(res));
+            match self.expire_instances().await {
+                Ok(expired) if expired.is_empty() => {}
+                Ok(expired) => {
+                    let expired = expired
+                        .iter()
+                        .map(|(key, instance_id, _)| {
+                            format!("{}/{}/{}/{}", key.0, key.1, key.2, instance_id)
+                        })
+                        .collect::<Vec<_>>();
+                    println!("serdict::reap: bye bye dead services: {:?}", expired);
+                }
+                Err(e) => {
+                    println!("serdict::reap: failed to expire instances: {}", e);
+                    // The deadlines are still due, don't retry right away
+                    tokio::time::sleep(interval).await;
+                }
+            }
         }
+    }
 
-        let msg = format!("Service {group}:{name} is not registered.");
-        return Err(Status::not_found(msg));
+    // Makes `entries` the pinned instances and announces what changed. Every node reads its own
+    // static entries file, so these changes are neither logged nor replicated.
+    pub fn pin(&self, entries: ServiceMap) -> Result<(), RegistryError> {
+        let pinned = export::filter_pinned(self.registry.list()?, true);
+        for (kind, key, instance_id, record) in export::diff(&pinned, &entries, true) {
+            let (audit_kind, event_kind, reason) = match kind {
+                registry_change::Kind::Removed => {
+                    self.registry.remove(&key, &instance_id)?;
+                    (
+                        AuditKind::Deregistered,
+                        Kind::Deregistered,
+                        "removed from the static entries file",
+                    )
+                }
+                _ => {
+                    self.registry
+                        .insert(key.clone(), instance_id.clone(), record.clone())?;
+                    (AuditKind::Registered, Kind::Registered, "static entry")
+                }
+            };
+            println!(
+                "serdict::pin: {} {}/{}/{}/{}",
+                reason, key.0, key.1, key.2, instance_id
+            );
+            self.audit.record(
+                audit_kind,
+                &key,
+                &instance_id,
+                &record,
+                "",
+                reason.to_string(),
+            );
+            self.publish(service_event(event_kind, &key, &instance_id, &record));
+        }
+        self.expiry.notify_one();
+        Ok(())
     }
 
-    async fn list_service(
+    fn apply_deregister(&self, request: DeregisterServiceRequest) -> Result<(), RegistryError> {
+        let key = (request.namespace, request.group, request.name);
+
+        let mut store = self.lock_store();
+        if self.registry.get(&key)?.contains_key(&request.instance_id) {
+            store.append(&WalEntry::Deregister {
+                namespace: key.0.clone(),
+                group: key.1.clone(),
+                name: key.2.clone(),
+                instance_id: request.instance_id.clone(),
+            })?;
+        }
+        let removed = self.registry.remove(&key, &request.instance_id)?;
+        drop(store);
+
+        if let Some(record) = removed {
+            self.audit.record(
+                AuditKind::Deregistered,
+                &key,
+                &request.instance_id,
+                &record,
+                &request.caller,
+                "deregistered".to_string(),
+            );
+            self.publish(service_event(
+                Kind::Deregistered,
+                &key,
+                &request.instance_id,
+                &record,
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn apply_set_instance_state(
         &self,
-        request: Request<()>,
-    ) -> Result<Response<ListServiceResponse>, Status> {
-        println!("serdict::list_service: Got a request: {:?}", request);
+        request: SetInstanceStateRequest,
+    ) -> Result<SetInstanceStateResponse, RegistryError> {
+        let state = InstanceState::from_proto(request.state());
+        let key = (request.namespace, request.group, request.name);
 
-        let services_map = self.service_registry.read().unwrap();
+        let mut store = self.lock_store();
+        let Some(mut record) = self.registry.get(&key)?.remove(&request.instance_id) else {
+            return Ok(SetInstanceStateResponse { instance: None });
+        };
+        let previous = record.state;
+        if previous != state {
+            record.state = state;
+            store.append(&WalEntry::Register {
+                namespace: key.0.clone(),
+                group: key.1.clone(),
+                name: key.2.clone(),
+                instance_id: request.instance_id.clone(),
+                record: record.clone(),
+            })?;
+            self.registry
+                .insert(key.clone(), request.instance_id.clone(), record.clone())?;
+        }
+        drop(store);
 
-        let res = ListServiceResponse {
-            services: services_map
-                .iter()
-                .map(|(key, record)| {
-                    let (group, name) = key.to_owned();
-                    let (ip, port) = record.addr.to_owned();
+        if previous != state {
+            self.audit.record(
+                AuditKind::StateChanged,
+                &key,
+                &request.instance_id,
+                &record,
+                &request.caller,
+                format!("{} to {}", previous, state),
+            );
+            self.publish(service_event(
+                Kind::StateChanged,
+                &key,
+                &request.instance_id,
+                &record,
+            ));
+        }
 
-                    GetServiceResponse {
-                        group,
-                        name,
-                        ip,
-                        port,
-                    }
+        Ok(SetInstanceStateResponse {
+            instance: Some(record.to_instance(&request.instance_id)),
+        })
+    }
+
+    // Applies the changes the node that took the request worked out and authorized, rather than
+    // working them out again from a registry that may have changed since
+    fn apply_import_registry(
+        &self,
+        request: ImportRegistryRequest,
+    ) -> Result<ImportRegistryResponse, RegistryError> {
+        let changes = export::decode_changes(&request.changes)?;
+
+        let mut store = self.lock_store();
+        let mut applied = Vec::new();
+        for (kind, key, instance_id, record) in changes {
+            let (namespace, group, name) = key.clone();
+            if kind == registry_change::Kind::Removed {
+                // Removed since, there is nothing left to remove
+                if !self.registry.get(&key)?.contains_key(&instance_id) {
+                    continue;
+                }
+                store.append(&WalEntry::Deregister {
+                    namespace,
+                    group,
+                    name,
+                    instance_id: instance_id.clone(),
+                })?;
+                self.registry.remove(&key, &instance_id)?;
+            } else {
+                store.append(&WalEntry::Register {
+                    namespace,
+                    group,
+                    name,
+                    instance_id: instance_id.clone(),
+                    record: record.clone(),
+                })?;
+                self.registry
+                    .insert(key.clone(), instance_id.clone(), record.clone())?;
+            }
+            applied.push((kind, key, instance_id, record));
+        }
+        drop(store);
+        self.expiry.notify_one();
+
+        for (kind, key, instance_id, record) in &applied {
+            let (audit_kind, event_kind, reason) = match kind {
+                registry_change::Kind::Added => {
+                    (AuditKind::Registered, Kind::Registered, "imported")
+                }
+                registry_change::Kind::Updated => {
+                    (AuditKind::Reregistered, Kind::Registered, "imported")
+                }
+                registry_change::Kind::Removed => (
+                    AuditKind::Deregistered,
+                    Kind::Deregistered,
+                    "missing from a replacing import",
+                ),
+            };
+            self.audit.record(
+                audit_kind,
+                key,
+                instance_id,
+                record,
+                &request.caller,
+                reason.to_string(),
+            );
+            self.publish(service_event(event_kind, key, instance_id, record));
+        }
+
+        Ok(ImportRegistryResponse {
+            changes: applied.iter().map(export::to_change).collect(),
+            applied: true,
+        })
+    }
+
+    fn apply_kv_put(&self, request: KvPutRequest) -> Result<KvPutResponse, RegistryError> {
+        let KvPutRequest {
+            key,
+            value,
+            precondition,
+            namespace,
+        } = request;
+        let mut store = self.lock_store();
+        let mut kv = self.write_kv();
+
+        let record = match kv.prepare_put(&namespace, &key, value, precondition.as_ref()) {
+            Ok(record) => record,
+            Err(current) => {
+                return Ok(KvPutResponse {
+                    succeeded: false,
+                    entry: current.map(|record| record.to_entry(&key)),
+                    revision: kv.revision,
                 })
-                .collect::<Vec<GetServiceResponse>>(),
+            }
         };
+        store.append(&WalEntry::KvPut {
+            namespace: namespace.clone(),
+            key: key.clone(),
+            record: record.clone(),
+        })?;
+        // Only fails when nobody is watching
+        let _ = self
+            .kv_events
+            .send(key_event(kv_event::Kind::Put, &namespace, &key, &record));
 
-        return Ok(Response::new(res));
+        let entry = record.to_entry(&key);
+        kv.insert(namespace, key, record);
+
+        Ok(KvPutResponse {
+            succeeded: true,
+            entry: Some(entry),
+            revision: kv.revision,
+        })
     }
 
-    async fn list_service_by_group_name(
+    fn apply_kv_delete(&self, request: KvDeleteRequest) -> Result<KvDeleteResponse, RegistryError> {
+        let KvDeleteRequest {
+            key,
+            precondition,
+            namespace,
+        } = request;
+        let mut store = self.lock_store();
+        let mut kv = self.write_kv();
+
+        let deleted = match kv.prepare_delete(&namespace, &key, precondition.as_ref()) {
+            Ok(deleted) => deleted,
+            Err(current) => {
+                return Ok(KvDeleteResponse {
+                    succeeded: false,
+                    entry: current.map(|record| record.to_entry(&key)),
+                    revision: kv.revision,
+                })
+            }
+        };
+        store.append(&WalEntry::KvDelete {
+            namespace: namespace.clone(),
+            key: key.clone(),
+            revision: deleted.mod_revision,
+        })?;
+        let _ = self.kv_events.send(key_event(
+            kv_event::Kind::Delete,
+            &namespace,
+            &key,
+            &deleted,
+        ));
+
+        let entry = deleted.to_entry(&key);
+        kv.remove(namespace, key, deleted.mod_revision);
+
+        Ok(KvDeleteResponse {
+            succeeded: true,
+            entry: Some(entry),
+            revision: kv.revision,
+        })
+    }
+
+    fn publish_lock(&self, event: LockEvent) {
+        // Only fails when nobody is waiting or observing
+        let _ = self.lock_events.send(event);
+    }
+
+    fn apply_grant_lease(
         &self,
-        request: Request<ListServiceByGroupNameRequest>,
-    ) -> Result<Response<ListServiceResponse>, Status> {
-        println!(
-            "serdict::list_service_by_group_name: Got a request: {:?}",
-            request
-        );
+        request: GrantLeaseRequest,
+    ) -> Result<GrantLeaseResponse, RegistryError> {
+        let ttl = match request.ttl {
+            0 => LEASE_TTL,
+            ttl => ttl,
+        };
+        let session = Session::new(ttl, request.owner, request.namespace);
+        let ttl = session.ttl;
 
-        let request = request.into_inner();
-        if request.group.is_empty() {
-            return Err(Status::invalid_argument("group parameter cannot be empty"));
+        let mut store = self.lock_store();
+        let mut kv = self.write_kv();
+        store.append(&WalEntry::Lease {
+            lease_id: request.lease_id,
+            session: session.clone(),
+        })?;
+        kv.locks.sessions.insert(request.lease_id, session);
+
+        Ok(GrantLeaseResponse {
+            lease_id: request.lease_id,
+            ttl,
+        })
+    }
+
+    fn apply_acquire_lock(
+        &self,
+        request: AcquireLockRequest,
+    ) -> Result<AcquireLockResponse, RegistryError> {
+        let AcquireLockRequest {
+            name,
+            lease_id,
+            holder,
+            namespace,
+        } = request;
+        let mut store = self.lock_store();
+        let mut kv = self.write_kv();
+        if !kv.locks.is_live(lease_id) {
+            return Ok(AcquireLockResponse {
+                acquired: false,
+                lock: None,
+            });
         }
 
-        let res = self.list_service(Request::new(())).await?;
-        let mut res = res.into_inner();
+        let key = (namespace, name);
+        if let Some(current) = kv.locks.holder(&key) {
+            return Ok(AcquireLockResponse {
+                acquired: current.lease_id == lease_id,
+                lock: Some(current.to_lock(&key.1)),
+            });
+        }
 
-        // Filter by group
-        res.services = res
-            .services
-            .into_iter()
-            .filter(|service| service.group.eq(&request.group))
-            .collect::<Vec<_>>();
+        let lock = LockRecord {
+            lease_id,
+            holder,
+            token: kv.revision + 1,
+        };
+        store.append(&WalEntry::Lock {
+            namespace: key.0.clone(),
+            name: key.1.clone(),
+            lock: lock.clone(),
+        })?;
+        self.publish_lock(LockEvent {
+            key: key.clone(),
+            holder: Some(lock.clone()),
+        });
 
-        return Ok(Response::new(res));
+        let res = AcquireLockResponse {
+            acquired: true,
+            lock: Some(lock.to_lock(&key.1)),
+        };
+        kv.revision = lock.token;
+        kv.locks.locks.insert(key, lock);
+
+        Ok(res)
+    }
+
+    fn apply_release_lock(&self, request: ReleaseLockRequest) -> Result<(), RegistryError> {
+        let mut store = self.lock_store();
+        let mut kv = self.write_kv();
+
+        let key = (request.namespace, request.name);
+        let held =
+            matches!(kv.locks.locks.get(&key), Some(lock) if lock.lease_id == request.lease_id);
+        if held {
+            store.append(&WalEntry::Unlock {
+                namespace: key.0.clone(),
+                name: key.1.clone(),
+            })?;
+            kv.locks.locks.remove(&key);
+            self.publish_lock(LockEvent { key, holder: None });
+        }
+
+        Ok(())
+    }
+
+    async fn acquire_lock_with(
+        &self,
+        request: AcquireLockRequest,
+    ) -> Result<AcquireLockResponse, RaftError> {
+        // Frees the locks whose lease ran out since the last sweep
+        self.expire_sessions().await?;
+        match &self.cluster {
+            None => self.apply_acquire_lock(request).map_err(raft_rejected),
+            Some(cluster) => {
+                let res = cluster.propose(Command::AcquireLock(request)).await?;
+                AcquireLockResponse::decode(res.as_slice())
+                    .map_err(|e| RaftError::Rejected(e.to_string()))
+            }
+        }
+    }
+
+    // Leases for locks are only renewed and used by the principal they were granted to, in the
+    // namespace they were granted in. Those not applied here yet are left to apply, which finds
+    // them gone.
+    fn check_session(
+        &self,
+        principal: Option<&Arc<Principal>>,
+        namespace: &str,
+        lease_id: LeaseId,
+    ) -> Result<(), AuthError> {
+        let kv = self.read_kv();
+        let Some(session) = kv.locks.sessions.get(&lease_id) else {
+            return Ok(());
+        };
+        let foreign = matches!(principal, Some(principal) if principal.name != session.owner);
+        if foreign || session.namespace != namespace {
+            return Err(AuthError::ForeignLease(lease_id));
+        }
+        Ok(())
+    }
+
+    // Forgets the lock leases that ran out and releases their locks, returns the leases. In a
+    // cluster only the leader finds them, and replicates their expiry so every node releases the
+    // same locks.
+    async fn expire_sessions(&self) -> Result<Vec<LeaseId>, RaftError> {
+        let mut due = self.read_kv().locks.due();
+        if let Some(cluster) = &self.cluster {
+            let Some(leading_for) = cluster.node.leading_for() else {
+                return Ok(Vec::new());
+            };
+            // Renewals sent to the previous leader never reached this one
+            let kv = self.read_kv();
+            due.retain(|lease_id| {
+                matches!(kv.locks.sessions.get(lease_id),
+                    Some(session) if leading_for >= Duration::from_millis(session.ttl))
+            });
+        }
+        if due.is_empty() {
+            return Ok(due);
+        }
+        let request = ExpireLocksRequest {
+            lease_ids: due.clone(),
+        };
+        match &self.cluster {
+            None => self.apply_expire_locks(request).map_err(raft_rejected)?,
+            Some(cluster) => {
+                cluster.propose(Command::ExpireLocks(request)).await?;
+            }
+        }
+        Ok(due)
+    }
+
+    fn apply_expire_locks(&self, request: ExpireLocksRequest) -> Result<(), RegistryError> {
+        let mut store = self.lock_store();
+        let mut kv = self.write_kv();
+        // Renewed leases are expired all the same, the leader saw them run out first
+        for key in kv.locks.expire(&request.lease_ids) {
+            println!(
+                "serdict::expire_locks: lease of {}/{} ran out",
+                key.0, key.1
+            );
+            store.append(&WalEntry::Unlock {
+                namespace: key.0.clone(),
+                name: key.1.clone(),
+            })?;
+            self.publish_lock(LockEvent { key, holder: None });
+        }
+        Ok(())
+    }
+
+    // Releases the locks of a lease once its TTL runs out, unless it is renewed by then
+    async fn expire_session(&self, lease_id: LeaseId) -> Result<(), RaftError> {
+        loop {
+            let remaining = self.read_kv().locks.remaining(lease_id);
+            if remaining.is_zero() {
+                break;
+            }
+            tokio::time::sleep(remaining).await;
+        }
+        self.expire_sessions().await.map(|_| ())
+    }
+
+    // Sweeps the locks of leases that ran out without their KeepAlive stream dropping, forever
+    pub async fn expire_locks(self, interval: Duration) {
+        loop {
+            tokio::time::sleep(interval).await;
+            if let Err(e) = self.expire_sessions().await {
+                println!("serdict::expire_locks: failed to expire leases: {}", e);
+            }
+        }
+    }
+
+    // Waits for the lock to change hands, or for its holder's lease to run out
+    async fn lock_changed(
+        &self,
+        key: &KvKey,
+        holder: Option<&Lock>,
+        events: &mut broadcast::Receiver<LockEvent>,
+    ) {
+        let remaining = holder.map(|holder| self.read_kv().locks.remaining(holder.lease_id));
+        let expired = async {
+            match remaining {
+                // Released once the leader expires the lease, its sweep is the latest that happens
+                Some(remaining) if remaining.is_zero() => {
+                    tokio::time::sleep(Duration::from_millis(HEARTBEAT_INTERVAL)).await
+                }
+                Some(remaining) => tokio::time::sleep(remaining).await,
+                // A free lock has no lease to run out, only takers to wait for
+                None => std::future::pending().await,
+            }
+        };
+        let changed = async {
+            loop {
+                match events.recv().await {
+                    Ok(event) if event.key == *key => break,
+                    Ok(_) => {}
+                    // Missed events may have been about this lock
+                    Err(_) => break,
+                }
+            }
+        };
+        tokio::select! {
+            _ = expired => {}
+            _ = changed => {}
+        }
+    }
+}
+
+// Commands are applied by every node of the cluster, in the order the leader committed them.
+// Raft applies them from under its state lock, so applying never waits on other tasks.
+impl StateMachine for SerDictImpl {
+    fn apply(&self, command: &[u8]) -> Result<Vec<u8>, String> {
+        let command = RegistryCommand::decode(command)
+            .map_err(|e| e.to_string())?
+            .command;
+        let result = match command {
+            Some(Command::Register(request)) => {
+                self.apply_register(request).map(|res| res.encode_to_vec())
+            }
+            Some(Command::Deregister(request)) => {
+                self.apply_deregister(request).map(|_| Vec::new())
+            }
+            Some(Command::SetInstanceState(request)) => self
+                .apply_set_instance_state(request)
+                .map(|res| res.encode_to_vec()),
+            Some(Command::ImportRegistry(request)) => self
+                .apply_import_registry(request)
+                .map(|res| res.encode_to_vec()),
+            Some(Command::ExpireLeases(request)) => {
+                self.apply_expire_leases(request).map(|_| Vec::new())
+            }
+            Some(Command::KvPut(request)) => {
+                self.apply_kv_put(request).map(|res| res.encode_to_vec())
+            }
+            Some(Command::KvDelete(request)) => {
+                self.apply_kv_delete(request).map(|res| res.encode_to_vec())
+            }
+            Some(Command::GrantLease(request)) => self
+                .apply_grant_lease(request)
+                .map(|res| res.encode_to_vec()),
+            Some(Command::AcquireLock(request)) => self
+                .apply_acquire_lock(request)
+                .map(|res| res.encode_to_vec()),
+            Some(Command::ReleaseLock(request)) => {
+                self.apply_release_lock(request).map(|_| Vec::new())
+            }
+            Some(Command::ExpireLocks(request)) => {
+                self.apply_expire_locks(request).map(|_| Vec::new())
+            }
+            None => Ok(Vec::new()),
+        };
+
+        result.map_err(|e| persist_failed(e).message().to_string())
+    }
+
+    fn snapshot(&self) -> Result<Vec<u8>, String> {
+        let services_map = self.registry.list().map_err(|e| e.to_string())?;
+        let kv = self.read_kv();
+        encode_map(&services_map, &kv).map_err(|e| e.to_string())
+    }
+
+    // Lease renewals, the leader keeps them without replicating them
+    fn call(&self, request: &[u8]) -> Result<Vec<u8>, String> {
+        let request = KeepAliveRequest::decode(request).map_err(|e| e.to_string())?;
+        self.renew_lease(request)
+            .map(|res| res.encode_to_vec())
+            .map_err(|e| e.to_string())
+    }
+
+    fn restore(&self, snapshot: &[u8]) -> Result<(), String> {
+        let (map, kv_map) = decode_map(snapshot).map_err(|e| e.to_string())?;
+        self.replace_state(map, kv_map)
+    }
+
+    fn clear(&self) -> Result<(), String> {
+        self.replace_state(ServiceMap::new(), KvMap::default())
+    }
+}
+
+impl SerDictImpl {
+    // Swaps in the state raft restored, dropping whatever the registry held before
+    fn replace_state(&self, map: ServiceMap, kv_map: KvMap) -> Result<(), String> {
+        let mut store = self.lock_store();
+        store
+            .snapshot(&map, &kv_map)
+            .map_err(|e| format!("failed to persist snapshot: {}", e))?;
+        self.registry
+            .replace(map)
+            .map_err(|e| format!("failed to replace registry: {}", e))?;
+        *self.write_kv() = kv_map;
+        drop(store);
+        self.expiry.notify_one();
+        Ok(())
+    }
+}
+
+#[tonic::async_trait]
+impl SerDict for SerDictImpl {
+    async fn register_service(
+        &self,
+        request: Request<RegisterServiceRequest>,
+    ) -> Result<Response<RegisterServiceResponse>, Status> {
+        println!("serdict::register_service: Got a request: {:?}", request);
+
+        authorize(&request, &request.get_ref().group, Permission::Register)?;
+        let namespace = resolve_namespace(&request, &request.get_ref().namespace)?;
+        let caller = caller_addr(&request);
+        let mut request = request.into_inner();
+        request.namespace = namespace;
+        request.caller = caller;
+        if request.instance_id.is_empty() {
+            request.instance_id = format!("{}:{}", request.ip, request.port);
+        }
+        // Drawn before replicating, so every node gives the instance the same lease
+        request.lease_id = rand::thread_rng().gen_range(1..=LeaseId::MAX);
+        if !request.version.is_empty() {
+            if let Err(e) = Version::parse(&request.version) {
+                let msg = format!(
+                    "version {:?} is not a semantic version: {e}",
+                    request.version
+                );
+                return Err(Status::invalid_argument(msg));
+            }
+        }
+
+        let res = match &self.cluster {
+            None => self.apply_register(request).map_err(persist_failed)?,
+            Some(cluster) => {
+                let res = cluster.propose(Command::Register(request)).await?;
+                RegisterServiceResponse::decode(res.as_slice())
+                    .map_err(|e| Status::internal(format!("Failed to register service: {e}")))?
+            }
+        };
+
+        return Ok(Response::new(res));
+    }
+
+    async fn deregister_service(
+        &self,
+        request: Request<DeregisterServiceRequest>,
+    ) -> Result<Response<()>, Status> {
+        println!("serdict::deregister_service: Got a request: {:?}", request);
+
+        authorize(&request, &request.get_ref().group, Permission::Deregister)?;
+        let namespace = resolve_namespace(&request, &request.get_ref().namespace)?;
+        let caller = caller_addr(&request);
+        let mut request = request.into_inner();
+        request.namespace = namespace;
+        request.caller = caller;
+        if request.instance_id.is_empty() {
+            return Err(Status::invalid_argument(
+                "instance_id parameter cannot be empty",
+            ));
+        }
+
+        match &self.cluster {
+            None => self.apply_deregister(request).map_err(persist_failed)?,
+            Some(cluster) => {
+                cluster.propose(Command::Deregister(request)).await?;
+            }
+        };
+
+        Ok(Response::new(()))
+    }
+
+    type KeepAliveStream = KeepAliveStream;
+
+    async fn keep_alive(
+        &self,
+        request: Request<Streaming<KeepAliveRequest>>,
+    ) -> Result<Response<Self::KeepAliveStream>, Status> {
+        println!("serdict::keep_alive: Got a request: {:?}", request);
+
+        let principal = request.extensions().get::<Arc<Principal>>().cloned();
+        // Leases for locks are renewed from the namespace they were granted in
+        let namespace = resolve_namespace(&request, "")?;
+        let mut requests = request.into_inner();
+        let (responses, res_recv) = mpsc::channel(16);
+        let serdict = self.clone();
+        tokio::spawn(async move {
+            let mut leases = HashSet::new();
+            while let Ok(Some(KeepAliveRequest { lease_id })) = requests.message().await {
+                // Renewing a lease keeps its instance registered, so it takes the same permission
+                let group = match serdict.lease_group(lease_id) {
+                    Ok(group) => group,
+                    Err(e) => {
+                        let _ = responses.send(Err(read_failed(e))).await;
+                        break;
+                    }
+                };
+                let allowed = match group {
+                    Some(group) => acl::check(principal.as_ref(), &group, Permission::Register),
+                    None => serdict.check_session(principal.as_ref(), &namespace, lease_id),
+                };
+                if let Err(e) = allowed {
+                    let _ = responses.send(Err(e.into())).await;
+                    break;
+                }
+                let res = serdict.keep_alive_lease(lease_id).await;
+                if let Ok(KeepAliveResponse { ttl, .. }) = res {
+                    if ttl > 0 {
+                        leases.insert(lease_id);
+                    }
+                }
+                if responses.send(res.map_err(Status::from)).await.is_err() {
+                    break;
+                }
+            }
+
+            // The instances behind the stream may be gone, don't wait for the next sweep
+            for lease_id in leases {
+                let serdict = serdict.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = serdict.expire_lease(lease_id).await {
+                        println!(
+                            "serdict::keep_alive: failed to expire lease {}: {}",
+                            lease_id, e
+                        );
+                    }
+                });
+            }
+        });
+
+        return Ok(Response::new(Box::pin(ReceiverStream::new(res_recv))));
+    }
+
+    async fn get_service(
+        &self,
+        request: Request<GetServiceRequest>,
+    ) -> Result<Response<GetServiceResponse>, Status> {
+        println!("serdict::get_service: Got a request: {:?}", request);
+
+        authorize(&request, &request.get_ref().group, Permission::Read)?;
+        let namespace = resolve_namespace(&request, &request.get_ref().namespace)?;
+        let GetServiceRequest {
+            group,
+            name,
+            include_inactive,
+            ..
+        } = request.into_inner();
+
+        if group.is_empty() || name.is_empty() {
+            return Err(Status::invalid_argument(
+                "group and name parameter cannot be empty",
+            ));
+        }
+
+        self.read_barrier().await?;
+        let key = (namespace, group.clone(), name.clone());
+        let instances = self.registry.get(&key).map_err(read_failed)?;
+        if let Some(mut res) =
+            service_response(&key, &instances, include_inactive, |instance_id, _| {
+                self.is_healthy(&key, instance_id)
+            })
+        {
+            res.datacenter = self.datacenter().to_string();
+            return Ok(Response::new(res));
+        }
+        if let Some(res) = self
+            .federation
+            .as_ref()
+            .and_then(|federation| federation.remote_service(&key))
+        {
+            return Ok(Response::new(res));
+        }
+
+        let msg = format!("Service {group}:{name} is not registered.");
+        return Err(Status::not_found(msg));
+    }
+
+    async fn list_service(
+        &self,
+        request: Request<ListServiceRequest>,
+    ) -> Result<Response<ListServiceResponse>, Status> {
+        println!("serdict::list_service: Got a request: {:?}", request);
+
+        let namespace = resolve_namespace(&request, &request.get_ref().namespace)?;
+        let principal = request.extensions().get::<Arc<Principal>>().cloned();
+        let request = request.into_inner();
+        let page = Page::new(request.page_size, &request.page_token)
+            .map_err(|e| Status::invalid_argument(e.to_string()))?;
+        let filter = ServiceFilter {
+            group: None,
+            group_prefix: request.group_prefix.clone(),
+            name_glob: request.name_glob.clone(),
+            health: request.health(),
+            include_inactive: request.include_inactive,
+        };
+
+        self.read_barrier().await?;
+        let res = self
+            .list_page(&namespace, principal.as_ref(), &filter, &page)
+            .map_err(read_failed)?;
+
+        return Ok(Response::new(res));
+    }
+
+    async fn list_service_by_group_name(
+        &self,
+        request: Request<ListServiceByGroupNameRequest>,
+    ) -> Result<Response<ListServiceResponse>, Status> {
+        println!(
+            "serdict::list_service_by_group_name: Got a request: {:?}",
+            request
+        );
+
+        let namespace = resolve_namespace(&request, &request.get_ref().namespace)?;
+        authorize(&request, &request.get_ref().group, Permission::Read)?;
+        let principal = request.extensions().get::<Arc<Principal>>().cloned();
+        let request = request.into_inner();
+        if request.group.is_empty() {
+            return Err(Status::invalid_argument("group parameter cannot be empty"));
+        }
+        let page = Page::new(request.page_size, &request.page_token)
+            .map_err(|e| Status::invalid_argument(e.to_string()))?;
+        let filter = ServiceFilter {
+            group: Some(request.group.clone()),
+            name_glob: request.name_glob.clone(),
+            health: request.health(),
+            ..Default::default()
+        };
+
+        self.read_barrier().await?;
+        let res = self
+            .list_page(&namespace, principal.as_ref(), &filter, &page)
+            .map_err(read_failed)?;
+
+        return Ok(Response::new(res));
+    }
+
+    async fn find_services(
+        &self,
+        request: Request<FindServicesRequest>,
+    ) -> Result<Response<ListServiceResponse>, Status> {
+        println!("serdict::find_services: Got a request: {:?}", request);
+
+        let namespace = resolve_namespace(&request, &request.get_ref().namespace)?;
+        let principal = request.extensions().get::<Arc<Principal>>().cloned();
+        let request = request.into_inner();
+        let filter = InstanceFilter::from_request(&request)
+            .map_err(|e| Status::invalid_argument(format!("invalid version requirement: {e}")))?;
+
+        self.read_barrier().await?;
+        let services_map = self.registry.list().map_err(read_failed)?;
+
+        let mut services = services_map
+            .iter()
+            .filter(|((key_namespace, group, name), _)| {
+                *key_namespace == namespace
+                    && (request.group.is_empty() || *group == request.group)
+                    && (request.name.is_empty() || *name == request.name)
+                    && acl::check(principal.as_ref(), group, Permission::Read).is_ok()
+            })
+            .filter_map(|(key, instances)| {
+                service_response(
+                    key,
+                    instances,
+                    request.include_inactive,
+                    |instance_id, record| {
+                        self.is_healthy(key, instance_id) && filter.matches(record)
+                    },
+                )
+            })
+            .map(|service| GetServiceResponse {
+                datacenter: self.datacenter().to_string(),
+                ..service
+            })
+            .collect::<Vec<_>>();
+        services.sort_by(|a, b| (&a.group, &a.name).cmp(&(&b.group, &b.name)));
+
+        return Ok(Response::new(ListServiceResponse {
+            services,
+            ..Default::default()
+        }));
+    }
+
+    type WatchServicesStream = WatchServicesStream;
+
+    async fn watch_services(
+        &self,
+        request: Request<WatchServicesRequest>,
+    ) -> Result<Response<Self::WatchServicesStream>, Status> {
+        println!("serdict::watch_services: Got a request: {:?}", request);
+
+        let namespace = resolve_namespace(&request, &request.get_ref().namespace)?;
+        if !request.get_ref().group.is_empty() {
+            authorize(&request, &request.get_ref().group, Permission::Read)?;
+        }
+        let principal = request.extensions().get::<Arc<Principal>>().cloned();
+        let WatchServicesRequest { group, .. } = request.into_inner();
+
+        let stream = BroadcastStream::new(self.events.subscribe()).filter_map(move |event| {
+            match event {
+                Ok(event)
+                    if event.namespace == namespace
+                        && (group.is_empty() || event.group == group)
+                        && acl::check(principal.as_ref(), &event.group, Permission::Read)
+                            .is_ok() =>
+                {
+                    Some(Ok(event))
+                }
+                Ok(_) => None,
+                // The watcher's view is stale, end the stream so it can list again
+                Err(BroadcastStreamRecvError::Lagged(skipped)) => Some(Err(Status::data_loss(
+                    format!("watcher lagged behind by {skipped} events"),
+                ))),
+            }
+        });
+
+        return Ok(Response::new(Box::pin(stream)));
+    }
+
+    async fn list_namespaces(
+        &self,
+        request: Request<()>,
+    ) -> Result<Response<ListNamespacesResponse>, Status> {
+        println!("serdict::list_namespaces: Got a request: {:?}", request);
+
+        let principal = request.extensions().get::<Arc<Principal>>();
+        self.read_barrier().await?;
+        let services_map = self.registry.list().map_err(read_failed)?;
+
+        let namespaces = services_map
+            .keys()
+            .filter(|(_, group, _)| acl::check(principal, group, Permission::Read).is_ok())
+            .map(|(namespace, _, _)| namespace.clone())
+            .collect::<BTreeSet<_>>();
+
+        return Ok(Response::new(ListNamespacesResponse {
+            namespaces: namespaces.into_iter().collect(),
+        }));
+    }
+
+    async fn get_registry_summary(
+        &self,
+        request: Request<()>,
+    ) -> Result<Response<RegistrySummary>, Status> {
+        println!(
+            "serdict::get_registry_summary: Got a request: {:?}",
+            request
+        );
+
+        let principal = request.extensions().get::<Arc<Principal>>();
+        self.read_barrier().await?;
+        let services_map = self.registry.list().map_err(read_failed)?;
+
+        // Only this datacenter's own instances, what it got from its peers stays here
+        let services = services_map
+            .iter()
+            .filter(|(key, _)| acl::check(principal, &key.1, Permission::Read).is_ok())
+            .filter_map(|(key, instances)| {
+                service_response(key, instances, false, |instance_id, _| {
+                    self.is_healthy(key, instance_id)
+                })
+            })
+            .collect();
+
+        return Ok(Response::new(RegistrySummary {
+            datacenter: self.datacenter().to_string(),
+            services,
+        }));
+    }
+
+    async fn kv_get(&self, request: Request<KvGetRequest>) -> Result<Response<KvEntry>, Status> {
+        println!("serdict::kv_get: Got a request: {:?}", request);
+
+        authorize(
+            &request,
+            key_group(&request.get_ref().key),
+            Permission::Read,
+        )?;
+        let namespace = resolve_namespace(&request, &request.get_ref().namespace)?;
+        let KvGetRequest { key, .. } = request.into_inner();
+        if key.is_empty() {
+            return Err(Status::invalid_argument("key parameter cannot be empty"));
+        }
+
+        self.read_barrier().await?;
+        let kv = self.read_kv();
+        match kv.get(&namespace, &key) {
+            Some(record) => Ok(Response::new(record.to_entry(&key))),
+            None => Err(Status::not_found(format!("Key {key} is not set."))),
+        }
+    }
+
+    async fn kv_put(
+        &self,
+        request: Request<KvPutRequest>,
+    ) -> Result<Response<KvPutResponse>, Status> {
+        println!("serdict::kv_put: Got a request: {:?}", request);
+
+        authorize(
+            &request,
+            key_group(&request.get_ref().key),
+            Permission::Write,
+        )?;
+        let namespace = resolve_namespace(&request, &request.get_ref().namespace)?;
+        let mut request = request.into_inner();
+        request.namespace = namespace;
+        if request.key.is_empty() {
+            return Err(Status::invalid_argument("key parameter cannot be empty"));
+        }
+
+        let res = match &self.cluster {
+            None => self.apply_kv_put(request).map_err(persist_failed)?,
+            Some(cluster) => {
+                let res = cluster.propose(Command::KvPut(request)).await?;
+                KvPutResponse::decode(res.as_slice())
+                    .map_err(|e| Status::internal(format!("Failed to put key: {e}")))?
+            }
+        };
+
+        Ok(Response::new(res))
+    }
+
+    async fn kv_delete(
+        &self,
+        request: Request<KvDeleteRequest>,
+    ) -> Result<Response<KvDeleteResponse>, Status> {
+        println!("serdict::kv_delete: Got a request: {:?}", request);
+
+        authorize(
+            &request,
+            key_group(&request.get_ref().key),
+            Permission::Write,
+        )?;
+        let namespace = resolve_namespace(&request, &request.get_ref().namespace)?;
+        let mut request = request.into_inner();
+        request.namespace = namespace;
+        if request.key.is_empty() {
+            return Err(Status::invalid_argument("key parameter cannot be empty"));
+        }
+
+        let res = match &self.cluster {
+            None => self.apply_kv_delete(request).map_err(persist_failed)?,
+            Some(cluster) => {
+                let res = cluster.propose(Command::KvDelete(request)).await?;
+                KvDeleteResponse::decode(res.as_slice())
+                    .map_err(|e| Status::internal(format!("Failed to delete key: {e}")))?
+            }
+        };
+
+        Ok(Response::new(res))
+    }
+
+    async fn kv_list(
+        &self,
+        request: Request<KvListRequest>,
+    ) -> Result<Response<KvListResponse>, Status> {
+        println!("serdict::kv_list: Got a request: {:?}", request);
+
+        let namespace = resolve_namespace(&request, &request.get_ref().namespace)?;
+        let principal = request.extensions().get::<Arc<Principal>>().cloned();
+        let KvListRequest { prefix, .. } = request.into_inner();
+
+        self.read_barrier().await?;
+        let kv = self.read_kv();
+        let entries = kv
+            .list(&namespace, &prefix)
+            .filter(|(key, _)| {
+                acl::check(principal.as_ref(), key_group(key), Permission::Read).is_ok()
+            })
+            .map(|(key, record)| record.to_entry(key))
+            .collect();
+
+        Ok(Response::new(KvListResponse {
+            entries,
+            revision: kv.revision,
+        }))
+    }
+
+    type KvWatchStream = KvWatchStream;
+
+    async fn kv_watch(
+        &self,
+        request: Request<KvWatchRequest>,
+    ) -> Result<Response<Self::KvWatchStream>, Status> {
+        println!("serdict::kv_watch: Got a request: {:?}", request);
+
+        let namespace = resolve_namespace(&request, &request.get_ref().namespace)?;
+        let principal = request.extensions().get::<Arc<Principal>>().cloned();
+        let KvWatchRequest { prefix, .. } = request.into_inner();
+
+        let stream = BroadcastStream::new(self.kv_events.subscribe()).filter_map(move |event| {
+            match event {
+                Ok(event) => {
+                    let key = event.entry.as_ref().map(|entry| entry.key.as_str());
+                    let matches = event.namespace == namespace
+                        && matches!(key, Some(key) if key.starts_with(&prefix)
+                            && acl::check(principal.as_ref(), key_group(key), Permission::Read)
+                                .is_ok());
+                    matches.then_some(Ok(event))
+                }
+                // The watcher's view is stale, end the stream so it can list again
+                Err(BroadcastStreamRecvError::Lagged(skipped)) => Some(Err(Status::data_loss(
+                    format!("watcher lagged behind by {skipped} events"),
+                ))),
+            }
+        });
+
+        Ok(Response::new(Box::pin(stream)))
+    }
+
+    async fn grant_lease(
+        &self,
+        request: Request<GrantLeaseRequest>,
+    ) -> Result<Response<GrantLeaseResponse>, Status> {
+        println!("serdict::grant_lease: Got a request: {:?}", request);
+
+        authorize(&request, &request.get_ref().group, Permission::Register)?;
+        let namespace = resolve_namespace(&request, &request.get_ref().namespace)?;
+        let owner = request
+            .extensions()
+            .get::<Arc<Principal>>()
+            .map(|principal| principal.name.clone())
+            .unwrap_or_default();
+        let mut request = request.into_inner();
+        request.namespace = namespace;
+        request.owner = owner;
+        // Drawn before replicating, so every node knows the lease by the same id
+        request.lease_id = rand::thread_rng().gen_range(1..=LeaseId::MAX);
+
+        let res = match &self.cluster {
+            None => self.apply_grant_lease(request).map_err(persist_failed)?,
+            Some(cluster) => {
+                let res = cluster.propose(Command::GrantLease(request)).await?;
+                GrantLeaseResponse::decode(res.as_slice())
+                    .map_err(|e| Status::internal(format!("Failed to grant lease: {e}")))?
+            }
+        };
+
+        Ok(Response::new(res))
+    }
+
+    async fn acquire_lock(
+        &self,
+        request: Request<AcquireLockRequest>,
+    ) -> Result<Response<AcquireLockResponse>, Status> {
+        println!("serdict::acquire_lock: Got a request: {:?}", request);
+
+        authorize(
+            &request,
+            key_group(&request.get_ref().name),
+            Permission::Write,
+        )?;
+        let namespace = resolve_namespace(&request, &request.get_ref().namespace)?;
+        let principal = request.extensions().get::<Arc<Principal>>();
+        self.check_session(principal, &namespace, request.get_ref().lease_id)?;
+        let mut request = request.into_inner();
+        request.namespace = namespace;
+        if request.name.is_empty() {
+            return Err(Status::invalid_argument("name parameter cannot be empty"));
+        }
+
+        Ok(Response::new(self.acquire_lock_with(request).await?))
+    }
+
+    async fn release_lock(
+        &self,
+        request: Request<ReleaseLockRequest>,
+    ) -> Result<Response<()>, Status> {
+        println!("serdict::release_lock: Got a request: {:?}", request);
+
+        authorize(
+            &request,
+            key_group(&request.get_ref().name),
+            Permission::Write,
+        )?;
+        let namespace = resolve_namespace(&request, &request.get_ref().namespace)?;
+        let principal = request.extensions().get::<Arc<Principal>>();
+        self.check_session(principal, &namespace, request.get_ref().lease_id)?;
+        let mut request = request.into_inner();
+        request.namespace = namespace;
+
+        match &self.cluster {
+            None => self.apply_release_lock(request).map_err(persist_failed)?,
+            Some(cluster) => {
+                cluster.propose(Command::ReleaseLock(request)).await?;
+            }
+        };
+
+        Ok(Response::new(()))
+    }
+
+    async fn campaign(&self, request: Request<CampaignRequest>) -> Result<Response<Lock>, Status> {
+        println!("serdict::campaign: Got a request: {:?}", request);
+
+        authorize(
+            &request,
+            key_group(&request.get_ref().election),
+            Permission::Write,
+        )?;
+        let namespace = resolve_namespace(&request, &request.get_ref().namespace)?;
+        let principal = request.extensions().get::<Arc<Principal>>();
+        self.check_session(principal, &namespace, request.get_ref().lease_id)?;
+        let CampaignRequest {
+            election,
+            lease_id,
+            candidate,
+            ..
+        } = request.into_inner();
+        if election.is_empty() {
+            return Err(Status::invalid_argument(
+                "election parameter cannot be empty",
+            ));
+        }
+
+        let key = (namespace, election);
+        let request = AcquireLockRequest {
+            name: key.1.clone(),
+            lease_id,
+            holder: candidate,
+            namespace: key.0.clone(),
+        };
+        // Subscribed before the first try, so no release slips in between
+        let mut events = self.lock_events.subscribe();
+        loop {
+            let res = self.acquire_lock_with(request.clone()).await?;
+            match (res.acquired, res.lock) {
+                (true, Some(lock)) => return Ok(Response::new(lock)),
+                (false, Some(leader)) => self.lock_changed(&key, Some(&leader), &mut events).await,
+                (_, None) => {
+                    let msg = format!("lease {lease_id} is not live, grant another one");
+                    return Err(Status::failed_precondition(msg));
+                }
+            }
+        }
+    }
+
+    type ObserveStream = ObserveStream;
+
+    async fn observe(
+        &self,
+        request: Request<ObserveRequest>,
+    ) -> Result<Response<Self::ObserveStream>, Status> {
+        println!("serdict::observe: Got a request: {:?}", request);
+
+        authorize(
+            &request,
+            key_group(&request.get_ref().election),
+            Permission::Read,
+        )?;
+        let namespace = resolve_namespace(&request, &request.get_ref().namespace)?;
+        let key = (namespace, request.into_inner().election);
+
+        let mut events = self.lock_events.subscribe();
+        let (updates, update_recv) = mpsc::channel(16);
+        let serdict = self.clone();
+        tokio::spawn(async move {
+            let mut last = None;
+            loop {
+                let leader = {
+                    let kv = serdict.read_kv();
+                    kv.locks.holder(&key).map(|lock| lock.to_lock(&key.1))
+                };
+                if last.as_ref() != Some(&leader) {
+                    let event = LeaderEvent {
+                        leader: leader.clone(),
+                    };
+                    if updates.send(Ok(event)).await.is_err() {
+                        return;
+                    }
+                    last = Some(leader.clone());
+                }
+
+                tokio::select! {
+                    _ = updates.closed() => return,
+                    _ = serdict.lock_changed(&key, leader.as_ref(), &mut events) => {}
+                }
+            }
+        });
+
+        Ok(Response::new(Box::pin(ReceiverStream::new(update_recv))))
+    }
+
+    async fn list_events(
+        &self,
+        request: Request<ListEventsRequest>,
+    ) -> Result<Response<ListEventsResponse>, Status> {
+        println!("serdict::list_events: Got a request: {:?}", request);
+
+        let namespace = resolve_namespace(&request, &request.get_ref().namespace)?;
+        if !request.get_ref().group.is_empty() {
+            authorize(&request, &request.get_ref().group, Permission::Read)?;
+        }
+        let principal = request.extensions().get::<Arc<Principal>>().cloned();
+        let ListEventsRequest {
+            group,
+            name,
+            since,
+            until,
+            ..
+        } = request.into_inner();
+
+        let events = self
+            .audit
+            .list(|entry| {
+                let at = entry.millis();
+                entry.namespace == namespace
+                    && (group.is_empty() || entry.group == group)
+                    && (name.is_empty() || entry.name == name)
+                    && at >= since
+                    && (until == 0 || at < until)
+                    && acl::check(principal.as_ref(), &entry.group, Permission::Read).is_ok()
+            })
+            .iter()
+            .map(|entry| entry.to_event())
+            .collect();
+
+        return Ok(Response::new(ListEventsResponse { events }));
+    }
+
+    async fn set_instance_state(
+        &self,
+        request: Request<SetInstanceStateRequest>,
+    ) -> Result<Response<SetInstanceStateResponse>, Status> {
+        println!("serdict::set_instance_state: Got a request: {:?}", request);
+
+        // Whoever may register an instance may also take it out of rotation
+        authorize(&request, &request.get_ref().group, Permission::Register)?;
+        let namespace = resolve_namespace(&request, &request.get_ref().namespace)?;
+        let caller = caller_addr(&request);
+        let mut request = request.into_inner();
+        request.namespace = namespace;
+        request.caller = caller;
+        if request.instance_id.is_empty() {
+            return Err(Status::invalid_argument(
+                "instance_id parameter cannot be empty",
+            ));
+        }
+
+        let (group, name, instance_id) = (
+            request.group.clone(),
+            request.name.clone(),
+            request.instance_id.clone(),
+        );
+        let res = match &self.cluster {
+            None => self
+                .apply_set_instance_state(request)
+                .map_err(persist_failed)?,
+            Some(cluster) => {
+                let res = cluster.propose(Command::SetInstanceState(request)).await?;
+                SetInstanceStateResponse::decode(res.as_slice())
+                    .map_err(|e| Status::internal(format!("Failed to set state: {e}")))?
+            }
+        };
+        if res.instance.is_none() {
+            let msg = format!("Instance {instance_id} of {group}:{name} is not registered.");
+            return Err(Status::not_found(msg));
+        }
+
+        Ok(Response::new(res))
+    }
+
+    async fn export_registry(
+        &self,
+        request: Request<ExportRegistryRequest>,
+    ) -> Result<Response<ExportRegistryResponse>, Status> {
+        println!("serdict::export_registry: Got a request: {:?}", request);
+
+        let principal = request.extensions().get::<Arc<Principal>>().cloned();
+        let request = request.into_inner();
+
+        self.read_barrier().await?;
+        let services_map = self.registry.list().map_err(read_failed)?;
+        let dump = RegistryDump::new(
+            &services_map,
+            |(namespace, group, _)| {
+                (request.namespace.is_empty() || *namespace == request.namespace)
+                    && acl::check(principal.as_ref(), group, Permission::Read).is_ok()
+            },
+            SystemTime::now(),
+        );
+        let data = dump
+            .encode(request.format())
+            .map_err(|e| Status::internal(format!("Failed to export registry: {e}")))?;
+
+        Ok(Response::new(ExportRegistryResponse { data }))
+    }
+
+    async fn import_registry(
+        &self,
+        request: Request<ImportRegistryRequest>,
+    ) -> Result<Response<ImportRegistryResponse>, Status> {
+        // The data may be large, only say who it is from
+        println!(
+            "serdict::import_registry: Got a request from {:?}",
+            request.remote_addr()
+        );
+
+        let principal = request.extensions().get::<Arc<Principal>>().cloned();
+        let caller = caller_addr(&request);
+        let mut request = request.into_inner();
+        let dump = RegistryDump::decode(&request.data, request.format())
+            .map_err(|e| Status::invalid_argument(format!("Cannot import registry: {e}")))?;
+        let now = SystemTime::now();
+
+        self.read_barrier().await?;
+        let services_map = self.registry.list().map_err(read_failed)?;
+        let changes = export::diff(
+            &export::filter_pinned(services_map, false),
+            &dump.into_map(now),
+            request.mode() == Mode::Replace,
+        );
+        for (kind, (_, group, _), _, _) in &changes {
+            let permission = match kind {
+                registry_change::Kind::Removed => Permission::Deregister,
+                _ => Permission::Register,
+            };
+            acl::check(principal.as_ref(), group, permission)?;
+        }
+        if request.dry_run {
+            return Ok(Response::new(ImportRegistryResponse {
+                changes: changes.iter().map(export::to_change).collect(),
+                applied: false,
+            }));
+        }
+
+        // Every node applies exactly what the caller was authorized for, with the same ages
+        request.changes = export::encode_changes(&changes)
+            .map_err(|e| Status::internal(format!("Failed to import registry: {e}")))?;
+        request.data.clear();
+        request.caller = caller;
+
+        let res = match &self.cluster {
+            None => self
+                .apply_import_registry(request)
+                .map_err(persist_failed)?,
+            Some(cluster) => {
+                let res = cluster.propose(Command::ImportRegistry(request)).await?;
+                ImportRegistryResponse::decode(res.as_slice())
+                    .map_err(|e| Status::internal(format!("Failed to import registry: {e}")))?
+            }
+        };
+
+        Ok(Response::new(res))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use prost::Message;
+    use tonic::{Code, Request};
+
+    use super::{service_response, Acl, InstanceFilter, InstanceState, SerDictImpl, ServiceRecord};
+    use crate::svc_dsc::{
+        gen::{
+            registry_command::Command, ser_dict_server::SerDict, service_event::Kind,
+            AcquireLockRequest, DeregisterServiceRequest, ExpireLeasesRequest, FindServicesRequest,
+            GetServiceRequest, GrantLeaseRequest, ListServiceRequest, RegisterServiceRequest,
+            RegistryCommand,
+        },
+        server::{
+            raft::StateMachine,
+            registry::{MemoryRegistry, SqliteRegistry},
+            store::NoopStore,
+        },
+        NAMESPACE_HEADER,
+    };
+
+    use std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    fn record(version: &str, tags: &[&str], zone: &str) -> ServiceRecord {
+        ServiceRecord::new(RegisterServiceRequest {
+            version: version.into(),
+            tags: tags.iter().map(|tag| tag.to_string()).collect(),
+            metadata: [("zone".to_string(), zone.to_string())].into(),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn it_filters_instances() {
+        let filter = InstanceFilter::from_request(&FindServicesRequest {
+            tags: vec!["canary".into()],
+            metadata: [("zone".to_string(), "a".to_string())].into(),
+            version: ">= 1.2".into(),
+            ..Default::default()
+        })
+        .unwrap();
+
+        assert!(filter.matches(&record("1.3.0", &["canary", "arm"], "a")));
+        assert!(!filter.matches(&record("1.1.9", &["canary"], "a")));
+        assert!(!filter.matches(&record("", &["canary"], "a")));
+        assert!(!filter.matches(&record("1.3.0", &[], "a")));
+        assert!(!filter.matches(&record("1.3.0", &["canary"], "b")));
+    }
+
+    #[test]
+    fn it_leaves_out_inactive_instances() {
+        let key = ("default".to_string(), "math".to_string(), "add".to_string());
+        let mut draining = record("1.0.0", &[], "a");
+        draining.state = InstanceState::Draining;
+        let instances = [
+            ("a".to_string(), draining),
+            ("b".to_string(), record("1.0.0", &[], "a")),
+        ]
+        .into();
+
+        let ids = |include_inactive| {
+            service_response(&key, &instances, include_inactive, |_, _| true)
+                .map(|res| res.instances.into_iter().map(|i| i.instance_id).collect())
+        };
+        assert_eq!(ids(false), Some(vec!["b".to_string()]));
+        assert_eq!(ids(true), Some(vec!["a".to_string(), "b".to_string()]));
+    }
+
+    #[tokio::test]
+    async fn it_keeps_leases_until_they_run_out() {
+        let (events, mut watch) = tokio::sync::broadcast::channel(16);
+        let serdict = SerDictImpl::new(
+            Arc::new(MemoryRegistry::default()),
+            events,
+            Arc::new(Mutex::new(NoopStore)),
+        );
+        let register = || async {
+            let request = Request::new(RegisterServiceRequest {
+                group: "math".into(),
+                name: "add".into(),
+                ip: "10.0.0.1".into(),
+                port: 50052,
+                ttl: 200,
+                ..Default::default()
+            });
+            serdict
+                .register_service(request)
+                .await
+                .unwrap()
+                .into_inner()
+        };
+
+        let lease_id = register().await.lease_id;
+        // Heartbeats re-register, the KeepAlive stream goes on renewing the same lease
+        assert_eq!(register().await.lease_id, lease_id);
+        tokio::time::sleep(Duration::from_millis(120)).await;
+        assert_eq!(serdict.keep_alive_lease(lease_id).await.unwrap().ttl, 200);
+        tokio::time::sleep(Duration::from_millis(120)).await;
+        // Renewed, it outlived its first TTL
+        assert_eq!(serdict.lease_group(lease_id).unwrap().unwrap(), "math");
+
+        serdict.expire_lease(lease_id).await.unwrap();
+        assert!(serdict.lease_group(lease_id).unwrap().is_none());
+        assert_eq!(serdict.keep_alive_lease(lease_id).await.unwrap().ttl, 0);
+        let kinds = std::iter::from_fn(|| watch.try_recv().ok())
+            .map(|event| event.kind())
+            .collect::<Vec<_>>();
+        assert_eq!(kinds, [Kind::Registered, Kind::Expired]);
+
+        // Followers expire what the leader replicated, whatever their own clock says
+        let (events, _) = tokio::sync::broadcast::channel(16);
+        let follower = SerDictImpl::new(
+            Arc::new(MemoryRegistry::default()),
+            events,
+            Arc::new(Mutex::new(NoopStore)),
+        );
+        let commands = [
+            Command::Register(RegisterServiceRequest {
+                group: "math".into(),
+                name: "add".into(),
+                lease_id,
+                ..Default::default()
+            }),
+            Command::ExpireLeases(ExpireLeasesRequest {
+                lease_ids: vec![lease_id],
+            }),
+        ];
+        for command in commands {
+            let command = RegistryCommand {
+                command: Some(command),
+            };
+            follower.apply(&command.encode_to_vec()).unwrap();
+        }
+        assert!(follower.lease_group(lease_id).unwrap().is_none());
+    }
+
+    async fn register_in(
+        serdict: &SerDictImpl,
+        namespace: &str,
+        header: Option<&str>,
+        name: &str,
+    ) -> Result<(), Code> {
+        let mut request = Request::new(RegisterServiceRequest {
+            namespace: namespace.into(),
+            group: "math".into(),
+            name: name.into(),
+            ip: "10.0.0.1".into(),
+            port: 50052,
+            ..Default::default()
+        });
+        if let Some(header) = header {
+            request
+                .metadata_mut()
+                .insert(NAMESPACE_HEADER, header.parse().unwrap());
+        }
+        serdict
+            .register_service(request)
+            .await
+            .map(|_| ())
+            .map_err(|status| status.code())
+    }
+
+    async fn names_in(serdict: &SerDictImpl, namespace: &str) -> Vec<(String, String)> {
+        let request = Request::new(ListServiceRequest {
+            namespace: namespace.into(),
+            ..Default::default()
+        });
+        let res = serdict.list_service(request).await.unwrap().into_inner();
+        res.services
+            .into_iter()
+            .map(|service| (service.namespace, service.name))
+            .collect()
+    }
+
+    async fn port_in(serdict: &SerDictImpl, namespace: &str, name: &str) -> Result<u32, Code> {
+        let request = Request::new(GetServiceRequest {
+            namespace: namespace.into(),
+            group: "math".into(),
+            name: name.into(),
+            ..Default::default()
+        });
+        match serdict.get_service(request).await {
+            Ok(res) => Ok(res.into_inner().port),
+            Err(status) => Err(status.code()),
+        }
+    }
+
+    #[tokio::test]
+    async fn it_keeps_namespaces_apart() {
+        let (events, _) = tokio::sync::broadcast::channel(16);
+        let serdict = SerDictImpl::new(
+            Arc::new(MemoryRegistry::default()),
+            events,
+            Arc::new(Mutex::new(NoopStore)),
+        );
+
+        // Without a namespace, nor a header, services go to the default one
+        register_in(&serdict, "", None, "add").await.unwrap();
+        register_in(&serdict, "staging", None, "sub").await.unwrap();
+        register_in(&serdict, "", Some("dev"), "mul").await.unwrap();
+        // The field wins over the header
+        register_in(&serdict, "staging", Some("dev"), "div")
+            .await
+            .unwrap();
+
+        let default = vec![("default".to_string(), "add".to_string())];
+        assert_eq!(names_in(&serdict, "").await, default);
+        assert_eq!(names_in(&serdict, "default").await, default);
+        assert_eq!(
+            names_in(&serdict, "staging").await,
+            vec![
+                ("staging".to_string(), "div".to_string()),
+                ("staging".to_string(), "sub".to_string())
+            ]
+        );
+        assert_eq!(
+            names_in(&serdict, "dev").await,
+            vec![("dev".to_string(), "mul".to_string())]
+        );
+        assert!(names_in(&serdict, "prod").await.is_empty());
+
+        assert_eq!(port_in(&serdict, "", "add").await, Ok(50052));
+        assert_eq!(port_in(&serdict, "staging", "sub").await, Ok(50052));
+        assert_eq!(port_in(&serdict, "", "sub").await, Err(Code::NotFound));
+        assert_eq!(
+            port_in(&serdict, "staging", "add").await,
+            Err(Code::NotFound)
+        );
+
+        let too_long = "a".repeat(64);
+        for namespace in [
+            "Staging",
+            "-dev",
+            "dev-",
+            "dev_1",
+            "dev.1",
+            too_long.as_str(),
+        ] {
+            let invalid = Err(Code::InvalidArgument);
+            assert_eq!(register_in(&serdict, namespace, None, "add").await, invalid);
+            assert_eq!(
+                register_in(&serdict, "", Some(namespace), "add").await,
+                invalid
+            );
+            assert_eq!(
+                port_in(&serdict, namespace, "add").await.map(|_| ()),
+                invalid
+            );
+        }
+        let longest = "a".repeat(63);
+        assert!(register_in(&serdict, &longest, None, "add").await.is_ok());
+    }
+
+    fn as_principal<T>(acl: &Acl, token: &str, message: T) -> Request<T> {
+        let mut request = Request::new(message);
+        let authorization = format!("Bearer {token}").parse().unwrap();
+        request
+            .metadata_mut()
+            .insert("authorization", authorization);
+        let principal = acl.authenticate(request.metadata()).unwrap();
+        request.extensions_mut().insert(principal);
+        request
+    }
+
+    #[tokio::test]
+    async fn it_keeps_leases_to_their_owner() {
+        let acl = Acl::parse(
+            r#"{
+                "principals": [
+                    { "name": "jobs", "token": "a", "rules": [
+                        { "group": "math", "permissions": ["register", "write"] } ] },
+                    { "name": "other", "token": "b", "rules": [
+                        { "group": "*", "permissions": ["write"] } ] }
+                ]
+            }"#,
+        )
+        .unwrap();
+        let (events, _) = tokio::sync::broadcast::channel(16);
+        let serdict = SerDictImpl::new(
+            Arc::new(MemoryRegistry::default()),
+            events,
+            Arc::new(Mutex::new(NoopStore)),
+        );
+        let grant = |token| {
+            let request = GrantLeaseRequest {
+                group: "math".into(),
+                ..Default::default()
+            };
+            serdict.grant_lease(as_principal(&acl, token, request))
+        };
+        let acquire = |token, namespace: &str, lease_id| {
+            let request = AcquireLockRequest {
+                name: "math/jobs".into(),
+                lease_id,
+                namespace: namespace.into(),
+                ..Default::default()
+            };
+            serdict.acquire_lock(as_principal(&acl, token, request))
+        };
+
+        // Leases take the register permission on their group
+        assert_eq!(grant("b").await.unwrap_err().code(), Code::PermissionDenied);
+        let lease_id = grant("a").await.unwrap().into_inner().lease_id;
+
+        // Only their owner takes locks with them, in their namespace
+        let err = acquire("b", "", lease_id).await.unwrap_err();
+        assert_eq!(err.code(), Code::PermissionDenied);
+        let err = acquire("a", "staging", lease_id).await.unwrap_err();
+        assert_eq!(err.code(), Code::PermissionDenied);
+        let res = acquire("a", "", lease_id).await.unwrap().into_inner();
+        assert!(res.acquired);
+    }
+
+    // SQLite statements block, workers of a multi-threaded runtime hand their tasks over first
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn it_serves_from_sqlite() {
+        let path = std::env::temp_dir().join(format!("svc-dsc-serdict-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let (events, _) = tokio::sync::broadcast::channel(16);
+        let serdict = SerDictImpl::new(
+            Arc::new(SqliteRegistry::open(&path).unwrap()),
+            events,
+            Arc::new(Mutex::new(NoopStore)),
+        );
+
+        register_in(&serdict, "", None, "add").await.unwrap();
+        register_in(&serdict, "staging", None, "add").await.unwrap();
+        assert_eq!(
+            names_in(&serdict, "").await,
+            vec![("default".to_string(), "add".to_string())]
+        );
+        assert_eq!(port_in(&serdict, "staging", "add").await, Ok(50052));
+
+        let res = serdict
+            .get_service(Request::new(GetServiceRequest {
+                group: "math".into(),
+                name: "add".into(),
+                ..Default::default()
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        let request = Request::new(DeregisterServiceRequest {
+            group: "math".into(),
+            name: "add".into(),
+            instance_id: res.instances[0].instance_id.clone(),
+            ..Default::default()
+        });
+        serdict.deregister_service(request).await.unwrap();
+        assert_eq!(port_in(&serdict, "", "add").await, Err(Code::NotFound));
+        assert_eq!(port_in(&serdict, "staging", "add").await, Ok(50052));
+        std::fs::remove_file(&path).unwrap();
     }
 }
@@ -0,0 +1,376 @@
+use serde::{Deserialize, Serialize};
+
+use crate::svc_dsc::DEFAULT_NAMESPACE;
+
+use super::{
+    kv::{KvMap, KvRecord},
+    lock::{LockRecord, Session},
+    serdict::{InstanceId, LeaseId, ServiceMap, ServiceRecord},
+};
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+const SNAPSHOT_FILE: &str = "snapshot.jsonl";
+const WAL_FILE: &str = "wal.jsonl";
+
+// Entries written before namespaces belong to the default one
+fn default_namespace() -> String {
+    DEFAULT_NAMESPACE.to_string()
+}
+
+// A change to the service map or the key/value store, as written to the write-ahead log.
+// Snapshots are a list of Register entries, one per live instance, then a KvPut per key, a Lease
+// and Lock per lease and lock, and the key/value store's revision.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum WalEntry {
+    Register {
+        #[serde(default = "default_namespace")]
+        namespace: String,
+        group: String,
+        name: String,
+        instance_id: InstanceId,
+        record: ServiceRecord,
+    },
+    Deregister {
+        #[serde(default = "default_namespace")]
+        namespace: String,
+        group: String,
+        name: String,
+        instance_id: InstanceId,
+    },
+    KvPut {
+        namespace: String,
+        key: String,
+        record: KvRecord,
+    },
+    KvDelete {
+        namespace: String,
+        key: String,
+        revision: u64,
+    },
+    // Deletes leave no key behind to carry the revision past a snapshot
+    KvRevision {
+        revision: u64,
+    },
+    // Renewals aren't logged, like those of instances
+    Lease {
+        lease_id: LeaseId,
+        session: Session,
+    },
+    Lock {
+        namespace: String,
+        name: String,
+        lock: LockRecord,
+    },
+    Unlock {
+        namespace: String,
+        name: String,
+    },
+}
+
+impl WalEntry {
+    // Replays this change onto map or kv. Replaying the same entry twice is harmless.
+    pub fn apply(self, map: &mut ServiceMap, kv: &mut KvMap) {
+        match self {
+            WalEntry::Register {
+                namespace,
+                group,
+                name,
+                instance_id,
+                record,
+            } => {
+                map.entry((namespace, group, name))
+                    .or_default()
+                    .insert(instance_id, record);
+            }
+            WalEntry::Deregister {
+                namespace,
+                group,
+                name,
+                instance_id,
+            } => {
+                let key = (namespace, group, name);
+                if let Some(instances) = map.get_mut(&key) {
+                    instances.remove(&instance_id);
+                    if instances.is_empty() {
+                        map.remove(&key);
+                    }
+                }
+            }
+            WalEntry::KvPut {
+                namespace,
+                key,
+                record,
+            } => kv.insert(namespace, key, record),
+            WalEntry::KvDelete {
+                namespace,
+                key,
+                revision,
+            } => {
+                kv.remove(namespace, key, revision);
+            }
+            WalEntry::KvRevision { revision } => kv.revision = kv.revision.max(revision),
+            WalEntry::Lease { lease_id, session } => {
+                kv.locks.sessions.insert(lease_id, session);
+            }
+            WalEntry::Lock {
+                namespace,
+                name,
+                lock,
+            } => {
+                kv.revision = kv.revision.max(lock.token);
+                kv.locks.locks.insert((namespace, name), lock);
+            }
+            WalEntry::Unlock { namespace, name } => {
+                kv.locks.locks.remove(&(namespace, name));
+            }
+        }
+    }
+}
+
+// Durable storage of the service map and the key/value store
+pub trait RegistryStore: Send {
+    // Appends a change to the log, before it is applied to the map
+    fn append(&mut self, entry: &WalEntry) -> io::Result<()>;
+
+    // Replaces the last snapshot with the whole map and key/value store, and truncates the log
+    fn snapshot(&mut self, map: &ServiceMap, kv: &KvMap) -> io::Result<()>;
+
+    // Rebuilds the map and key/value store out of the last snapshot and the log written after it.
+    // Instances and lock leases whose heartbeat expired while svc-dsc was down are dropped.
+    fn load(&mut self) -> io::Result<(ServiceMap, KvMap)>;
+}
+
+// Keeps nothing, the registry starts empty every time
+#[derive(Debug, Default)]
+pub struct NoopStore;
+
+impl RegistryStore for NoopStore {
+    fn append(&mut self, _entry: &WalEntry) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn snapshot(&mut self, _map: &ServiceMap, _kv: &KvMap) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn load(&mut self) -> io::Result<(ServiceMap, KvMap)> {
+        Ok((ServiceMap::new(), KvMap::default()))
+    }
+}
+
+// Stores a JSON lines snapshot and write-ahead log in a directory
+#[derive(Debug)]
+pub struct FileStore {
+    dir: PathBuf,
+    wal: File,
+}
+
+impl FileStore {
+    pub fn open(dir: impl AsRef<Path>) -> io::Result<FileStore> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        let wal = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(dir.join(WAL_FILE))?;
+
+        Ok(Self { dir, wal })
+    }
+}
+
+fn to_line(entry: &WalEntry) -> io::Result<Vec<u8>> {
+    let mut line = serde_json::to_vec(entry)?;
+    line.push(b'\n');
+    Ok(line)
+}
+
+fn write_map(w: &mut impl Write, map: &ServiceMap, kv: &KvMap) -> io::Result<()> {
+    for ((namespace, group, name), instances) in map {
+        for (instance_id, record) in instances {
+            w.write_all(&to_line(&WalEntry::Register {
+                namespace: namespace.clone(),
+                group: group.clone(),
+                name: name.clone(),
+                instance_id: instance_id.clone(),
+                record: record.clone(),
+            })?)?;
+        }
+    }
+    for ((namespace, key), record) in &kv.entries {
+        w.write_all(&to_line(&WalEntry::KvPut {
+            namespace: namespace.clone(),
+            key: key.clone(),
+            record: record.clone(),
+        })?)?;
+    }
+    for (lease_id, session) in &kv.locks.sessions {
+        w.write_all(&to_line(&WalEntry::Lease {
+            lease_id: *lease_id,
+            session: session.clone(),
+        })?)?;
+    }
+    for ((namespace, name), lock) in &kv.locks.locks {
+        w.write_all(&to_line(&WalEntry::Lock {
+            namespace: namespace.clone(),
+            name: name.clone(),
+            lock: lock.clone(),
+        })?)?;
+    }
+    w.write_all(&to_line(&WalEntry::KvRevision {
+        revision: kv.revision,
+    })?)
+}
+
+// Applies every entry read onto map and kv, returns how many were applied.
+// Stops at the first torn line, which is what a crash in the middle of a write leaves behind.
+fn replay(
+    reader: impl BufRead,
+    source: &str,
+    map: &mut ServiceMap,
+    kv: &mut KvMap,
+) -> io::Result<usize> {
+    let mut applied = 0;
+    for line in reader.lines() {
+        let line = line?;
+        match serde_json::from_str::<WalEntry>(&line) {
+            Ok(entry) => {
+                entry.apply(map, kv);
+                applied += 1;
+            }
+            Err(e) => {
+                println!(
+                    "svc_dsc::store: ignoring the rest of {} after a torn entry: {}",
+                    source, e
+                );
+                break;
+            }
+        }
+    }
+
+    Ok(applied)
+}
+
+fn replay_file(path: &Path, map: &mut ServiceMap, kv: &mut KvMap) -> io::Result<usize> {
+    match File::open(path) {
+        Ok(file) => replay(BufReader::new(file), &path.display().to_string(), map, kv),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(0),
+        Err(e) => Err(e),
+    }
+}
+
+// The whole map and key/value store in the snapshot format, for shipping them to another node
+pub fn encode_map(map: &ServiceMap, kv: &KvMap) -> io::Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    write_map(&mut bytes, map, kv)?;
+    Ok(bytes)
+}
+
+pub fn decode_map(bytes: &[u8]) -> io::Result<(ServiceMap, KvMap)> {
+    let mut map = ServiceMap::new();
+    let mut kv = KvMap::default();
+    replay(bytes, "snapshot", &mut map, &mut kv)?;
+    Ok((map, kv))
+}
+
+impl RegistryStore for FileStore {
+    fn append(&mut self, entry: &WalEntry) -> io::Result<()> {
+        self.wal.write_all(&to_line(entry)?)
+    }
+
+    fn snapshot(&mut self, map: &ServiceMap, kv: &KvMap) -> io::Result<()> {
+        let tmp_path = self.dir.join(format!("{}.tmp", SNAPSHOT_FILE));
+        let mut tmp = BufWriter::new(File::create(&tmp_path)?);
+        write_map(&mut tmp, map, kv)?;
+        tmp.into_inner()?.sync_all()?;
+        fs::rename(&tmp_path, self.dir.join(SNAPSHOT_FILE))?;
+
+        // Everything in the log is part of the snapshot now
+        self.wal.set_len(0)?;
+        self.wal.sync_all()
+    }
+
+    fn load(&mut self) -> io::Result<(ServiceMap, KvMap)> {
+        let mut map = ServiceMap::new();
+        let mut kv = KvMap::default();
+        let from_snapshot = replay_file(&self.dir.join(SNAPSHOT_FILE), &mut map, &mut kv)?;
+        let from_wal = replay_file(&self.dir.join(WAL_FILE), &mut map, &mut kv)?;
+        println!(
+            "svc_dsc::store: replayed {} snapshot and {} log entries from {}",
+            from_snapshot,
+            from_wal,
+            self.dir.display()
+        );
+
+        for instances in map.values_mut() {
+            instances.retain(|_, record| !record.is_expired());
+        }
+        map.retain(|_, instances| !instances.is_empty());
+        let due = kv.locks.due();
+        kv.locks.expire(&due);
+
+        Ok((map, kv))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{FileStore, RegistryStore, WalEntry};
+    use crate::svc_dsc::server::serdict::ServiceRecord;
+
+    use std::time::{Duration, SystemTime};
+
+    fn register(instance_id: &str, last_updated: SystemTime) -> WalEntry {
+        WalEntry::Register {
+            namespace: "default".into(),
+            group: "math".into(),
+            name: "add".into(),
+            instance_id: instance_id.into(),
+            record: ServiceRecord {
+                addr: ("[::1]".into(), 50052),
+                last_updated,
+                lease_id: 1,
+                ttl: 5000,
+                ..Default::default()
+            },
+        }
+    }
+
+    #[test]
+    fn it_replays_snapshot_and_log() {
+        let dir = std::env::temp_dir().join(format!("svc-dsc-store-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let now = SystemTime::now();
+        let mut store = FileStore::open(&dir).unwrap();
+        store.append(&register("a", now)).unwrap();
+        store.append(&register("b", now)).unwrap();
+        let (map, kv) = store.load().unwrap();
+        store.snapshot(&map, &kv).unwrap();
+
+        store
+            .append(&WalEntry::Deregister {
+                namespace: "default".into(),
+                group: "math".into(),
+                name: "add".into(),
+                instance_id: "a".into(),
+            })
+            .unwrap();
+        store
+            .append(&register("stale", now - Duration::from_secs(3600)))
+            .unwrap();
+        drop(store);
+
+        let (map, _) = FileStore::open(&dir).unwrap().load().unwrap();
+        let key = ("default".to_string(), "math".to_string(), "add".to_string());
+        let instances = &map[&key];
+        assert_eq!(instances.keys().collect::<Vec<_>>(), vec!["b"]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
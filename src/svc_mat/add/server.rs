@@ -42,6 +42,8 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         host: SERVICE_HOST.to_string(),
         port: SERVICE_PORT,
         should_register: true,
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        ..Default::default()
     };
 
     serve_with_shutdown(service, &cfg).await?;
@@ -1,16 +1,17 @@
 use dotenv::dotenv;
-use tonic::transport::Channel;
 
 pub use crate::svc_mat::gen::sub_client::SubClient;
+use crate::{
+    svc_dsc::client::balance::{shared_channel, BalancedChannel},
+    svc_mat::SERVICE_GROUP,
+};
 
-use super::{SERVICE_HOST, SERVICE_PORT};
+use super::SERVICE_NAME;
 
-pub async fn client() -> Result<SubClient<Channel>, Box<dyn std::error::Error>> {
+// Spreads calls over every sub instance registered in svc-dsc
+pub async fn client() -> Result<SubClient<BalancedChannel>, Box<dyn std::error::Error>> {
     dotenv().expect("missing .env file. Create .env or run from the root of project");
-    let host = SERVICE_HOST;
-    let port = SERVICE_PORT;
-    let addr = format!("http://{}:{}", host, port);
 
-    let client = SubClient::connect(addr).await?;
-    Ok(client)
+    let channel = shared_channel(SERVICE_GROUP, SERVICE_NAME).await?;
+    Ok(SubClient::new(channel))
 }